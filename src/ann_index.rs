@@ -1,61 +1,723 @@
-use dashmap::DashSet;
 use itertools::Itertools;
-use rayon::prelude::*;
-use std::cmp::min;
-use std::collections::HashSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
-use crate::hyperplane::HyperPlane;
-use crate::tree::{InnerNode, LeafNode, TreeNode};
-use crate::vector::Vector;
-use rand::prelude::SliceRandom;
+use crate::builder::IndexBuilder;
+use crate::error::{BuildError, InsertError, MergeError, ValidationError};
+use crate::metric::{compare_distances, smallest_k, DistanceMetric};
+#[cfg(feature = "mmap")]
+use crate::mmap_index::MmapIndex;
+use crate::parallel::*;
+use crate::persistence::{
+    read_alias_index, read_header, read_i32, read_tree, write_header, write_i32, write_tree,
+    write_u64, Header,
+};
+use crate::query::{inverse_norm, PreparedQuery, PreparedSearch};
+use crate::scalar::VectorScalar;
+use crate::search::{CandidateScoring, SearchExplanation, SearchParams, SearchStats};
+use crate::split::{SplitStrategy, TreeStats};
+use crate::tree::{
+    build_trees, build_trees_sequential, build_trees_until, candidates_or_scan,
+    collect_budgeted_candidates, collect_candidate_votes, collect_candidates,
+    collect_candidates_sequential, collect_counted_candidates, collect_path_candidates,
+    collect_probed_candidates, insert_into_tree, leaves, min_path_margin, reachable_count,
+    remap_tree, remove_from_tree, replace_in_tree, tree_memory_usage, tree_stats, validate_tree,
+    TraversalCounters, TreeConfig, TreeNode,
+};
+use crate::vector::{flatten, Vector, VectorOps};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::ThreadPool;
 
 /// A search result from an approximate nearest neighbors search
 /// Each result contains the vector id, the distance from the query vector, and the vector itself
+/// The distance is measured using the `DistanceMetric` of the index that produced the result
 #[derive(Debug, Clone)]
-pub struct ApproximateNearestNeighborsSearchResult<const N: usize> {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ApproximateNearestNeighborsSearchResult<const N: usize, T = f32> {
     pub vector_id: i32,
-    pub distance: f32,
-    pub vector: Vector<N>,
+    /// The distance to the query under the index's metric, use `DistanceMetric::Euclidean`
+    /// rather than the default `SquaredEuclidean` to report the true euclidian distance
+    /// With `DistanceMetric::MaxInnerProduct` this is the dot product with the query instead
+    pub distance: T,
+    pub vector: Vector<N, T>,
+}
+
+/// Results are ordered by distance (ascending, NaN last) then by id, the order every search returns
+/// them in, so results of several indexes can be merged with `sort` or a `BinaryHeap`
+/// Two results are equal when they have the same distance and id, regardless of their vectors
+/// With `DistanceMetric::MaxInnerProduct` the best results come last, as they have the largest distance
+impl<const N: usize, T: VectorScalar> Ord for ApproximateNearestNeighborsSearchResult<N, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_distances(&self.distance, &other.distance)
+            .then(self.vector_id.cmp(&other.vector_id))
+    }
+}
+
+impl<const N: usize, T: VectorScalar> PartialOrd for ApproximateNearestNeighborsSearchResult<N, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize, T: VectorScalar> PartialEq for ApproximateNearestNeighborsSearchResult<N, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<const N: usize, T: VectorScalar> Eq for ApproximateNearestNeighborsSearchResult<N, T> {}
+
+/// The ids dropped while deduplicating the vectors of an index, see `build_with_report`
+/// A vector sharing its id with the vector it duplicates is not reported, as the id is still in the index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// The ids of the dropped vectors, in the order they were supplied
+    pub dropped: Vec<i32>,
+    /// The id of the vector kept in place of each dropped id
+    pub mapping: HashMap<i32, i32>,
+}
+
+impl DedupReport {
+    /// Report the `duplicates` returned by `deduplicate`, where `ids` are the ids of the kept vectors
+    fn from_duplicates(duplicates: &[(i32, usize)], ids: &[i32]) -> Self {
+        let mut report = DedupReport::default();
+        for &(dropped, kept) in duplicates {
+            let kept = ids[kept];
+            if dropped != kept && !report.mapping.contains_key(&dropped) {
+                report.dropped.push(dropped);
+                report.mapping.insert(dropped, kept);
+            }
+        }
+        report
+    }
+}
+
+/// The most vectors an index can hold, the leaves of the trees store the positions of the vectors
+/// as `u32` since every tree holds every position once
+pub const MAX_VECTORS: usize = u32::MAX as usize;
+
+/// The vectors, ids, and trees of a built index, see `ApproximateNearestNeighborsIndex::into_parts`
+pub(crate) struct IndexParts<const N: usize, T: VectorScalar> {
+    pub(crate) vectors: Vec<Vector<N, T>>,
+    pub(crate) ids: Vec<i32>,
+    pub(crate) aliases: HashMap<usize, Vec<i32>>,
+    pub(crate) trees: Vec<TreeNode<Vector<N, T>>>,
+    pub(crate) metric: DistanceMetric,
+    pub(crate) pool: Option<Arc<ThreadPool>>,
 }
 
 /// An index of vectors that can be searched for approximate nearest neighbors
 /// The index constructs an in-memory tree of the vectors, and searches the tree for the nearest neighbors
-pub struct ApproximateNearestNeighborsIndex<const N: usize> {
-    vectors: Vec<Vector<N>>,
+/// Vectors can use any `VectorScalar` component type, defaulting to `f32`
+/// Cloning deep-copies the vectors, ids, and trees, so the clone can be mutated independently,
+/// while a thread pool set with `IndexBuilder::num_threads` is shared with the clone
+#[derive(Clone)]
+pub struct ApproximateNearestNeighborsIndex<const N: usize, T: VectorScalar = f32> {
+    vectors: Vec<Vector<N, T>>,
+    /// The squared norm of every vector, at the same position as the vector
+    norms: Vec<T>,
+    /// One over the norm of every vector, zero for the zero vector, see `ranking_distance`
+    inverse_norms: Vec<T>,
     ids: Vec<i32>,
-    trees: Vec<TreeNode<N>>,
+    trees: Vec<TreeNode<Vector<N, T>>>,
+    metric: DistanceMetric,
+    split_strategy: SplitStrategy,
+    split_sample_size: usize,
+    max_size: i32,
+    max_depth: Option<usize>,
+    id_to_index: HashMap<i32, usize>,
+    /// The ids in `ids` at more than one position, `id_to_index` only holds the first of them so
+    /// removing such an id has to search `ids` for the others
+    repeated_ids: HashSet<i32>,
+    /// The ids of a vector beyond the one in `ids`, for identical vectors kept with
+    /// `IndexBuilder::keep_duplicates`, by the position of the vector
+    aliases: HashMap<usize, Vec<i32>>,
+    keep_duplicates: bool,
+    pool: Option<Arc<ThreadPool>>,
+    /// Draws the hyperplanes of inserted vectors and the seed of rebuilt trees, see `set_seed`
+    rng: StdRng,
 }
 
-impl<const N: usize> ApproximateNearestNeighborsIndex<N> {
+impl<const N: usize, T: VectorScalar> ApproximateNearestNeighborsIndex<N, T> {
     /// Build an index of vectors by constructing a tree of the vectors
     /// The index will contain `num_trees` trees, each with a maximum of `max_size` vectors
     /// The index will deduplicate vectors with the same hashkey
+    /// Searches on the index will use the default `DistanceMetric::SquaredEuclidean` metric
+    /// With `num_trees` zero no trees are built and searches scan every vector, returning the exact
+    /// nearest neighbors, which is slow but suits datasets too small to benefit from trees
+    /// A `max_size` below 1 is treated as 1, see `try_build` to reject it instead
     pub fn build(
         num_trees: i32,
         max_size: i32,
-        vectors: &Vec<Vector<N>>,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        Self::build_with_metric(
+            num_trees,
+            max_size,
+            vectors,
+            vector_ids,
+            DistanceMetric::default(),
+        )
+    }
+
+    /// Build an index of vectors the same way as `build`, validating the input first
+    /// Returns `BuildError::LengthMismatch` if the number of vectors and ids differ,
+    /// `BuildError::Empty` if no vectors are supplied, and `BuildError::TooManyVectors` if more
+    /// than `MAX_VECTORS` are supplied, and `BuildError::InvalidLeafSize` if `max_size` is below 1
+    pub fn try_build(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> Result<ApproximateNearestNeighborsIndex<N, T>, BuildError> {
+        Self::validate_input(vectors, vector_ids)?;
+        if max_size < 1 {
+            return Err(BuildError::InvalidLeafSize { max_size });
+        }
+        Ok(Self::build(num_trees, max_size, vectors, vector_ids))
+    }
+
+    /// Build an index of vectors the same way as `build`, but rank search results with `metric`
+    /// The metric is stored on the index so every search is consistent with how the index was built
+    pub fn build_with_metric(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+        metric: DistanceMetric,
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        let builder = IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size)
+            .metric(metric);
+        Self::from_builder(&builder, vectors, vector_ids)
+    }
+
+    /// Build an index of vectors the same way as `build`, but deterministically from `seed`
+    /// Each tree derives its own sub-seed from `seed`, so given the same inputs and seed
+    /// the constructed trees and search results are identical regardless of thread scheduling
+    pub fn build_with_seed(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+        seed: u64,
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        let builder = IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size)
+            .seed(seed);
+        Self::from_builder(&builder, vectors, vector_ids)
+    }
+
+    /// Build an index of vectors the same way as `build`, also reporting which ids were dropped as
+    /// duplicates and the id each of them collapsed into, so references to a dropped id can be
+    /// redirected to the id that is searchable
+    pub fn build_with_report(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> (ApproximateNearestNeighborsIndex<N, T>, DedupReport) {
+        let builder = IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size);
+        Self::from_builder_with_report(&builder, vectors, vector_ids)
+    }
+
+    /// Build an index with the settings of `builder`, the input is expected to be validated
+    pub(crate) fn from_builder(
+        builder: &IndexBuilder<N, T>,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        Self::from_builder_with_report(builder, vectors, vector_ids).0
+    }
+
+    /// Build an index with the settings of `builder` the same way as `from_builder`,
+    /// also returning the ids dropped as duplicates
+    pub(crate) fn from_builder_with_report(
+        builder: &IndexBuilder<N, T>,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> (ApproximateNearestNeighborsIndex<N, T>, DedupReport) {
+        let never = AtomicBool::new(false);
+        Self::from_builder_until(builder, vectors, vector_ids, &never)
+            .expect("builds are only abandoned once cancelled")
+    }
+
+    /// Build an index the same way as `from_builder_with_report`, or `None` as soon as `cancel`
+    /// is set, see `IndexBuilder::build_cancellable`
+    pub(crate) fn from_builder_until(
+        builder: &IndexBuilder<N, T>,
+        vectors: &[Vector<N, T>],
         vector_ids: &[i32],
-    ) -> ApproximateNearestNeighborsIndex<N> {
+        cancel: &AtomicBool,
+    ) -> Option<(ApproximateNearestNeighborsIndex<N, T>, DedupReport)> {
         let (mut unique_vecs, mut ids) = (vec![], vec![]);
-        Self::deduplicate(vectors, vector_ids, &mut unique_vecs, &mut ids);
-        let all_indexes: Vec<usize> = (0..unique_vecs.len()).collect();
+        let duplicates = match builder.dedup_epsilon {
+            Some(epsilon) => {
+                deduplicate_within(vectors, vector_ids, epsilon, &mut unique_vecs, &mut ids)
+            }
+            None => deduplicate(vectors, vector_ids, &mut unique_vecs, &mut ids),
+        };
+        if !builder.keep_duplicates {
+            let report = DedupReport::from_duplicates(&duplicates, &ids);
+            return Some((
+                Self::from_unique(builder, unique_vecs, ids, cancel)?,
+                report,
+            ));
+        }
+        let mut index = Self::from_unique(builder, unique_vecs, ids, cancel)?;
+        for (id, kept) in duplicates {
+            index.alias(kept, id);
+        }
+        Some((index, DedupReport::default()))
+    }
 
-        let trees = (0..num_trees)
-            .into_par_iter()
-            .map(|_| Self::build_tree(max_size, &all_indexes, &unique_vecs))
-            .collect();
+    /// Build an index of the `(id, vector)` pairs of `entries` the same way as `build`, without the
+    /// caller collecting them into slices first
+    /// Duplicate vectors are dropped as the entries are consumed, so only a single copy of each
+    /// unique vector is ever held, the first entry with a given vector is kept
+    pub fn build_from_iter(
+        num_trees: i32,
+        max_size: i32,
+        entries: impl IntoIterator<Item = (i32, Vector<N, T>)>,
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        let (mut unique_vecs, mut ids) = (vec![], vec![]);
+        let mut hashes_seen = HashSet::new();
+        for (id, vector) in entries {
+            if hashes_seen.insert(vector.hashkey()) {
+                unique_vecs.push(vector);
+                ids.push(id);
+            }
+        }
+        let builder = IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size);
+        let never = AtomicBool::new(false);
+        Self::from_unique(&builder, unique_vecs, ids, &never)
+            .expect("builds are only abandoned once cancelled")
+    }
 
+    /// Build the trees of an index over vectors that are already deduplicated,
+    /// or `None` as soon as `cancel` is set
+    fn from_unique(
+        builder: &IndexBuilder<N, T>,
+        unique_vecs: Vec<Vector<N, T>>,
+        ids: Vec<i32>,
+        cancel: &AtomicBool,
+    ) -> Option<ApproximateNearestNeighborsIndex<N, T>> {
+        let seed = builder.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        let on_tree_built = builder.on_tree_built.as_ref().map(|callback| &*callback.0);
+        let trees = install(&builder.pool, || {
+            let config = builder.tree_config();
+            build_trees_until(
+                builder.num_trees,
+                config,
+                &unique_vecs,
+                seed,
+                on_tree_built,
+                cancel,
+            )
+        })?;
+        Some(Self::assemble(builder, unique_vecs, ids, trees))
+    }
+
+    /// Build an index of vectors the same way as `build_with_seed`, but build the trees one after
+    /// another on the calling thread instead of in parallel, for environments that cannot spawn
+    /// threads or to profile without scheduler noise
+    /// Given the same inputs and seed the index is identical to the one `build_with_seed` builds
+    pub fn build_sequential(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+        seed: u64,
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        let builder = IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size)
+            .seed(seed);
+        let (mut unique_vecs, mut ids) = (vec![], vec![]);
+        deduplicate(vectors, vector_ids, &mut unique_vecs, &mut ids);
+        let trees = build_trees_sequential(num_trees, builder.tree_config(), &unique_vecs, seed);
+        Self::assemble(&builder, unique_vecs, ids, trees)
+    }
+
+    /// Construct an index from trees built outside the crate, e.g. by an alternative tree-building
+    /// algorithm, so they can be searched like the trees of a built index
+    /// `ids[i]` is the id of `vectors[i]`, and the leaves of `trees` hold positions in `vectors`
+    /// The vectors are used as supplied, they are not deduplicated, and searches use the default
+    /// `DistanceMetric`, see `IndexBuilder` for the other defaults
+    /// Returns `BuildError::LengthMismatch` if the number of vectors and ids differ, `BuildError::Empty`
+    /// if no vectors are supplied, and `BuildError::LeafIndexOutOfRange` if a leaf holds a position
+    /// past the end of `vectors`
+    pub fn from_parts(
+        vectors: Vec<Vector<N, T>>,
+        ids: Vec<i32>,
+        trees: Vec<TreeNode<Vector<N, T>>>,
+    ) -> Result<ApproximateNearestNeighborsIndex<N, T>, BuildError> {
+        Self::validate_input(&vectors, &ids)?;
+        for (tree, root) in trees.iter().enumerate() {
+            let mut nodes = vec![root];
+            while let Some(node) = nodes.pop() {
+                match node {
+                    TreeNode::Branch(inner) => nodes.extend([inner.left(), inner.right()]),
+                    TreeNode::Leaf(leaf) => {
+                        if let Some(index) = leaf.positions().find(|&i| i >= vectors.len()) {
+                            return Err(BuildError::LeafIndexOutOfRange {
+                                tree,
+                                index,
+                                vectors: vectors.len(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Self::assemble(&IndexBuilder::new(), vectors, ids, trees))
+    }
+
+    fn assemble(
+        builder: &IndexBuilder<N, T>,
+        unique_vecs: Vec<Vector<N, T>>,
+        ids: Vec<i32>,
+        trees: Vec<TreeNode<Vector<N, T>>>,
+    ) -> ApproximateNearestNeighborsIndex<N, T> {
+        let (id_to_index, repeated_ids) = index_ids(&ids);
         ApproximateNearestNeighborsIndex {
             trees,
+            id_to_index,
+            repeated_ids,
             ids,
+            norms: squared_norms(&unique_vecs),
+            inverse_norms: inverse_norms(&unique_vecs),
             vectors: unique_vecs,
+            metric: builder.metric,
+            split_strategy: builder.split_strategy,
+            split_sample_size: builder.split_sample_size,
+            max_size: builder.max_size.max(1),
+            max_depth: builder.max_depth,
+            aliases: HashMap::new(),
+            keep_duplicates: builder.keep_duplicates,
+            pool: builder.pool.clone(),
+            rng: index_rng(builder.seed),
+        }
+    }
+
+    /// Run the parallel work of the index inside `pool` instead of the global rayon thread pool,
+    /// or go back to the global pool with `None`
+    /// Useful for indexes that were loaded from disk, which always start on the global pool
+    pub fn set_thread_pool(&mut self, pool: Option<Arc<ThreadPool>>) {
+        self.pool = pool;
+    }
+
+    /// Seed the randomness of `insert`, `extend` and `rebuild_trees`, so the same sequence of
+    /// changes always produces the same trees
+    /// An index built with `IndexBuilder::seed` is already seeded with that seed, a loaded index
+    /// starts from an unpredictable seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Insert a single vector into the index without rebuilding it
+    /// The vector is appended to the leaf it falls into in every tree, and any leaf that grows
+    /// beyond the `max_size` the index was built with is split into a branch with a new hyperplane
+    /// Returns `InsertError::DuplicateVector` if a vector with the same hashkey is already indexed,
+    /// unless the index keeps duplicates, then the id is added to the indexed vector instead
+    /// Returns `InsertError::TooManyVectors` if the index already holds `MAX_VECTORS` vectors
+    pub fn insert(&mut self, vector: Vector<N, T>, id: i32) -> Result<(), InsertError> {
+        let hash_key = vector.hashkey();
+        let vectors = &self.vectors;
+        let existing = install(&self.pool, || {
+            vectors.par_iter().position_any(|v| v.hashkey() == hash_key)
+        });
+        match existing {
+            Some(index) if self.keep_duplicates => {
+                self.alias(index, id);
+                return Ok(());
+            }
+            Some(_) => return Err(InsertError::DuplicateVector { id }),
+            None if self.len() >= MAX_VECTORS => {
+                return Err(InsertError::TooManyVectors {
+                    vectors: self.len() + 1,
+                    max: MAX_VECTORS,
+                })
+            }
+            None => {}
         }
+
+        let index = self.vectors.len();
+        self.vectors.push(vector);
+        let squared_norm = vector.dot(&vector);
+        self.norms.push(squared_norm);
+        self.inverse_norms.push(inverse_norm(squared_norm));
+        self.ids.push(id);
+        self.index_id(id, index);
+
+        let seeds = self.tree_seeds();
+        let (config, vectors, trees) = (self.tree_config(), &self.vectors, &mut self.trees);
+        install(&self.pool, || {
+            trees.par_iter_mut().zip(seeds).for_each(|(tree, seed)| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                insert_into_tree(tree, index, config, vectors, &mut rng);
+            })
+        });
+        Ok(())
+    }
+
+    /// Insert a batch of vectors into the index without rebuilding it, where `ids[i]` is the id
+    /// of `vectors[i]`, returning how many vectors were added
+    /// Vectors whose hashkey is already indexed, or appears earlier in the batch, are skipped
+    /// rather than treated as an error, and the positions of the existing vectors are unchanged
+    /// If the index keeps duplicates the ids of skipped vectors are added to the indexed vector
+    /// Returns `InsertError::LengthMismatch` if the number of vectors and ids differ, and
+    /// `InsertError::TooManyVectors` if the index could exceed `MAX_VECTORS` were no vector skipped
+    pub fn extend(&mut self, vectors: &[Vector<N, T>], ids: &[i32]) -> Result<usize, InsertError> {
+        if vectors.len() != ids.len() {
+            return Err(InsertError::LengthMismatch {
+                vectors: vectors.len(),
+                ids: ids.len(),
+            });
+        }
+        if self.len() + vectors.len() > MAX_VECTORS {
+            return Err(InsertError::TooManyVectors {
+                vectors: self.len() + vectors.len(),
+                max: MAX_VECTORS,
+            });
+        }
+
+        let indexed = &self.vectors;
+        let mut hash_keys: HashMap<_, _> = install(&self.pool, || {
+            indexed
+                .par_iter()
+                .enumerate()
+                .map(|(index, v)| (v.hashkey(), index))
+                .collect()
+        });
+        let first = self.vectors.len();
+        for (vector, &id) in vectors.iter().zip(ids) {
+            match hash_keys.entry(vector.hashkey()) {
+                Entry::Occupied(existing) if self.keep_duplicates => {
+                    self.alias(*existing.get(), id)
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(entry) => {
+                    entry.insert(self.vectors.len());
+                    self.index_id(id, self.vectors.len());
+                    self.vectors.push(*vector);
+                    let squared_norm = vector.dot(vector);
+                    self.norms.push(squared_norm);
+                    self.inverse_norms.push(inverse_norm(squared_norm));
+                    self.ids.push(id);
+                }
+            }
+        }
+
+        let added = first..self.vectors.len();
+        let seeds = self.tree_seeds();
+        let (config, vectors, trees) = (self.tree_config(), &self.vectors, &mut self.trees);
+        install(&self.pool, || {
+            trees.par_iter_mut().zip(seeds).for_each(|(tree, seed)| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                for index in added.clone() {
+                    insert_into_tree(tree, index, config, vectors, &mut rng);
+                }
+            })
+        });
+        Ok(added.len())
+    }
+
+    /// Combine this index with `other`, for example shards built separately, without rebuilding
+    /// any trees: the merged index holds the vectors of both indexes and all of their trees
+    /// Searches consult every tree and rank the candidates of both, so neighbors are found in
+    /// either source. The merged index keeps the settings and thread pool of `self`, the
+    /// `max_size`, depth limit, split strategy, duplicate handling and pool of `other` are
+    /// discarded, so vectors inserted later split the leaves of every tree by the settings of `self`
+    /// A vector of `other` already in `self` is not stored twice, if `self` keeps duplicates its ids
+    /// are added to the vector of `self` the same as `insert` does, and its position in the trees
+    /// of `other` refers to that vector
+    /// Returns `MergeError::IdCollision` if an id is used in both indexes,
+    /// `MergeError::DuplicateVector` if a vector is in both indexes and `self` drops duplicates,
+    /// `MergeError::MetricMismatch` if the indexes use different distance metrics, and
+    /// `MergeError::TooManyVectors` if the merged index would hold more than `MAX_VECTORS` vectors
+    /// The dimension and scalar type of both indexes are the same by construction
+    pub fn merge(mut self, mut other: Self) -> Result<Self, MergeError> {
+        if self.metric != other.metric {
+            return Err(MergeError::MetricMismatch {
+                expected: self.metric,
+                found: other.metric,
+            });
+        }
+        if let Some(&id) = other
+            .ids
+            .iter()
+            .chain(other.aliases.values().flatten())
+            .find(|id| self.id_to_index.contains_key(id))
+        {
+            return Err(MergeError::IdCollision { id });
+        }
+        let positions: HashMap<_, usize> = self
+            .vectors
+            .iter()
+            .enumerate()
+            .map(|(index, vector)| (vector.hashkey(), index))
+            .collect();
+        let existing: Vec<Option<usize>> = other
+            .vectors
+            .iter()
+            .map(|vector| positions.get(&vector.hashkey()).copied())
+            .collect();
+        if !self.keep_duplicates {
+            if let Some(index) = existing.iter().position(Option::is_some) {
+                return Err(MergeError::DuplicateVector {
+                    id: other.ids[index],
+                });
+            }
+        }
+        let added = existing.iter().filter(|index| index.is_none()).count();
+        if self.len() + added > MAX_VECTORS {
+            return Err(MergeError::TooManyVectors {
+                vectors: self.len() + added,
+                max: MAX_VECTORS,
+            });
+        }
+
+        // the position every vector of `other` has in the merged index
+        let mut next = self.vectors.len();
+        let remap: Vec<usize> = existing
+            .iter()
+            .map(|index| {
+                index.unwrap_or_else(|| {
+                    next += 1;
+                    next - 1
+                })
+            })
+            .collect();
+        install(&self.pool, || {
+            other
+                .trees
+                .par_iter_mut()
+                .for_each(|tree| remap_tree(tree, &remap))
+        });
+        self.repeated_ids.extend(other.repeated_ids);
+        for (index, vector) in other.vectors.into_iter().enumerate() {
+            let id = other.ids[index];
+            let aliases = other.aliases.remove(&index).unwrap_or_default();
+            if let Some(existing) = existing[index] {
+                for id in std::iter::once(id).chain(aliases) {
+                    self.alias(existing, id);
+                }
+                continue;
+            }
+            let merged = remap[index];
+            self.index_id(id, merged);
+            self.vectors.push(vector);
+            self.norms.push(other.norms[index]);
+            self.inverse_norms.push(other.inverse_norms[index]);
+            self.ids.push(id);
+            if !aliases.is_empty() {
+                for &id in &aliases {
+                    self.id_to_index.entry(id).or_insert(merged);
+                }
+                self.aliases.insert(merged, aliases);
+            }
+        }
+        self.trees.append(&mut other.trees);
+        Ok(self)
+    }
+
+    /// Discard every tree and build `num_trees` new ones with at most `max_size` vectors per leaf
+    /// over the vectors currently in the index, keeping the vectors and their ids as they are
+    /// Trees degrade as vectors are inserted into and removed from them, so long-running indexes
+    /// can be refreshed periodically without ingesting their data again
+    /// The new trees keep the metric, split strategy and depth limit the index was built with
+    /// A `max_size` below 1 is treated as 1
+    pub fn rebuild_trees(&mut self, num_trees: i32, max_size: i32) {
+        self.max_size = max_size.max(1);
+        let seed = self.rng.gen();
+        let (config, vectors) = (self.tree_config(), &self.vectors);
+        self.trees = install(&self.pool, || {
+            build_trees(num_trees, config, vectors, seed, None)
+        });
+    }
+
+    /// Remove every vector with the given `id` from the index without rebuilding it
+    /// Returns `false` if no vector with the id was found
+    ///
+    /// NOTE:
+    /// Removal compacts the index rather than leaving tombstones, the last vector in the index is
+    /// moved into the slot of the removed vector and its position is remapped in every tree
+    /// This keeps searches free of any filtering, but changes the position of the moved vector
+    /// A vector kept for several ids with `keep_duplicates` stays in place until its last id is removed
+    pub fn remove(&mut self, id: i32) -> bool {
+        let Some(index) = self.id_to_index.remove(&id) else {
+            return false;
+        };
+        self.aliases.retain(|_, ids| {
+            ids.retain(|&i| i != id);
+            !ids.is_empty()
+        });
+        if self.repeated_ids.remove(&id) {
+            while let Some(index) = self.ids.iter().position(|&i| i == id) {
+                self.remove_position(index);
+            }
+        } else if self.ids[index] == id {
+            self.remove_position(index);
+        }
+        true
+    }
+
+    /// Remove the vector at `index` from the index, or if the vector has other ids with
+    /// `keep_duplicates`, keep it under the first of them instead
+    fn remove_position(&mut self, index: usize) {
+        if let Some(mut others) = self.aliases.remove(&index) {
+            self.ids[index] = others.remove(0);
+            if !others.is_empty() {
+                self.aliases.insert(index, others);
+            }
+            return;
+        }
+        let last = self.vectors.len() - 1;
+        let (vectors, trees) = (&self.vectors, &mut self.trees);
+        install(&self.pool, || {
+            trees.par_iter_mut().for_each(|tree| {
+                remove_from_tree(tree, index, &vectors[index]);
+                if index != last {
+                    replace_in_tree(tree, last, index, &vectors[last]);
+                }
+            })
+        });
+        for moved in self.ids_at(last).collect_vec() {
+            if self.id_to_index.get(&moved) == Some(&last) {
+                self.id_to_index.insert(moved, index);
+            }
+        }
+        if let Some(moved) = self.aliases.remove(&last) {
+            self.aliases.insert(index, moved);
+        }
+        self.vectors.swap_remove(index);
+        self.norms.swap_remove(index);
+        self.inverse_norms.swap_remove(index);
+        self.ids.swap_remove(index);
     }
 
     /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector
     /// Returns a vector of `ApproximateNearestNeighborsSearchResult` structs
     /// The vector is sorted by distance from the query vector (ascending) and limited to `top_k` results
-    /// using the squared euclidian distance as the distance metric.
+    /// using the `DistanceMetric` the index was built with. Equal distances are ordered by id (ascending)
+    /// At most `min(top_k, len)` results are returned, and a `top_k` of zero or less returns none
     ///
     /// NOTE:
     /// Search is an approximate nearest neighbors search, and may not return the true nearest neighbors
@@ -66,121 +728,1092 @@ impl<const N: usize> ApproximateNearestNeighborsIndex<N> {
     /// the search may not return the true nearest neighbors.
     pub fn search(
         &self,
-        query: Vector<N>,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.search_prepared(&PreparedQuery::new(query), top_k)
+    }
+
+    /// Prepare `query` for several searches of this index, e.g. with different `top_k`, so the
+    /// quantities every search of the query needs (such as its norm) are computed only once
+    pub fn prepare(&self, query: Vector<N, T>) -> PreparedSearch<'_, N, T> {
+        PreparedSearch::new(self, PreparedQuery::new(query))
+    }
+
+    /// Search the index the same way as `search`, but gather up to `search_k` candidates from each
+    /// tree before ranking them and keeping the closest `top_k`
+    /// Gathering more candidates than `top_k` improves recall at the cost of latency,
+    /// a `search_k` below `top_k` is treated as `top_k`
+    pub fn search_with_params(
+        &self,
+        query: Vector<N, T>,
         top_k: i32,
-    ) -> Vec<ApproximateNearestNeighborsSearchResult<N>> {
-        let candidates = DashSet::new();
-        self.trees.par_iter().for_each(|tree| {
-            Self::query_tree(query, top_k, tree, &candidates);
+        search_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| collect_candidates(&self.trees, &query, search_k.max(top_k)))
         });
-        candidates
+        self.rank(candidates, &query, top_k)
+    }
+
+    /// Search the index the same way as `search`, returning the results as an iterator in ascending
+    /// distance order, so callers can stop early or process each result as it is produced
+    /// The candidates are still gathered and ranked before the first result is returned,
+    /// only building each result from its stored vector is deferred to the iteration
+    pub fn search_iter(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> impl Iterator<Item = ApproximateNearestNeighborsSearchResult<N, T>> + '_ {
+        let query = PreparedQuery::new(query);
+        // a `top_k` that returns no results gathers no candidates
+        let top_k = clamp_top_k(top_k, self.num_results()).unwrap_or(0);
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| collect_candidates(&self.trees, query.vector(), top_k))
+        });
+        let distances = self.ranking_distances(candidates, &query);
+        self.closest(distances, query.vector(), top_k, |_| true)
+    }
+
+    /// Search the index the same way as `search` for a query that was prepared up front,
+    /// see `PreparedQuery`
+    /// With `DistanceMetric::Cosine` a query prepared with `Vector::prepare_for_cosine` or
+    /// `PreparedQuery::normalized` is never divided by its magnitude
+    pub fn search_prepared(
+        &self,
+        query: &PreparedQuery<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.search_trees(query, top_k, self.trees.len())
+    }
+
+    /// Search the index the same way as `search`, but only traverse the first `trees_to_search`
+    /// trees, trading recall for latency at query time without rebuilding with fewer trees
+    /// Every tree is built independently at random, so the first trees are as good as any subset
+    /// A `trees_to_search` above `num_trees` searches every tree, and zero returns no results
+    pub fn search_with_trees(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+        trees_to_search: usize,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.search_trees(&PreparedQuery::new(query), top_k, trees_to_search)
+    }
+
+    fn search_trees(
+        &self,
+        query: &PreparedQuery<N, T>,
+        top_k: i32,
+        trees_to_search: usize,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let trees = &self.trees[..trees_to_search.min(self.trees.len())];
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| collect_candidates(trees, query.vector(), top_k))
+        });
+        self.rank_prepared(candidates, query, top_k, |_| true)
+    }
+
+    /// Search the index the same way as `search`, but visit the trees and compute the distances
+    /// one after another on the calling thread, returning the same results as `search`
+    pub fn search_sequential(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let (ranking, prepared) = (self.metric.ranking(), PreparedQuery::new(query));
+        let distances = self
+            .candidates_from(|| collect_candidates_sequential(&self.trees, &query, top_k))
             .into_iter()
-            .map(|idx| (idx, self.vectors[idx].squared_euclidian_distance(&query)))
-            .sorted_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
-            .take(top_k as usize)
-            .map(|(ids, dis)| ApproximateNearestNeighborsSearchResult {
-                vector_id: self.ids[ids],
-                distance: dis,
-                vector: self.vectors[ids],
+            .map(|idx| (idx, self.ranking_distance(ranking, idx, &prepared)))
+            .collect_vec();
+        self.closest(distances, &query, top_k, |_| true)
+            .collect_vec()
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the vector stored for `id`,
+    /// the vectors with `id` itself are never part of the results
+    /// Returns `None` if no vector with the id is in the index
+    pub fn search_by_id(
+        &self,
+        id: i32,
+        top_k: i32,
+    ) -> Option<Vec<ApproximateNearestNeighborsSearchResult<N, T>>> {
+        let query = self.get(id)?;
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return Some(vec![]);
+        };
+        // the query is found as its own nearest neighbor, so one extra candidate is gathered
+        // from each tree to make up for excluding it
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| {
+                collect_candidates(&self.trees, &query, top_k.saturating_add(1))
+            })
+        });
+        let candidates = candidates
+            .into_iter()
+            .filter(|&idx| self.ids_at(idx).any(|other| other != id));
+        Some(self.rank_where(candidates, &query, top_k, |other| other != id))
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector,
+    /// leaving out every vector whose id is in `exclude`
+    /// Excluded vectors are skipped before the results are truncated to `top_k`, and more candidates
+    /// are gathered from the trees as needed, so up to `top_k` visible results are still returned
+    pub fn search_filtered(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+        exclude: &HashSet<i32>,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.search_where(query, top_k, |id| !exclude.contains(&id))
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector whose id
+    /// satisfies `pred`, e.g. ids in an allowed set or ids whose external metadata matches a filter
+    /// Candidates are gathered from the trees until `top_k` of them pass or every vector was considered,
+    /// the candidates gathered from each tree double every round starting from `top_k`
+    ///
+    /// NOTE:
+    /// Filters that reject most vectors force the search to gather and test many candidates,
+    /// in the worst case every vector in the index
+    pub fn search_where(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+        pred: impl Fn(i32) -> bool,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let mut n = top_k;
+        loop {
+            let candidates = install(&self.pool, || {
+                self.candidates_from(|| collect_candidates(&self.trees, &query, n))
+            });
+            let passing = candidates
+                .into_iter()
+                .filter(|&idx| self.ids_at(idx).any(&pred))
+                .collect_vec();
+            // once each tree is asked for as many candidates as there are vectors it yields them all
+            let exhausted = n as usize >= self.vectors.len();
+            if passing.len() >= top_k as usize || exhausted {
+                return self.rank_where(passing, &query, top_k, pred);
+            }
+            n = n.saturating_mul(2);
+        }
+    }
+
+    /// Search the index the same way as `search`, also reporting the effort the search took
+    /// Useful to correlate slow queries with unusually deep or wide exploration of the trees,
+    /// and to score the confidence of the results by how close the query lies to a split
+    pub fn search_with_stats(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> (
+        Vec<ApproximateNearestNeighborsSearchResult<N, T>>,
+        SearchStats,
+    ) {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return (vec![], SearchStats::default());
+        };
+        let counters = TraversalCounters::default();
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| {
+                collect_counted_candidates(&self.trees, &query, top_k, Some(&counters))
             })
+        });
+        let stats = SearchStats {
+            candidates: candidates.len(),
+            leaves_visited: counters.leaves.into_inner(),
+            nodes_traversed: counters.nodes.into_inner(),
+            min_margin: self
+                .trees
+                .iter()
+                .filter_map(|tree| min_path_margin(&query, tree))
+                .min_by(compare_distances)
+                .map(T::to_f64),
+        };
+        (self.rank(candidates, &query, top_k), stats)
+    }
+
+    /// Search the index with the options of `params`, see `SearchParams`
+    /// With `CandidateScoring::Distance` and no probes this is the same as `search_with_params`, the
+    /// other scorings also count how many trees found each candidate and use those votes in the ranking
+    ///
+    /// NOTE:
+    /// Recall is measured against the true nearest neighbors by distance, so letting votes override
+    /// distance with `CandidateScoring::Blend` can only keep or lower recall for the same candidates
+    pub fn search_with(
+        &self,
+        query: Vector<N, T>,
+        params: &SearchParams,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let SearchParams {
+            top_k,
+            search_k,
+            scoring,
+            probes,
+            max_candidates,
+            sequential,
+        } = *params;
+        let max_candidates = max_candidates.unwrap_or(usize::MAX);
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        // without trees there are no votes to count, every vector is ranked by distance
+        if scoring == CandidateScoring::Distance || self.trees.is_empty() {
+            let candidates = install(&self.pool, || {
+                let n = search_k.max(top_k);
+                self.candidates_from(|| {
+                    collect_probed_candidates(
+                        &self.trees,
+                        &query,
+                        n,
+                        probes,
+                        max_candidates,
+                        sequential,
+                    )
+                })
+            });
+            return self.rank(candidates, &query, top_k);
+        }
+
+        let votes = install(&self.pool, || {
+            let n = search_k.max(top_k);
+            collect_candidate_votes(&self.trees, &query, n, probes, max_candidates, sequential)
+        });
+        let vote_share = |votes: usize| votes as f64 / self.trees.len() as f64;
+        let blend = |vote_weight: f32, votes: usize, distance: T| {
+            distance.to_f64() - vote_weight as f64 * vote_share(votes)
+        };
+        let distances = self.ranking_distances(votes.keys().copied(), &PreparedQuery::new(query));
+        // each candidate yields at least one result, so the best `top_k` of them suffice
+        let kept = match scoring {
+            CandidateScoring::Blend { vote_weight } => {
+                let scored = distances.into_iter().map(|(idx, distance)| {
+                    // blend on the scale of the metric's raw distance, `report` undoes itself
+                    let raw = self.metric.report(self.metric.unrank(distance));
+                    ((self.ids[idx], idx), blend(vote_weight, votes[&idx], raw))
+                });
+                smallest_k(scored, top_k as usize)
+                    .into_iter()
+                    .map(|((_, idx), _)| idx)
+                    .collect_vec()
+            }
+            _ => {
+                let scored = distances
+                    .into_iter()
+                    .map(|(idx, distance)| ((Reverse(votes[&idx]), self.ids[idx], idx), distance));
+                smallest_k(scored, top_k as usize)
+                    .into_iter()
+                    .map(|((_, _, idx), _)| idx)
+                    .collect_vec()
+            }
+        };
+        // the kept candidates are ordered again by their exact distances, which are reported
+        let scored = kept.into_iter().map(|idx| {
+            (
+                idx,
+                votes[&idx],
+                self.metric.distance(&self.vectors[idx], &query),
+            )
+        });
+        let by_id =
+            |a: &(usize, usize, T), b: &(usize, usize, T)| self.ids[a.0].cmp(&self.ids[b.0]);
+        let ranked = match scoring {
+            CandidateScoring::Blend { vote_weight } => scored.sorted_by(|a, b| {
+                let score =
+                    |&(_, votes, distance): &(usize, usize, T)| blend(vote_weight, votes, distance);
+                compare_distances(&score(a), &score(b)).then(by_id(a, b))
+            }),
+            _ => scored.sorted_by(|a, b| {
+                compare_distances(&a.2, &b.2)
+                    .then(b.1.cmp(&a.1))
+                    .then(by_id(a, b))
+            }),
+        };
+        ranked
+            .flat_map(|(idx, _, dis)| self.ids_at(idx).map(move |id| (idx, id, dis)))
+            .take(top_k as usize)
+            .map(|(idx, id, dis)| self.result(idx, id, dis))
             .collect_vec()
     }
 
-    /// Retrieve all vectors in the index, the id of the vector is its index within the returned Vec
-    /// since the index is immutable after construction, the id of a vector will not change
-    pub fn all_vectors(&self) -> Vec<Vector<N>> {
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector,
+    /// exploring the nodes of all trees best-first until `max_nodes` nodes have been visited
+    /// Nodes are ordered by how far the query lies on their side of the hyperplanes leading to them,
+    /// so the budget is spent on the leaves most likely to hold neighbors
+    /// Every visited leaf contributes all of its vectors, and a budget too small to reach a leaf
+    /// in any tree returns no results
+    pub fn search_budgeted(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+        max_nodes: usize,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let candidates = install(&self.pool, || {
+            self.candidates_from(|| collect_budgeted_candidates(&self.trees, &query, max_nodes))
+        });
+        self.rank(candidates, &query, top_k)
+    }
+
+    /// Search every vector in the index for the true `top_k` nearest neighbors of the `query` vector
+    /// This is a linear scan using the index's `DistanceMetric`, so it is much slower than `search`,
+    /// but it provides the ground truth needed to measure the recall of `search`
+    pub fn exact_search(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        self.rank(0..self.vectors.len(), &query, top_k)
+    }
+
+    /// Measure the average recall@k of `search` over `queries`, using `exact_search` as the ground truth
+    /// The recall of a query is the fraction of its true `k` nearest neighbors that `search` found
+    /// If the index holds fewer than `k` vectors the recall is measured against the neighbors that
+    /// can actually be returned, and a query with nothing to recall counts as fully recalled
+    pub fn recall_at_k(&self, queries: &[Vector<N, T>], k: i32) -> f32 {
+        if queries.is_empty() {
+            return 1.0;
+        }
+        let total: f32 = install(&self.pool, || {
+            queries
+                .par_iter()
+                .map(|query| self.explain_search(*query, k).recall)
+                .sum()
+        });
+        total / queries.len() as f32
+    }
+
+    /// Compare `search` with `exact_search` for a single `query`, showing which of the true `top_k`
+    /// nearest neighbors the approximate search missed
+    /// The recall is measured the same way as `recall_at_k`
+    pub fn explain_search(&self, query: Vector<N, T>, top_k: i32) -> SearchExplanation<N, T> {
+        let approximate = self.search(query, top_k);
+        let exact = self.exact_search(query, top_k);
+
+        let found: HashSet<i32> = approximate.iter().map(|r| r.vector_id).collect();
+        let missed = exact
+            .iter()
+            .map(|r| r.vector_id)
+            .filter(|id| !found.contains(id))
+            .collect_vec();
+        let recall = match exact.len() {
+            0 => 1.0,
+            len => (len - missed.len()) as f32 / len as f32,
+        };
+        SearchExplanation {
+            approximate,
+            exact,
+            missed,
+            recall,
+        }
+    }
+
+    /// Search the index for every vector within `radius` of the `query` vector
+    /// Returns the matching results sorted by distance from the query vector (ascending),
+    /// or an empty vector if nothing is within the radius
+    ///
+    /// NOTE:
+    /// Radius search is approximate, candidates are gathered from the query's leaf in every tree and
+    /// from the leaf the query falls into on the backup side of each branch along its path
+    /// Vectors within the radius that were split away from the query by a hyperplane may be missed
+    pub fn search_radius(
+        &self,
+        query: Vector<N, T>,
+        radius: T,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        install(&self.pool, || {
+            self.candidates_from(|| collect_path_candidates(&self.trees, &query))
+        })
+        .into_iter()
+        .map(|idx| (idx, self.metric.distance(&self.vectors[idx], &query)))
+        .filter(|(_, distance)| *distance <= radius)
+        .flat_map(|(idx, dis)| self.ids_at(idx).map(move |id| (idx, id, dis)))
+        .sorted_by(|a, b| self.by_distance(a, b))
+        .map(|(idx, id, dis)| self.result(idx, id, dis))
+        .collect_vec()
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of every vector in `queries`
+    /// The queries are searched in parallel, and the results are returned in the same order as `queries`
+    pub fn search_batch(
+        &self,
+        queries: &[Vector<N, T>],
+        top_k: i32,
+    ) -> Vec<Vec<ApproximateNearestNeighborsSearchResult<N, T>>> {
+        install(&self.pool, || {
+            queries
+                .par_iter()
+                .map(|query| self.search(*query, top_k))
+                .collect()
+        })
+    }
+
+    /// Retrieve all vectors in the index in the order they are stored, after deduplication
+    /// The position of a vector within the returned Vec is not its id and does not follow the order
+    /// of the input, vectors are appended by `insert` and `remove` moves the last vector into the
+    /// removed slot, use `all_with_ids` to pair every vector with its ids
+    pub fn all_vectors(&self) -> Vec<Vector<N, T>> {
         self.vectors.clone()
     }
 
-    fn build_tree(max_size: i32, indexes: &Vec<usize>, all_vecs: &Vec<Vector<N>>) -> TreeNode<N> {
-        if indexes.len() <= (max_size as usize) {
-            return TreeNode::Leaf(Box::new(LeafNode::new(indexes.clone())));
+    /// Retrieve all vectors in the index paired with the ids supplied for them, in the same order
+    /// as `all_vectors`
+    /// A vector kept for several ids with `keep_duplicates` appears once for every id
+    pub fn all_with_ids(&self) -> Vec<(i32, Vector<N, T>)> {
+        (0..self.vectors.len())
+            .flat_map(|idx| self.ids_at(idx).map(move |id| (id, self.vectors[idx])))
+            .collect()
+    }
+
+    /// Look up the vector stored for `id`, returns `None` if no vector has the id
+    /// If several vectors were built with the same id, the first of them is returned
+    ///
+    /// NOTE:
+    /// Lookups are O(1) through an id to position map built alongside the index, which costs
+    /// memory for an `i32` key and `usize` position (plus hashing overhead) for every unique id
+    pub fn get(&self, id: i32) -> Option<Vector<N, T>> {
+        self.id_to_index.get(&id).map(|&index| self.vectors[index])
+    }
+
+    /// Whether a vector with `id` is in the index, an id is missing if it was never added,
+    /// was removed, or was dropped as a duplicate when the index was built or extended
+    /// The ids of duplicates kept with `keep_duplicates` are in the index
+    pub fn contains_id(&self, id: i32) -> bool {
+        self.id_to_index.contains_key(&id)
+    }
+
+    /// Iterate over every vector in the index paired with its id, without cloning the vectors
+    /// A vector kept for several ids with `keep_duplicates` is paired with the first of them
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &Vector<N, T>)> {
+        self.ids.iter().copied().zip(self.vectors.iter())
+    }
+
+    /// Every vector in the index as a single row-major slice of `len() * N` values, the values of the
+    /// vector at position `i` are at `i * N..(i + 1) * N`, in the same order as `iter`
+    /// Useful to run distance computations (e.g. SIMD kernels) over all vectors without copying them
+    pub fn raw(&self) -> &[T] {
+        flatten(&self.vectors)
+    }
+
+    /// The number of vectors in the index, after deduplication
+    /// Identical vectors kept for several ids with `keep_duplicates` are stored and counted once,
+    /// so `len` may be smaller than the number of vectors supplied, see `contains_id`
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    /// Whether the index holds no vectors
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /// The distance metric used to rank search results
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// The strategy used to split the vectors of the trees, also used when `insert` splits a leaf
+    pub fn split_strategy(&self) -> SplitStrategy {
+        self.split_strategy
+    }
+
+    /// The number of vectors sampled to derive each split, see `IndexBuilder::split_sample_size`
+    pub fn split_sample_size(&self) -> usize {
+        self.split_sample_size
+    }
+
+    /// The number of trees in the index
+    pub fn num_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// The maximum number of vectors in a leaf, the `max_size` the index was built with
+    pub fn max_leaf_size(&self) -> usize {
+        self.max_size as usize
+    }
+
+    /// The maximum depth of the trees, the `max_depth` the index was built with if any
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// The dimension `N` of the vectors in the index
+    pub fn dimension(&self) -> usize {
+        N
+    }
+
+    /// The number of distinct vectors each tree can reach through its leaves, in the order the
+    /// trees were built
+    /// Every vector is stored in exactly one leaf of every tree, so each count should equal `len`,
+    /// a smaller count means some vectors can never be found through that tree
+    /// With the `log` feature a warning is emitted for every tree that cannot reach all vectors
+    pub fn reachable_counts(&self) -> Vec<usize> {
+        let counts = self.trees.iter().map(reachable_count).collect_vec();
+        #[cfg(feature = "log")]
+        for (tree, &count) in counts.iter().enumerate() {
+            if count < self.len() {
+                log::warn!(
+                    "tree {} can only reach {} of {} vectors",
+                    tree,
+                    count,
+                    self.len()
+                );
+            }
         }
-        let (plane, above, below) = Self::build_hyperplane(indexes, all_vecs);
-        let node_above = Self::build_tree(max_size, &above, all_vecs);
-        let node_below = Self::build_tree(max_size, &below, all_vecs);
+        counts
+    }
 
-        TreeNode::Branch(Box::new(InnerNode::new(plane, node_below, node_above)))
+    /// Check that every tree holds the position of every vector of the index in exactly one leaf,
+    /// returning the first omission, duplicate, or out of range position found
+    /// Trees built by the index are also checked in debug builds as they are built, this is useful
+    /// after mutating the index or for trees supplied to `from_parts`
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.trees
+            .iter()
+            .enumerate()
+            .try_for_each(|(number, tree)| validate_tree(number, tree, self.len()))
     }
 
-    fn build_hyperplane(
-        indexes: &[usize],
-        all_vecs: &[Vector<N>],
-    ) -> (HyperPlane<N>, Vec<usize>, Vec<usize>) {
-        let sample: Vec<_> = indexes
-            .choose_multiple(&mut rand::thread_rng(), 2)
+    /// The ids held by each leaf of the tree at `tree_index`, counting the trees in the order they
+    /// were built, for inspecting how the tree partitions the vectors
+    /// The leaves are listed from left to right, the leaves below the hyperplane of a branch first,
+    /// and every id of a vector kept for several ids is listed
+    /// Returns `None` if the index has no tree at `tree_index`
+    pub fn leaves(&self, tree_index: usize) -> Option<Vec<Vec<i32>>> {
+        let tree = self.trees.get(tree_index)?;
+        let leaves = leaves(tree)
+            .into_iter()
+            .map(|leaf| leaf.positions().flat_map(|idx| self.ids_at(idx)).collect())
             .collect();
+        Some(leaves)
+    }
 
-        // cartesian eq for hyperplane n * (x - x_0) = 0
-        // n (normal vector) is the coefs x_1 to x_n
-        let (a, b) = (*sample[0], *sample[1]);
-        let coefficients = all_vecs[b].sub(&all_vecs[a]);
-        let point_on_plane = all_vecs[a].avg(&all_vecs[b]);
-        let constant = -coefficients.dot(&point_on_plane);
-        let hyperplane = HyperPlane::new(coefficients, constant);
-
-        let (mut above, mut below) = (vec![], vec![]);
-        for &id in indexes.iter() {
-            if hyperplane.is_point_above(&all_vecs[id]) {
-                above.push(id)
-            } else {
-                below.push(id)
-            };
-        }
-        (hyperplane, above, below)
-    }
-
-    fn deduplicate(
-        vectors: &Vec<Vector<N>>,
-        ids: &[i32],
-        dedup_vectors: &mut Vec<Vector<N>>,
-        dedup_ids: &mut Vec<i32>,
-    ) {
-        let mut hashes_seen = HashSet::new();
-        for i in 0..vectors.len() {
-            let hash_key = vectors[i].hashkey();
-            if !hashes_seen.contains(&hash_key) {
-                hashes_seen.insert(hash_key);
-                dedup_vectors.push(vectors[i]);
-                dedup_ids.push(ids[i]);
+    /// The depth and leaf sizes of each tree in the index, in the order the trees were built
+    pub fn tree_stats(&self) -> Vec<TreeStats> {
+        self.trees.iter().map(tree_stats).collect()
+    }
+
+    /// An approximate number of bytes of memory the index occupies, for capacity planning
+    /// Counts the vectors with their cached norms and ids, the id lookup map and extra ids of
+    /// duplicates, and every tree node with its hyperplane and leaf positions
+    ///
+    /// NOTE:
+    /// This is an estimate, the spare capacity of the vector buffers, the hashing overhead of the
+    /// maps beyond their entries, and allocator overhead are not counted
+    pub fn memory_usage(&self) -> usize {
+        let vectors =
+            self.len() * (size_of::<Vector<N, T>>() + 2 * size_of::<T>() + size_of::<i32>());
+        let id_to_index = self.id_to_index.len() * size_of::<(i32, usize)>()
+            + self.repeated_ids.len() * size_of::<i32>();
+        let aliases: usize = self
+            .aliases
+            .values()
+            .map(|ids| size_of::<(usize, Vec<i32>)>() + ids.len() * size_of::<i32>())
+            .sum();
+        let trees: usize = self.trees.iter().map(tree_memory_usage).sum();
+        size_of::<Self>() + vectors + id_to_index + aliases + trees
+    }
+
+    /// Save the index to a binary file at `path`
+    /// The file contains a versioned header with the dimension `N`, the vectors, the ids (with the
+    /// extra ids of vectors kept for several ids), and the full structure of every tree so the
+    /// index can be loaded without rebuilding the trees
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Load an index previously written by `save`
+    /// Returns an `InvalidData` error if the file is not an index, was written by an unsupported
+    /// version, or holds vectors of a different dimension than `N`
+    /// The loaded index runs on the global rayon thread pool, see `set_thread_pool`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::read_from(&mut reader)
+    }
+
+    /// Memory-map an index previously written by `save` instead of loading it into memory
+    /// See `MmapIndex`, the mapped index is read-only and reads vectors from the file as needed
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap(path: &Path) -> io::Result<MmapIndex<N, T>> {
+        MmapIndex::open(path)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = Header {
+            metric: self.metric,
+            split_strategy: self.split_strategy,
+            split_sample_size: self.split_sample_size,
+            max_size: self.max_size,
+            max_depth: self.max_depth,
+            keep_duplicates: self.keep_duplicates,
+            num_vectors: self.vectors.len(),
+            num_aliases: self.num_results() - self.ids.len(),
+            num_trees: self.trees.len(),
+        };
+        write_header::<W, N, T>(writer, &header)?;
+        for vector in &self.vectors {
+            vector.write_to(writer)?;
+        }
+        for &id in &self.ids {
+            write_i32(writer, id)?;
+        }
+        // every alias is the position of its vector followed by the id
+        for (&index, ids) in self.aliases.iter().sorted_by_key(|(&index, _)| index) {
+            for &id in ids {
+                write_u64(writer, index as u64)?;
+                write_i32(writer, id)?;
             }
         }
+        for tree in &self.trees {
+            write_tree(writer, tree)?;
+        }
+        Ok(())
     }
 
-    fn query_tree(
-        query: Vector<N>,
-        n: i32,
-        tree: &TreeNode<N>,
-        candidates: &DashSet<usize>,
-    ) -> i32 {
-        match tree {
-            TreeNode::Leaf(box_leaf) => {
-                let leaf_values = &(box_leaf.value());
-                let num_candidates_found = min(n as usize, leaf_values.len());
-                for item in leaf_values.iter().take(num_candidates_found) {
-                    candidates.insert(*item);
-                }
-                num_candidates_found as i32
-            }
-            TreeNode::Branch(inner) => {
-                let above = (*inner).hyperplane().is_point_above(&query);
-                let (main, backup) = match above {
-                    true => {
-                        let main = inner.right();
-                        let backup = inner.left();
-                        (main, backup)
-                    }
-                    false => {
-                        let main = inner.left();
-                        let backup = inner.right();
-                        (main, backup)
-                    }
-                };
-                match Self::query_tree(query, n, main, candidates) {
-                    k if k < n => k + Self::query_tree(query, n - k, backup, candidates),
-                    k => k,
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let Header {
+            metric,
+            split_strategy,
+            split_sample_size,
+            max_size,
+            max_depth,
+            keep_duplicates,
+            num_vectors,
+            num_aliases,
+            num_trees,
+        } = read_header::<R, N, T>(reader)?;
+
+        let vectors = (0..num_vectors)
+            .map(|_| Vector::read_from(reader))
+            .collect::<io::Result<Vec<_>>>()?;
+        let ids = (0..num_vectors)
+            .map(|_| read_i32(reader))
+            .collect::<io::Result<Vec<_>>>()?;
+        let aliases = (0..num_aliases)
+            .map(|_| Ok((read_alias_index(reader, num_vectors)?, read_i32(reader)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let trees = (0..num_trees)
+            .map(|_| read_tree(reader, num_vectors))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (id_to_index, repeated_ids) = index_ids(&ids);
+        let mut index = ApproximateNearestNeighborsIndex {
+            norms: squared_norms(&vectors),
+            inverse_norms: inverse_norms(&vectors),
+            vectors,
+            id_to_index,
+            repeated_ids,
+            ids,
+            trees,
+            metric,
+            split_strategy,
+            split_sample_size,
+            max_size,
+            max_depth,
+            aliases: HashMap::new(),
+            keep_duplicates,
+            pool: None,
+            rng: index_rng(None),
+        };
+        for (position, id) in aliases {
+            index.alias(position, id);
+        }
+        Ok(index)
+    }
+
+    /// The settings the trees of the index were built with, reused to split leaves on insert
+    /// A seed for every tree drawn from the index's randomness, so each tree can be changed in
+    /// parallel while the result only depends on the seed of the index
+    fn tree_seeds(&mut self) -> Vec<u64> {
+        (0..self.trees.len()).map(|_| self.rng.gen()).collect()
+    }
+
+    fn tree_config(&self) -> TreeConfig {
+        TreeConfig {
+            max_size: self.max_size,
+            max_depth: self.max_depth,
+            metric: self.metric,
+            strategy: self.split_strategy,
+            sample_size: self.split_sample_size,
+        }
+    }
+
+    /// Rank the vectors at `candidates` by their distance to `query` and keep the closest `top_k`
+    /// The distances are computed in parallel, only the final sort runs on a single thread
+    /// Candidates are ranked with the metric's `ranking`, see `ranking_distance`
+    fn rank(
+        &self,
+        candidates: impl IntoIterator<Item = usize>,
+        query: &Vector<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.rank_where(candidates, query, top_k, |_| true)
+    }
+
+    /// Rank the `candidates` the same way as `rank`, only returning results for ids that satisfy `pred`
+    fn rank_where(
+        &self,
+        candidates: impl IntoIterator<Item = usize>,
+        query: &Vector<N, T>,
+        top_k: i32,
+        pred: impl Fn(i32) -> bool,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.rank_prepared(candidates, &PreparedQuery::new(*query), top_k, pred)
+    }
+
+    /// Rank the `candidates` the same way as `rank_where`, for a query that is already prepared
+    fn rank_prepared(
+        &self,
+        candidates: impl IntoIterator<Item = usize>,
+        query: &PreparedQuery<N, T>,
+        top_k: i32,
+        pred: impl Fn(i32) -> bool,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let distances = self.ranking_distances(candidates, query);
+        self.closest(distances, query.vector(), top_k, pred)
+            .collect_vec()
+    }
+
+    /// The distance between each of the `candidates` and `query` under the ranking metric,
+    /// computed in parallel
+    fn ranking_distances(
+        &self,
+        candidates: impl IntoIterator<Item = usize>,
+        query: &PreparedQuery<N, T>,
+    ) -> Vec<(usize, T)> {
+        let ranking = self.metric.ranking();
+        let candidates = candidates.into_iter().collect_vec();
+        install(&self.pool, || {
+            candidates
+                .into_par_iter()
+                .map(|idx| (idx, self.ranking_distance(ranking, idx, query)))
+                .collect()
+        })
+    }
+
+    /// The distance between the vector at `idx` and `query` under the `ranking` metric
+    /// Squared euclidian and cosine distances are computed from the cached norms of the vector and
+    /// of the prepared query, so each costs a single dot product: the squared euclidian distance
+    /// is expanded into `‖a‖² + ‖b‖² − 2a·b`, and the cosine similarity is `a·b` scaled by the
+    /// inverse norms, without the query's for a query prepared as normalized
+    fn ranking_distance(
+        &self,
+        ranking: DistanceMetric,
+        idx: usize,
+        query: &PreparedQuery<N, T>,
+    ) -> T {
+        let vector = &self.vectors[idx];
+        match ranking {
+            DistanceMetric::SquaredEuclidean => {
+                let dot = vector.dot(query.vector());
+                self.norms[idx] + query.squared_norm() - (dot + dot)
+            }
+            DistanceMetric::Cosine => {
+                let similarity = vector.dot(query.vector()) * self.inverse_norms[idx];
+                match query.is_normalized() {
+                    true => T::ONE - similarity,
+                    false => T::ONE - similarity * query.inverse_norm(),
                 }
             }
+            metric => metric.distance(vector, query.vector()),
+        }
+    }
+
+    /// Keep the `top_k` closest of the candidates and their `distances` under the ranking metric
+    /// The kept results report their exact distance under the metric, as the expanded squared
+    /// euclidian distance of `ranking_distance` loses precision to cancellation
+    /// Equal distances are ordered by id, so the results do not depend on the order the
+    /// candidates were gathered in
+    /// Every id of a candidate that satisfies `pred` is a separate result
+    /// The results are ranked up front, but built from the stored vectors as they are iterated
+    fn closest<'a>(
+        &'a self,
+        distances: Vec<(usize, T)>,
+        query: &Vector<N, T>,
+        top_k: i32,
+        pred: impl Fn(i32) -> bool + 'a,
+    ) -> impl Iterator<Item = ApproximateNearestNeighborsSearchResult<N, T>> + 'a {
+        let pred = &pred;
+        // each candidate with a passing id yields at least one result, so `top_k` of them suffice
+        let passing = distances
+            .into_iter()
+            .filter(|&(idx, _)| self.ids_at(idx).any(pred))
+            .map(|(idx, distance)| ((self.ids[idx], idx), distance));
+        smallest_k(passing, top_k as usize)
+            .into_iter()
+            .map(|((_, idx), _)| (idx, self.metric.distance(&self.vectors[idx], query)))
+            .flat_map(|(idx, dis)| {
+                self.ids_at(idx)
+                    .filter(move |&id| pred(id))
+                    .map(move |id| (idx, id, dis))
+            })
+            .sorted_by(|a, b| self.by_distance(a, b))
+            .take(top_k as usize)
+            .map(move |(idx, id, dis)| self.result(idx, id, dis))
+    }
+
+    /// Order `(position, id, distance)` results by distance, breaking ties by id then position
+    /// so equally distant results are always returned in the same order
+    fn by_distance(&self, a: &(usize, i32, T), b: &(usize, i32, T)) -> Ordering {
+        compare_distances(&a.2, &b.2)
+            .then(a.1.cmp(&b.1))
+            .then(a.0.cmp(&b.0))
+    }
+
+    /// The candidates gathered by `collect`, or every vector if the index has no trees,
+    /// see `candidates_or_scan`
+    fn candidates_from<C: FromIterator<usize>>(&self, collect: impl FnOnce() -> C) -> C {
+        candidates_or_scan(&self.trees, self.vectors.len(), collect)
+    }
+
+    /// Every id of the vector at `idx`, the id in `ids` followed by its aliases
+    fn ids_at(&self, idx: usize) -> impl Iterator<Item = i32> + '_ {
+        let aliases = self.aliases.get(&idx).into_iter().flatten().copied();
+        std::iter::once(self.ids[idx]).chain(aliases)
+    }
+
+    /// Map `id` to the vector at `index` unless an earlier vector has the id,
+    /// in which case the id is repeated and only the earlier vector is mapped
+    fn index_id(&mut self, id: i32, index: usize) {
+        match self.id_to_index.entry(id) {
+            Entry::Occupied(_) => {
+                self.repeated_ids.insert(id);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+            }
+        }
+    }
+
+    /// Record `id` as another id of the vector at `idx`, unless the vector already has the id
+    fn alias(&mut self, idx: usize, id: i32) {
+        if !self.ids_at(idx).any(|other| other == id) {
+            self.aliases.entry(idx).or_default().push(id);
+            self.id_to_index.entry(id).or_insert(idx);
+        }
+    }
+
+    /// The number of results a search can return, one for every id of every vector
+    fn num_results(&self) -> usize {
+        self.ids.len() + self.aliases.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// The result for the vector at `idx` with `id` at `distance` from the query under the index's metric
+    fn result(
+        &self,
+        idx: usize,
+        id: i32,
+        distance: T,
+    ) -> ApproximateNearestNeighborsSearchResult<N, T> {
+        ApproximateNearestNeighborsSearchResult {
+            vector_id: id,
+            distance: self.metric.report(distance),
+            vector: self.vectors[idx],
+        }
+    }
+
+    /// Take the index apart for an index that stores the vectors differently, see `QuantizedIndex`
+    pub(crate) fn into_parts(self) -> IndexParts<N, T> {
+        IndexParts {
+            vectors: self.vectors,
+            ids: self.ids,
+            aliases: self.aliases,
+            trees: self.trees,
+            metric: self.metric,
+            pool: self.pool,
+        }
+    }
+
+    pub(crate) fn validate_input(
+        vectors: &[Vector<N, T>],
+        vector_ids: &[i32],
+    ) -> Result<(), BuildError> {
+        if vectors.len() != vector_ids.len() {
+            return Err(BuildError::LengthMismatch {
+                vectors: vectors.len(),
+                ids: vector_ids.len(),
+            });
+        }
+        if vectors.is_empty() {
+            return Err(BuildError::Empty);
+        }
+        if vectors.len() > MAX_VECTORS {
+            return Err(BuildError::TooManyVectors {
+                vectors: vectors.len(),
+                max: MAX_VECTORS,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A one-line summary of the index for logs, the vectors and trees themselves are never printed
+/// e.g. `Index(dim=128, vectors=10234, trees=10, metric=SquaredEuclidean, leaves=2650, max_depth=12)`
+impl<const N: usize, T: VectorScalar> fmt::Debug for ApproximateNearestNeighborsIndex<N, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.tree_stats();
+        write!(
+            f,
+            "Index(dim={}, vectors={}, trees={}, metric={:?}, leaves={}, max_depth={})",
+            N,
+            self.len(),
+            self.num_trees(),
+            self.metric,
+            stats.iter().map(|stats| stats.leaf_count).sum::<usize>(),
+            stats.iter().map(|stats| stats.max_depth).max().unwrap_or(0),
+        )
+    }
+}
+
+/// The squared norm of each of `vectors`, see `ranking_distance`
+fn squared_norms<const N: usize, T: VectorScalar>(vectors: &[Vector<N, T>]) -> Vec<T> {
+    vectors.iter().map(|vector| vector.dot(vector)).collect()
+}
+
+/// One over the norm of each of `vectors`, see `ranking_distance`
+fn inverse_norms<const N: usize, T: VectorScalar>(vectors: &[Vector<N, T>]) -> Vec<T> {
+    vectors
+        .iter()
+        .map(|vector| inverse_norm(vector.dot(vector)))
+        .collect()
+}
+
+/// Map every id to the position of the first vector with that id,
+/// also returning the ids held by more than one vector
+fn index_ids(ids: &[i32]) -> (HashMap<i32, usize>, HashSet<i32>) {
+    let (mut id_to_index, mut repeated_ids) = (HashMap::with_capacity(ids.len()), HashSet::new());
+    for (index, &id) in ids.iter().enumerate() {
+        match id_to_index.entry(id) {
+            Entry::Occupied(_) => {
+                repeated_ids.insert(id);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+            }
+        }
+    }
+    (id_to_index, repeated_ids)
+}
+
+/// Merge the results of the same query against several indexes (shards) into a single top-k
+/// Each list of `results` must be sorted the way searches return them, by distance then id, and
+/// the lists are merged lazily so only the results that make the top-k are compared
+/// An id returned by several shards is kept once, with its closest result
+/// The shards should share a `DistanceMetric`, distances of different metrics do not compare
+pub fn merge_results<const N: usize, T: VectorScalar>(
+    results: Vec<Vec<ApproximateNearestNeighborsSearchResult<N, T>>>,
+    top_k: i32,
+) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+    let Some(top_k) = clamp_top_k(top_k, results.iter().map(Vec::len).sum()) else {
+        return vec![];
+    };
+    let mut seen = HashSet::new();
+    results
+        .into_iter()
+        .kmerge()
+        .filter(|result| seen.insert(result.vector_id))
+        .take(top_k as usize)
+        .collect()
+}
+
+/// Clamp `top_k` to the `len` vectors that can be returned, or `None` if `top_k` is not positive
+/// so searches return no results rather than casting a negative `top_k` to a huge count
+pub(crate) fn clamp_top_k(top_k: i32, len: usize) -> Option<i32> {
+    let len = i32::try_from(len).unwrap_or(i32::MAX);
+    (top_k > 0).then(|| top_k.min(len))
+}
+
+/// Run `op` inside `pool` so its parallel work is confined to that pool,
+/// or on the current (by default the global) rayon pool if there is none
+/// On `wasm32` every parallel iterator runs on the calling thread, so `op` runs there directly
+pub(crate) fn install<R: Send>(pool: &Option<Arc<ThreadPool>>, op: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) if cfg!(not(target_arch = "wasm32")) => pool.install(op),
+        _ => op(),
+    }
+}
+
+/// The randomness of an index, seeded with `seed` or from the operating system without one
+fn index_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Deduplicate `vectors` by their hashkey, keeping the first vector (and its id) for each key
+/// Returns the id of every dropped vector with the position of the kept vector it duplicates
+pub(crate) fn deduplicate<V: VectorOps>(
+    vectors: &[V],
+    ids: &[i32],
+    dedup_vectors: &mut Vec<V>,
+    dedup_ids: &mut Vec<i32>,
+) -> Vec<(i32, usize)> {
+    let mut duplicates = vec![];
+    let mut kept_positions = HashMap::new();
+    for i in 0..vectors.len() {
+        match kept_positions.entry(vectors[i].hashkey()) {
+            Entry::Occupied(kept) => duplicates.push((ids[i], *kept.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(dedup_vectors.len());
+                dedup_vectors.push(vectors[i].clone());
+                dedup_ids.push(ids[i]);
+            }
+        }
+    }
+    duplicates
+}
+
+/// Deduplicate `vectors` the same way as `deduplicate`, but also drop every vector within `epsilon`
+/// euclidian distance of a vector that was already kept
+/// Each vector is compared against all kept vectors, so this is quadratic in the number of vectors
+fn deduplicate_within<const N: usize, T: VectorScalar>(
+    vectors: &[Vector<N, T>],
+    ids: &[i32],
+    epsilon: f32,
+    dedup_vectors: &mut Vec<Vector<N, T>>,
+    dedup_ids: &mut Vec<i32>,
+) -> Vec<(i32, usize)> {
+    let max_squared = (epsilon as f64).powi(2);
+    let mut duplicates = vec![];
+    let mut kept_positions = HashMap::new();
+    for (vector, &id) in vectors.iter().zip(ids) {
+        let hash_key = vector.hashkey();
+        let kept = kept_positions.get(&hash_key).copied().or_else(|| {
+            dedup_vectors
+                .iter()
+                .position(|kept| vector.squared_euclidian_distance(kept).to_f64() <= max_squared)
+        });
+        match kept {
+            Some(kept) => duplicates.push((id, kept)),
+            None => {
+                dedup_vectors.push(*vector);
+                dedup_ids.push(id);
+            }
         }
+        kept_positions
+            .entry(hash_key)
+            .or_insert(kept.unwrap_or(dedup_vectors.len() - 1));
     }
+    duplicates
 }
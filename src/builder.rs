@@ -0,0 +1,298 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::ann_index::{ApproximateNearestNeighborsIndex, DedupReport};
+use crate::error::BuildError;
+use crate::metric::DistanceMetric;
+use crate::payload_index::PayloadIndex;
+use crate::quantized_index::QuantizedIndex;
+use crate::scalar::VectorScalar;
+use crate::split::SplitStrategy;
+use crate::tree::{TreeConfig, TreeProgress};
+use crate::vector::Vector;
+
+/// A builder for configuring and constructing an `ApproximateNearestNeighborsIndex`
+/// Any setting that is not configured falls back to a sensible default:
+/// 10 trees, a maximum of 16 vectors per leaf with no depth limit, a random seed, the default `DistanceMetric`
+/// and `SplitStrategy` with splits sampled from pairs of vectors, exact deduplication, and the global rayon thread pool
+#[derive(Debug, Clone)]
+pub struct IndexBuilder<const N: usize, T = f32> {
+    pub(crate) num_trees: i32,
+    pub(crate) max_size: i32,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) metric: DistanceMetric,
+    pub(crate) split_strategy: SplitStrategy,
+    pub(crate) split_sample_size: usize,
+    pub(crate) pool: Option<Arc<ThreadPool>>,
+    pub(crate) max_threads: Option<usize>,
+    pub(crate) dedup_epsilon: Option<f32>,
+    pub(crate) keep_duplicates: bool,
+    pub(crate) on_tree_built: Option<TreeBuiltCallback>,
+    marker: PhantomData<T>,
+}
+
+/// The callback set with `IndexBuilder::on_tree_built`, shared by clones of the builder
+#[derive(Clone)]
+pub(crate) struct TreeBuiltCallback(pub(crate) Arc<TreeProgress>);
+
+impl fmt::Debug for TreeBuiltCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TreeBuiltCallback")
+    }
+}
+
+impl<const N: usize, T: VectorScalar> IndexBuilder<N, T> {
+    pub fn new() -> Self {
+        Self {
+            num_trees: 10,
+            max_size: 16,
+            max_depth: None,
+            seed: None,
+            metric: DistanceMetric::default(),
+            split_strategy: SplitStrategy::default(),
+            split_sample_size: 2,
+            pool: None,
+            max_threads: None,
+            dedup_epsilon: None,
+            keep_duplicates: false,
+            on_tree_built: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// The number of trees to build, more trees improve recall at the cost of memory and latency
+    /// With zero trees searches fall back to scanning every vector, returning the exact neighbors
+    pub fn num_trees(mut self, num_trees: i32) -> Self {
+        self.num_trees = num_trees;
+        self
+    }
+
+    /// The maximum number of vectors stored in a single leaf of a tree
+    pub fn max_leaf_size(mut self, max_size: i32) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// The maximum number of branches between the root of a tree and any leaf, bounding build time
+    /// Whichever of `max_leaf_size` and `max_depth` is reached first turns a node into a leaf,
+    /// so a node at `max_depth` stays a leaf even if it holds more than `max_leaf_size` vectors
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Seed the construction of the trees so the index is built deterministically,
+    /// the built index also draws the randomness of later inserts from the seed, see `set_seed`
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// The distance metric used to rank search results
+    pub fn metric(mut self, metric: DistanceMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Build an angular index the way Annoy handles cosine similarity, using `DistanceMetric::Angular`
+    /// Only the direction of a vector matters: the trees split the normalized vectors by hyperplanes
+    /// through the origin, the norms are cached when the index is built so ranking a candidate costs
+    /// a single dot product, and results report the angular distance `sqrt(2 - 2cos)`
+    pub fn angular(self) -> Self {
+        self.metric(DistanceMetric::Angular)
+    }
+
+    /// How the vectors of an oversized node are split between its children
+    pub fn split_strategy(mut self, split_strategy: SplitStrategy) -> Self {
+        self.split_strategy = split_strategy;
+        self
+    }
+
+    /// The number of vectors sampled to derive each `SplitStrategy::RandomProjection` split, 2 by default
+    /// With 2 the split bisects the pair, with more the sample is divided around two of its vectors
+    /// and the split bisects the centroids of the two halves, which varies less on noisy data at
+    /// the cost of a slower build. Values below 2 are treated as 2, `KMeans2` is not affected
+    pub fn split_sample_size(mut self, sample_size: usize) -> Self {
+        self.split_sample_size = sample_size.max(2);
+        self
+    }
+
+    /// Treat vectors within `epsilon` euclidian distance of an earlier vector as duplicates of it,
+    /// instead of only dropping vectors that are bit-for-bit identical
+    /// Every vector is compared against every vector kept so far, so building is quadratic in the
+    /// number of vectors, exact deduplication by hashkey stays the default
+    /// The epsilon only applies to the vectors the index is built from, it is not stored on the
+    /// index, so `insert`, `extend` and `merge` still only reject vectors with an indexed hashkey
+    pub fn dedup_epsilon(mut self, epsilon: f32) -> Self {
+        self.dedup_epsilon = Some(epsilon);
+        self
+    }
+
+    /// Keep every id of duplicate vectors instead of dropping the later ones, for identical vectors
+    /// that stand for distinct items: the vector is stored once, and searches return a result for
+    /// each of its ids. Inserting a vector that is already indexed adds its id instead of failing
+    /// Nothing is dropped, so the report of `build_with_report` is empty
+    pub fn keep_duplicates(mut self, keep_duplicates: bool) -> Self {
+        self.keep_duplicates = keep_duplicates;
+        self
+    }
+
+    /// Call `on_tree_built` with the number of trees built so far and the total number of trees
+    /// every time a tree is completed, e.g. to drive a progress bar or estimate the remaining time
+    /// Trees are built in parallel, so the callback may be invoked from several threads at once,
+    /// every count from 1 to the total is reported exactly once but not necessarily in order
+    pub fn on_tree_built(
+        mut self,
+        on_tree_built: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_tree_built = Some(TreeBuiltCallback(Arc::new(on_tree_built)));
+        self
+    }
+
+    /// Run the construction and the parallel work of the built index inside `pool`
+    /// instead of the global rayon thread pool, capping the parallelism of the index
+    /// Replaces any limit set with `max_threads`
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.pool = Some(pool);
+        self.max_threads = None;
+        self
+    }
+
+    /// Run the construction and the parallel work of the built index on a dedicated pool of
+    /// `max_threads` threads, `max_threads(1)` runs everything on a single thread
+    /// The pool is created when the index is built and replaces any pool set with `thread_pool`,
+    /// a `max_threads` of 0 lets rayon pick the number of threads
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self.pool = None;
+        self
+    }
+
+    /// Build an index of `vectors`, where `ids[i]` is the id of `vectors[i]`
+    /// Returns `BuildError::LengthMismatch` if the number of vectors and ids differ,
+    /// `BuildError::Empty` if no vectors are supplied, and `BuildError::TooManyVectors` if more
+    /// than `MAX_VECTORS` are supplied
+    /// Returns `BuildError::InvalidLeafSize` if `max_leaf_size` is below 1
+    /// Returns `BuildError::ThreadPool` if the pool for `max_threads` cannot be created
+    pub fn build(
+        &self,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+    ) -> Result<ApproximateNearestNeighborsIndex<N, T>, BuildError> {
+        self.build_with_report(vectors, ids).map(|(index, _)| index)
+    }
+
+    /// Build an index the same way as `build`, also reporting the ids dropped as duplicates,
+    /// see `ApproximateNearestNeighborsIndex::build_with_report`
+    pub fn build_with_report(
+        &self,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+    ) -> Result<(ApproximateNearestNeighborsIndex<N, T>, DedupReport), BuildError> {
+        let never = AtomicBool::new(false);
+        self.build_until(vectors, ids, &never)
+    }
+
+    /// Build an index the same way as `build`, abandoning the build as soon as `cancel` is set,
+    /// for example from another thread when the index is no longer needed
+    /// The flag is checked before every tree and before every node of a tree is split, so a
+    /// cancelled build returns promptly and the trees built so far are dropped
+    /// Returns `BuildError::Cancelled` if `cancel` is set before the build completes,
+    /// and the same errors as `build` otherwise
+    pub fn build_cancellable(
+        &self,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+        cancel: &AtomicBool,
+    ) -> Result<ApproximateNearestNeighborsIndex<N, T>, BuildError> {
+        self.build_until(vectors, ids, cancel)
+            .map(|(index, _)| index)
+    }
+
+    /// Validate the input and build an index inside the pool requested with `max_threads`,
+    /// returning `BuildError::Cancelled` as soon as `cancel` is set
+    fn build_until(
+        &self,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+        cancel: &AtomicBool,
+    ) -> Result<(ApproximateNearestNeighborsIndex<N, T>, DedupReport), BuildError> {
+        ApproximateNearestNeighborsIndex::validate_input(vectors, ids)?;
+        if self.max_size < 1 {
+            return Err(BuildError::InvalidLeafSize {
+                max_size: self.max_size,
+            });
+        }
+        let builder = match self.max_threads {
+            // every parallel iterator runs on the calling thread on wasm32, so there is no pool
+            Some(max_threads) if cfg!(not(target_arch = "wasm32")) => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(max_threads)
+                    .build()
+                    .map_err(|error| BuildError::ThreadPool {
+                        message: error.to_string(),
+                    })?;
+                self.clone().thread_pool(Arc::new(pool))
+            }
+            _ => self.clone(),
+        };
+        ApproximateNearestNeighborsIndex::from_builder_until(&builder, vectors, ids, cancel)
+            .ok_or(BuildError::Cancelled)
+    }
+
+    /// Build a `PayloadIndex` of `vectors`, where `ids[i]` and `payloads[i]` belong to `vectors[i]`
+    /// Returns the same errors as `build`, and `BuildError::PayloadLengthMismatch` if the number
+    /// of vectors and payloads differ
+    pub fn build_with_payloads<P>(
+        &self,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+        payloads: Vec<P>,
+    ) -> Result<PayloadIndex<N, P, T>, BuildError> {
+        PayloadIndex::from_builder(self, vectors, ids, payloads)
+    }
+
+    /// The settings the trees are split with
+    pub(crate) fn tree_config(&self) -> TreeConfig {
+        TreeConfig {
+            max_size: self.max_size,
+            max_depth: self.max_depth,
+            metric: self.metric,
+            strategy: self.split_strategy,
+            sample_size: self.split_sample_size,
+        }
+    }
+}
+
+impl<const N: usize> IndexBuilder<N, f32> {
+    /// Build a `QuantizedIndex` of `vectors`, storing every vector as `N` bytes instead of `N` floats
+    /// Returns the same errors as `build`
+    pub fn build_quantized(
+        &self,
+        vectors: &[Vector<N>],
+        ids: &[i32],
+    ) -> Result<QuantizedIndex<N>, BuildError> {
+        QuantizedIndex::from_builder(self, vectors, ids, false)
+    }
+
+    /// Build a `QuantizedIndex` of `vectors` the same way as `build_quantized`, but also retain the
+    /// original vectors so results can be reranked exactly with `QuantizedIndex::search_reranked`
+    pub fn build_quantized_with_originals(
+        &self,
+        vectors: &[Vector<N>],
+        ids: &[i32],
+    ) -> Result<QuantizedIndex<N>, BuildError> {
+        QuantizedIndex::from_builder(self, vectors, ids, true)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> Default for IndexBuilder<N, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
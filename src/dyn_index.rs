@@ -0,0 +1,164 @@
+use itertools::Itertools;
+use rand::Rng;
+
+use crate::ann_index::{clamp_top_k, deduplicate, MAX_VECTORS};
+use crate::dyn_vector::DynVector;
+use crate::error::{BuildError, DimError};
+use crate::metric::{smallest_k, DistanceMetric};
+use crate::parallel::*;
+use crate::split::SplitStrategy;
+use crate::tree::{build_trees, candidates_or_scan, collect_candidates, TreeConfig, TreeNode};
+
+/// A search result from a `DynApproximateNearestNeighborsIndex`
+/// Each result contains the vector id, the distance from the query vector, and the vector itself
+#[derive(Debug, Clone)]
+pub struct DynApproximateNearestNeighborsSearchResult {
+    pub vector_id: i32,
+    pub distance: f32,
+    pub vector: DynVector,
+}
+
+/// An index of vectors whose dimension is only known at runtime
+/// This builds and searches the same trees as `ApproximateNearestNeighborsIndex`, but over
+/// `DynVector`s, with the dimension of the index taken from the vectors it is built from
+pub struct DynApproximateNearestNeighborsIndex {
+    dimension: usize,
+    vectors: Vec<DynVector>,
+    ids: Vec<i32>,
+    trees: Vec<TreeNode<DynVector>>,
+    metric: DistanceMetric,
+}
+
+impl DynApproximateNearestNeighborsIndex {
+    /// Build an index of vectors by constructing a tree of the vectors
+    /// The index will contain `num_trees` trees, each with a maximum of `max_size` vectors
+    /// With `num_trees` zero no trees are built and searches scan every vector, returning the exact
+    /// nearest neighbors
+    /// Returns `BuildError::DimensionMismatch` if the vectors do not all share the same dimension,
+    /// `BuildError::TooManyVectors` if more than `MAX_VECTORS` vectors are supplied, and
+    /// `BuildError::InvalidLeafSize` if `max_size` is below 1
+    pub fn build(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[DynVector],
+        vector_ids: &[i32],
+    ) -> Result<DynApproximateNearestNeighborsIndex, BuildError> {
+        Self::build_with_metric(
+            num_trees,
+            max_size,
+            vectors,
+            vector_ids,
+            DistanceMetric::default(),
+        )
+    }
+
+    /// Build an index of vectors the same way as `build`, but rank search results with `metric`
+    pub fn build_with_metric(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[DynVector],
+        vector_ids: &[i32],
+        metric: DistanceMetric,
+    ) -> Result<DynApproximateNearestNeighborsIndex, BuildError> {
+        if vectors.len() != vector_ids.len() {
+            return Err(BuildError::LengthMismatch {
+                vectors: vectors.len(),
+                ids: vector_ids.len(),
+            });
+        }
+        let dimension = vectors.first().ok_or(BuildError::Empty)?.dimension();
+        if vectors.len() > MAX_VECTORS {
+            return Err(BuildError::TooManyVectors {
+                vectors: vectors.len(),
+                max: MAX_VECTORS,
+            });
+        }
+        if let Some(vector) = vectors.iter().find(|v| v.dimension() != dimension) {
+            return Err(BuildError::DimensionMismatch {
+                expected: dimension,
+                found: vector.dimension(),
+            });
+        }
+        if max_size < 1 {
+            return Err(BuildError::InvalidLeafSize { max_size });
+        }
+
+        let (mut unique_vecs, mut ids) = (vec![], vec![]);
+        deduplicate(vectors, vector_ids, &mut unique_vecs, &mut ids);
+        let seed = rand::thread_rng().gen();
+        let trees = build_trees(
+            num_trees,
+            TreeConfig {
+                max_size,
+                max_depth: None,
+                metric,
+                strategy: SplitStrategy::default(),
+                sample_size: 2,
+            },
+            &unique_vecs,
+            seed,
+            None,
+        );
+
+        Ok(DynApproximateNearestNeighborsIndex {
+            dimension,
+            vectors: unique_vecs,
+            ids,
+            trees,
+            metric,
+        })
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector
+    /// See `ApproximateNearestNeighborsIndex::search` for details on the approximation
+    /// Returns a `DimError` if the dimension of `query` differs from the dimension of the index
+    pub fn search(
+        &self,
+        query: &DynVector,
+        top_k: i32,
+    ) -> Result<Vec<DynApproximateNearestNeighborsSearchResult>, DimError> {
+        if query.dimension() != self.dimension {
+            return Err(DimError {
+                expected: self.dimension,
+                found: query.dimension(),
+            });
+        }
+        let Some(top_k) = clamp_top_k(top_k, self.vectors.len()) else {
+            return Ok(vec![]);
+        };
+        let candidates = candidates_or_scan(&self.trees, self.vectors.len(), || {
+            collect_candidates(&self.trees, query, top_k)
+                .into_iter()
+                .collect_vec()
+        });
+        let ranking = self.metric.ranking();
+        let distances: Vec<_> = candidates
+            .into_par_iter()
+            .map(|idx| (idx, ranking.distance(&self.vectors[idx], query)))
+            .collect();
+        let distances = distances
+            .into_iter()
+            .map(|(idx, distance)| ((self.ids[idx], idx), distance));
+        let results = smallest_k(distances, top_k as usize)
+            .into_iter()
+            .map(
+                |((_, idx), dis)| DynApproximateNearestNeighborsSearchResult {
+                    vector_id: self.ids[idx],
+                    distance: self.metric.unrank(dis),
+                    vector: self.vectors[idx].clone(),
+                },
+            )
+            .collect_vec();
+        Ok(results)
+    }
+
+    /// The dimension every vector in the index shares
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// The distance metric used to rank search results
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+}
@@ -0,0 +1,216 @@
+use rand::Rng;
+
+use crate::scalar::VectorScalar;
+use crate::vector::VectorOps;
+
+/// A vector whose dimension is only known at runtime, backed by a `Vec<f32>`
+/// Operations between two `DynVector`s assume both have the same dimension,
+/// `DynApproximateNearestNeighborsIndex` validates this when an index is built or searched
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynVector {
+    values: Vec<f32>,
+}
+
+impl DynVector {
+    pub fn new(values: Vec<f32>) -> Self {
+        Self { values }
+    }
+
+    pub fn random(dimension: usize, lower_bound: Option<f32>, upper_bound: Option<f32>) -> Self {
+        let lower_bound = lower_bound.unwrap_or(-1.0);
+        let upper_bound = upper_bound.unwrap_or(1.0);
+        let mut rng = rand::thread_rng();
+        let values = (0..dimension)
+            .map(|_| rng.gen_range(lower_bound..upper_bound))
+            .collect();
+
+        Self { values }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn dot(&self, vector: &DynVector) -> f32 {
+        self.values
+            .iter()
+            .zip(&vector.values)
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+    }
+
+    pub fn avg(&self, vector: &DynVector) -> DynVector {
+        let average = self
+            .values
+            .iter()
+            .zip(&vector.values)
+            .map(|(a, b)| (a + b) / 2.0)
+            .collect();
+
+        DynVector { values: average }
+    }
+
+    pub fn sub(&self, vector: &DynVector) -> DynVector {
+        let difference = self
+            .values
+            .iter()
+            .zip(&vector.values)
+            .map(|(a, b)| a - b)
+            .collect();
+
+        DynVector { values: difference }
+    }
+
+    pub fn add(&self, vector: &DynVector) -> DynVector {
+        let total = self
+            .values
+            .iter()
+            .zip(&vector.values)
+            .map(|(a, b)| a + b)
+            .collect();
+
+        DynVector { values: total }
+    }
+
+    pub fn scale(&self, factor: f32) -> DynVector {
+        DynVector {
+            values: self.values.iter().map(|a| a * factor).collect(),
+        }
+    }
+
+    /// See `Vector::normalized`, the zero vector is returned unchanged
+    pub fn normalized(&self) -> DynVector {
+        let magnitude = self.dot(self).sqrt();
+        if magnitude == 0.0 {
+            return self.clone();
+        }
+        DynVector {
+            values: self.values.iter().map(|a| a / magnitude).collect(),
+        }
+    }
+
+    pub fn squared_euclidian_distance(&self, vector: &DynVector) -> f32 {
+        f32::squared_differences(&self.values, &vector.values)
+    }
+
+    /// See `Vector::euclidian_distance`
+    pub fn euclidian_distance(&self, vector: &DynVector) -> f32 {
+        self.squared_euclidian_distance(vector).sqrt()
+    }
+
+    /// See `Vector::cosine_similarity`, the similarity is 0 if either vector is zero
+    pub fn cosine_similarity(&self, vector: &DynVector) -> f32 {
+        let magnitudes = (self.dot(self) * vector.dot(vector)).sqrt();
+        if magnitudes == 0.0 {
+            return 0.0;
+        }
+        self.dot(vector) / magnitudes
+    }
+
+    /// See `Vector::cosine_distance`
+    pub fn cosine_distance(&self, vector: &DynVector) -> f32 {
+        1.0 - self.cosine_similarity(vector)
+    }
+
+    /// See `Vector::manhattan_distance`
+    pub fn manhattan_distance(&self, vector: &DynVector) -> f32 {
+        self.values
+            .iter()
+            .zip(&vector.values)
+            .map(|(a, b)| (a - b).abs())
+            .sum()
+    }
+
+    /// See `Vector::hashkey`, identifies the vector's contents for deduplication
+    pub fn hashkey(&self) -> DynHashKey {
+        DynHashKey(self.values.iter().map(|a| a.to_bits()).collect())
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Debug)]
+pub struct DynHashKey(Vec<u32>);
+
+impl VectorOps for DynVector {
+    type Scalar = f32;
+    type HashKey = DynHashKey;
+
+    fn dimension(&self) -> usize {
+        DynVector::dimension(self)
+    }
+
+    fn dot(&self, vector: &Self) -> f32 {
+        DynVector::dot(self, vector)
+    }
+
+    fn sub(&self, vector: &Self) -> Self {
+        DynVector::sub(self, vector)
+    }
+
+    fn avg(&self, vector: &Self) -> Self {
+        DynVector::avg(self, vector)
+    }
+
+    fn add(&self, vector: &Self) -> Self {
+        DynVector::add(self, vector)
+    }
+
+    fn scale(&self, factor: f32) -> Self {
+        DynVector::scale(self, factor)
+    }
+
+    fn normalized(&self) -> Self {
+        DynVector::normalized(self)
+    }
+
+    fn squared_euclidian_distance(&self, vector: &Self) -> f32 {
+        DynVector::squared_euclidian_distance(self, vector)
+    }
+
+    fn euclidian_distance(&self, vector: &Self) -> f32 {
+        DynVector::euclidian_distance(self, vector)
+    }
+
+    fn cosine_distance(&self, vector: &Self) -> f32 {
+        DynVector::cosine_distance(self, vector)
+    }
+
+    fn manhattan_distance(&self, vector: &Self) -> f32 {
+        DynVector::manhattan_distance(self, vector)
+    }
+
+    fn hashkey(&self) -> Self::HashKey {
+        DynVector::hashkey(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rand() {
+        let a = DynVector::random(3, Some(0.0), Some(2.0));
+        assert_eq!(a.dimension(), 3, "Should have 3 values");
+        assert!(a.values.iter().all(|&v| (0.0..=2.0).contains(&v)));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = DynVector::new(vec![1.0, 2.0, 3.0]);
+        let b = DynVector::new(vec![4.0, 5.0, 6.0]);
+        assert_eq!(a.add(&b).values, vec![5.0, 7.0, 9.0]);
+        assert_eq!(a.sub(&b).values, vec![-3.0, -3.0, -3.0]);
+        assert_eq!(a.avg(&b).values, vec![2.5, 3.5, 4.5]);
+        assert_eq!(a.dot(&b), 32.0);
+        assert_eq!(a.squared_euclidian_distance(&b), 27.0);
+    }
+
+    #[test]
+    fn test_hashkey() {
+        let a = DynVector::new(vec![1.0, 2.0, 3.0]);
+        let b = DynVector::new(vec![1.0, 2.0, 3.0]);
+        let c = DynVector::new(vec![1.0, 2.0, 4.0]);
+        assert_eq!(a.hashkey(), b.hashkey());
+        assert_ne!(a.hashkey(), c.hashkey());
+    }
+}
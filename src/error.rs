@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::metric::DistanceMetric;
+
+/// An error returned when an index cannot be built from the supplied vectors and ids
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The number of vectors and the number of ids differ
+    LengthMismatch { vectors: usize, ids: usize },
+    /// No vectors were supplied to build the index from
+    Empty,
+    /// A vector's dimension differs from the dimension of the first vector
+    DimensionMismatch { expected: usize, found: usize },
+    /// The number of vectors and the number of payloads differ
+    PayloadLengthMismatch { vectors: usize, payloads: usize },
+    /// The thread pool requested with `IndexBuilder::max_threads` could not be created
+    ThreadPool { message: String },
+    /// A leaf of a tree supplied to `ApproximateNearestNeighborsIndex::from_parts` holds a
+    /// position past the end of the vectors
+    LeafIndexOutOfRange {
+        tree: usize,
+        index: usize,
+        vectors: usize,
+    },
+    /// More vectors were supplied than an index can hold, see `MAX_VECTORS`
+    TooManyVectors { vectors: usize, max: usize },
+    /// The build was cancelled with the flag passed to `IndexBuilder::build_cancellable`
+    Cancelled,
+    /// The maximum leaf size is below 1, a leaf has to hold at least one vector
+    InvalidLeafSize { max_size: i32 },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::LengthMismatch { vectors, ids } => write!(
+                f,
+                "received {} vectors but {} ids, every vector needs exactly one id",
+                vectors, ids
+            ),
+            BuildError::Empty => write!(f, "cannot build an index without any vectors"),
+            BuildError::DimensionMismatch { expected, found } => write!(
+                f,
+                "expected every vector to have dimension {} but found dimension {}",
+                expected, found
+            ),
+            BuildError::PayloadLengthMismatch { vectors, payloads } => write!(
+                f,
+                "received {} vectors but {} payloads, every vector needs exactly one payload",
+                vectors, payloads
+            ),
+            BuildError::ThreadPool { message } => {
+                write!(f, "could not create the thread pool: {}", message)
+            }
+            BuildError::LeafIndexOutOfRange {
+                tree,
+                index,
+                vectors,
+            } => write!(
+                f,
+                "a leaf of tree {} holds index {} but there are only {} vectors",
+                tree, index, vectors
+            ),
+            BuildError::TooManyVectors { vectors, max } => write!(
+                f,
+                "received {} vectors but an index holds at most {}",
+                vectors, max
+            ),
+            BuildError::Cancelled => write!(f, "the build was cancelled"),
+            BuildError::InvalidLeafSize { max_size } => write!(
+                f,
+                "received a maximum leaf size of {} but every leaf holds at least 1 vector",
+                max_size
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// An error returned when a vector cannot be inserted into an index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    /// A vector with the same hashkey is already in the index
+    DuplicateVector { id: i32 },
+    /// The number of vectors and the number of ids of a batch differ
+    LengthMismatch { vectors: usize, ids: usize },
+    /// The index would hold more vectors than it can, see `MAX_VECTORS`
+    TooManyVectors { vectors: usize, max: usize },
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::DuplicateVector { id } => {
+                write!(f, "the vector for id {} is already in the index", id)
+            }
+            InsertError::LengthMismatch { vectors, ids } => write!(
+                f,
+                "received {} vectors but {} ids, every vector needs exactly one id",
+                vectors, ids
+            ),
+            InsertError::TooManyVectors { vectors, max } => write!(
+                f,
+                "the index would hold {} vectors but holds at most {}",
+                vectors, max
+            ),
+        }
+    }
+}
+
+impl Error for InsertError {}
+
+/// An error returned when two indexes cannot be merged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// The id is used by vectors of both indexes
+    IdCollision { id: i32 },
+    /// The vector of the id is in both indexes, and the index merged into drops duplicates
+    DuplicateVector { id: i32 },
+    /// The indexes rank their results with different distance metrics
+    MetricMismatch {
+        expected: DistanceMetric,
+        found: DistanceMetric,
+    },
+    /// The merged index would hold more vectors than it can, see `MAX_VECTORS`
+    TooManyVectors { vectors: usize, max: usize },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::IdCollision { id } => {
+                write!(f, "id {} is used by vectors of both indexes", id)
+            }
+            MergeError::DuplicateVector { id } => {
+                write!(f, "the vector for id {} is in both indexes", id)
+            }
+            MergeError::MetricMismatch { expected, found } => write!(
+                f,
+                "expected an index using the {:?} metric but found {:?}",
+                expected, found
+            ),
+            MergeError::TooManyVectors { vectors, max } => write!(
+                f,
+                "the merged index would hold {} vectors but holds at most {}",
+                vectors, max
+            ),
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+/// An error returned when a vector cannot be constructed from the supplied values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VectorError {
+    /// The value at `index` is NaN or infinite
+    NonFinite { index: usize },
+}
+
+impl fmt::Display for VectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorError::NonFinite { index } => {
+                write!(f, "the value at index {} is NaN or infinite", index)
+            }
+        }
+    }
+}
+
+impl Error for VectorError {}
+
+/// An error returned when runtime data does not have the dimension of the vector it is converted into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DimError {
+    pub expected: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for DimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected {} values for the vector but found {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl Error for DimError {}
+
+/// An error returned when a tree of an index does not hold every vector exactly once,
+/// see `ApproximateNearestNeighborsIndex::validate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A leaf of the tree holds a position past the end of the vectors
+    IndexOutOfRange {
+        tree: usize,
+        index: usize,
+        vectors: usize,
+    },
+    /// The position is held by more than one leaf of the tree, or twice by the same leaf
+    DuplicateIndex { tree: usize, index: usize },
+    /// No leaf of the tree holds the position, so the vector can never be found through the tree
+    MissingIndex { tree: usize, index: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::IndexOutOfRange {
+                tree,
+                index,
+                vectors,
+            } => write!(
+                f,
+                "a leaf of tree {} holds index {} but there are only {} vectors",
+                tree, index, vectors
+            ),
+            ValidationError::DuplicateIndex { tree, index } => {
+                write!(f, "tree {} holds index {} more than once", tree, index)
+            }
+            ValidationError::MissingIndex { tree, index } => {
+                write!(f, "tree {} does not hold index {}", tree, index)
+            }
+        }
+    }
+}
+
+impl Error for ValidationError {}
@@ -1,19 +1,54 @@
-use crate::vector::Vector;
+use std::io::{self, Read, Write};
 
-pub(crate) struct HyperPlane<const N: usize> {
-    coefficients: Vector<N>,
-    constant: f32,
+use crate::scalar::VectorScalar;
+use crate::vector::{Vector, VectorOps};
+
+/// A hyperplane `coefficients · x + constant = 0` splitting the vectors of a tree node in two
+/// Points with a non-negative margin lie above the hyperplane and go to the right child
+#[derive(Debug, Clone)]
+pub struct HyperPlane<V: VectorOps> {
+    coefficients: V,
+    constant: V::Scalar,
 }
-impl<const N: usize> HyperPlane<N> {
-    pub fn new(coefficients: Vector<N>, constant: f32) -> Self {
+impl<V: VectorOps> HyperPlane<V> {
+    pub fn new(coefficients: V, constant: V::Scalar) -> Self {
         Self {
             coefficients,
             constant,
         }
     }
 
-    pub fn is_point_above(&self, point: &Vector<N>) -> bool {
-        self.coefficients.dot(point) + self.constant >= 0.0
+    /// The normal vector of the hyperplane
+    pub fn coefficients(&self) -> &V {
+        &self.coefficients
+    }
+
+    pub fn constant(&self) -> V::Scalar {
+        self.constant
+    }
+
+    /// The signed margin of `point`, positive above the hyperplane and negative below it
+    /// Its magnitude grows with the distance of the point from the hyperplane, it is that distance
+    /// scaled by the length of `coefficients`, so a small margin means a point close to the split
+    pub fn margin(&self, point: &V) -> V::Scalar {
+        self.coefficients.dot(point) + self.constant
+    }
+
+    pub fn is_point_above(&self, point: &V) -> bool {
+        self.margin(point) >= V::Scalar::ZERO
+    }
+}
+
+impl<const N: usize, T: VectorScalar> HyperPlane<Vector<N, T>> {
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.coefficients.write_to(writer)?;
+        self.constant.write_le(writer)
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let coefficients = Vector::read_from(reader)?;
+        let constant = T::read_le(reader)?;
+        Ok(Self::new(coefficients, constant))
     }
 }
 
@@ -27,4 +62,12 @@ mod tests {
         let point = Vector::new([1.0, 1.0]);
         assert!(plane.is_point_above(&point));
     }
+
+    #[test]
+    fn test_margin() {
+        let plane = HyperPlane::new(Vector::new([2.0, 0.0]), -2.0);
+        assert_eq!(plane.margin(&Vector::new([3.0, 5.0])), 4.0);
+        assert_eq!(plane.margin(&Vector::new([0.0, 1.0])), -2.0);
+        assert!(!plane.is_point_above(&Vector::new([0.0, 1.0])));
+    }
 }
@@ -1,4 +1,21 @@
 pub mod ann_index;
-mod hyperplane;
-mod tree;
+pub mod builder;
+pub mod dyn_index;
+pub mod dyn_vector;
+pub mod error;
+pub mod hyperplane;
+pub mod metric;
+#[cfg(feature = "mmap")]
+pub mod mmap_index;
+mod parallel;
+pub mod payload_index;
+mod persistence;
+pub mod quantized_index;
+pub mod query;
+pub mod scalar;
+pub mod search;
+#[cfg(feature = "simd")]
+mod simd;
+pub mod split;
+pub mod tree;
 pub mod vector;
@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::scalar::VectorScalar;
+use crate::vector::VectorOps;
+
+/// The distance metric used to rank candidates during a search
+/// Smaller distances are always considered closer, metrics that measure similarity
+/// (cosine, dot product) are converted into a distance so results sort the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+    /// The euclidian (L2) distance between two vectors
+    /// Searches still rank candidates by the squared distance and only take the square root of
+    /// the distances they return, so this costs no more than `SquaredEuclidean`
+    Euclidean,
+    /// The squared euclidian distance between two vectors, this is the default metric
+    #[default]
+    SquaredEuclidean,
+    /// One minus the cosine similarity of two vectors
+    /// If either vector has zero magnitude the similarity is defined as 0, giving a distance of 1
+    Cosine,
+    /// The manhattan (L1) distance between two vectors
+    Manhattan,
+    /// The negated dot product of two vectors, so that larger dot products rank first
+    DotProduct,
+    /// Maximum inner product search (MIPS), candidates are ranked by descending dot product with
+    /// the query and results report the dot product itself as their distance, largest first
+    /// `distance` still returns the negated dot product so that smaller is closer, which is also
+    /// what `search_radius` compares against its radius
+    ///
+    /// NOTE:
+    /// The trees split vectors by proximity, but the vector with the largest dot product is often
+    /// not close to the query, so recall is usually lower than for the other metrics
+    /// If the vectors can be normalized to unit length, the inner product ranks like `Cosine`
+    /// Otherwise append `sqrt(M² - ‖x‖²)` to every vector `x`, where `M` is the largest norm, and
+    /// `0` to the query, the euclidian nearest neighbors are then the maximum inner products
+    MaxInnerProduct,
+    /// The angular distance `sqrt(2 - 2cos)` of two vectors as used by Annoy, the euclidian
+    /// distance between the normalized vectors, ranging from 0 for the same direction to 2
+    /// Like `Cosine` the trees split the normalized vectors by hyperplanes through the origin,
+    /// and candidates are ranked by their cosine distance, see `IndexBuilder::angular`
+    Angular,
+}
+
+impl DistanceMetric {
+    /// Compute the distance between two vectors using this metric
+    pub fn distance<V: VectorOps>(&self, a: &V, b: &V) -> V::Scalar {
+        match self {
+            DistanceMetric::Euclidean => a.euclidian_distance(b),
+            DistanceMetric::SquaredEuclidean => a.squared_euclidian_distance(b),
+            DistanceMetric::Cosine => a.cosine_distance(b),
+            DistanceMetric::Manhattan => a.manhattan_distance(b),
+            DistanceMetric::DotProduct | DistanceMetric::MaxInnerProduct => -a.dot(b),
+            DistanceMetric::Angular => angular(a.cosine_distance(b)),
+        }
+    }
+
+    /// The metric search candidates are ranked with, which orders vectors exactly like this metric
+    /// The euclidian distance is ranked by its square and the angular distance by the cosine
+    /// distance, so the square root is only taken for the distances that are returned, see `unrank`
+    pub(crate) fn ranking(&self) -> DistanceMetric {
+        match self {
+            DistanceMetric::Euclidean => DistanceMetric::SquaredEuclidean,
+            DistanceMetric::Angular => DistanceMetric::Cosine,
+            metric => *metric,
+        }
+    }
+
+    /// Convert a distance measured with the `ranking` metric into the distance reported in results
+    pub(crate) fn unrank<T: VectorScalar>(&self, distance: T) -> T {
+        match self {
+            DistanceMetric::Euclidean => distance.sqrt(),
+            DistanceMetric::Angular => angular(distance),
+            metric => metric.report(distance),
+        }
+    }
+
+    /// Convert a distance of this metric into the distance reported in results, which is the same
+    /// distance for every metric except `MaxInnerProduct` that reports the dot product
+    pub(crate) fn report<T: VectorScalar>(&self, distance: T) -> T {
+        match self {
+            DistanceMetric::MaxInnerProduct => -distance,
+            _ => distance,
+        }
+    }
+}
+
+/// The angular distance `sqrt(2 * cosine)` of a cosine distance, rounding errors that push the
+/// cosine distance of parallel vectors just below zero give a distance of zero instead of NaN
+fn angular<T: VectorScalar>(cosine: T) -> T {
+    match cosine > T::ZERO {
+        true => (cosine + cosine).sqrt(),
+        false => T::ZERO,
+    }
+}
+
+/// Order two distances ascending, sinking NaN after every other distance
+/// A NaN distance (from a vector holding NaN for example) is unordered, so this keeps a single
+/// bad vector from panicking a sort, it just ranks last
+pub(crate) fn compare_distances<T: PartialOrd>(a: &T, b: &T) -> Ordering {
+    let is_nan = |x: &T| x.partial_cmp(x).is_none();
+    a.partial_cmp(b)
+        .unwrap_or_else(|| is_nan(a).cmp(&is_nan(b)))
+}
+
+/// A distance paired with the key of its vector, ordered by `compare_distances` then key
+struct Ranked<K, T>(T, K);
+
+impl<K: Ord, T: PartialOrd> Ord for Ranked<K, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_distances(&self.0, &other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl<K: Ord, T: PartialOrd> PartialOrd for Ranked<K, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, T: PartialOrd> PartialEq for Ranked<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord, T: PartialOrd> Eq for Ranked<K, T> {}
+
+/// Keep the `k` smallest of the `(key, distance)` pairs of `distances`, sorted ascending
+/// The pairs are pushed through a max-heap holding at most `k` of them, so selecting from `c`
+/// candidates costs O(c log k) rather than the O(c log c) of sorting all of them
+/// Equal distances are ordered by key, and NaN distances rank last, see `compare_distances`
+pub(crate) fn smallest_k<K: Ord, T: PartialOrd>(
+    distances: impl IntoIterator<Item = (K, T)>,
+    k: usize,
+) -> Vec<(K, T)> {
+    let mut heap = BinaryHeap::with_capacity(k);
+    for (key, distance) in distances {
+        let ranked = Ranked(distance, key);
+        if heap.len() < k {
+            heap.push(ranked);
+        } else if let Some(mut worst) = heap.peek_mut() {
+            if ranked < *worst {
+                *worst = ranked;
+            }
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Ranked(distance, key)| (key, distance))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vector;
+
+    #[test]
+    fn test_distances() {
+        let a = Vector::new([1.0, 2.0]);
+        let b = Vector::new([4.0, 6.0]);
+        assert_eq!(DistanceMetric::Euclidean.distance(&a, &b), 5.0);
+        assert_eq!(DistanceMetric::SquaredEuclidean.distance(&a, &b), 25.0);
+        assert_eq!(DistanceMetric::Manhattan.distance(&a, &b), 7.0);
+        assert_eq!(DistanceMetric::DotProduct.distance(&a, &b), -16.0);
+        assert_eq!(DistanceMetric::MaxInnerProduct.distance(&a, &b), -16.0);
+
+        let (x, y) = (Vector::new([2.0, 0.0]), Vector::new([0.0, 3.0]));
+        assert_eq!(DistanceMetric::Angular.distance(&x, &y), 2.0f32.sqrt());
+        assert_eq!(DistanceMetric::Angular.distance(&x, &-x), 2.0);
+        assert_eq!(DistanceMetric::Angular.distance(&x, &x.scale(3.0)), 0.0);
+    }
+
+    #[test]
+    fn test_cosine_distance() {
+        let a = Vector::new([1.0, 0.0]);
+        let b = Vector::new([0.0, 2.0]);
+        let c = Vector::new([3.0, 0.0]);
+        assert_eq!(DistanceMetric::Cosine.distance(&a, &b), 1.0);
+        assert_eq!(DistanceMetric::Cosine.distance(&a, &c), 0.0);
+
+        let zero: Vector<2> = Vector::new([0.0, 0.0]);
+        let distance = DistanceMetric::Cosine.distance(&a, &zero);
+        assert!(!distance.is_nan(), "Zero vectors should not produce NaN");
+        assert_eq!(distance, 1.0);
+    }
+
+    #[test]
+    fn test_ranking_metric() {
+        let a = Vector::new([1.0, 2.0]);
+        let b = Vector::new([4.0, 6.0]);
+        for metric in [
+            DistanceMetric::Euclidean,
+            DistanceMetric::SquaredEuclidean,
+            DistanceMetric::Cosine,
+            DistanceMetric::Manhattan,
+            DistanceMetric::DotProduct,
+            DistanceMetric::Angular,
+        ] {
+            let ranked = metric.ranking().distance(&a, &b);
+            assert_eq!(metric.unrank(ranked), metric.distance(&a, &b));
+        }
+        let ranked = DistanceMetric::MaxInnerProduct.ranking().distance(&a, &b);
+        assert_eq!(DistanceMetric::MaxInnerProduct.unrank(ranked), 16.0);
+        assert_eq!(
+            DistanceMetric::Euclidean.ranking(),
+            DistanceMetric::SquaredEuclidean
+        );
+    }
+
+    #[test]
+    fn test_compare_distances() {
+        assert_eq!(compare_distances(&1.0, &2.0), Ordering::Less);
+        assert_eq!(compare_distances(&f32::NAN, &2.0), Ordering::Greater);
+        assert_eq!(compare_distances(&2.0, &f32::NAN), Ordering::Less);
+        assert_eq!(compare_distances(&f32::NAN, &f32::NAN), Ordering::Equal);
+
+        let mut distances = [f32::NAN, 3.0, f32::INFINITY, 1.0];
+        distances.sort_by(compare_distances);
+        assert_eq!(&distances[..3], &[1.0, 3.0, f32::INFINITY]);
+        assert!(distances[3].is_nan());
+    }
+
+    #[test]
+    fn test_smallest_k() {
+        let distances = vec![
+            (0, 4.0),
+            (1, f32::NAN),
+            (2, 1.0),
+            (3, 3.0),
+            (4, 1.0),
+            (5, 2.0),
+        ];
+        assert_eq!(
+            smallest_k(distances.clone(), 4),
+            vec![(2, 1.0), (4, 1.0), (5, 2.0), (3, 3.0)]
+        );
+        assert!(smallest_k(distances.clone(), 0).is_empty());
+
+        let all = smallest_k(distances, 10);
+        assert_eq!(all.len(), 6);
+        assert!(all[5].1.is_nan());
+    }
+}
@@ -0,0 +1,332 @@
+use itertools::Itertools;
+use memmap2::Mmap;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::path::Path;
+
+use crate::ann_index::{clamp_top_k, ApproximateNearestNeighborsSearchResult};
+use crate::hyperplane::HyperPlane;
+use crate::metric::{compare_distances, smallest_k, DistanceMetric};
+use crate::parallel::*;
+use crate::persistence::{
+    invalid_data, read_alias_index, read_header, read_i32, Header, LEAF_FLAG,
+};
+use crate::scalar::VectorScalar;
+use crate::tree::{is_sequential_search, merge_per_tree};
+use crate::vector::Vector;
+
+/// The size of an id, an alias (position and id), a leaf record (offset and length), and an index in the file
+const ID_SIZE: usize = 4;
+const ALIAS_SIZE: usize = 12;
+const LEAF_SIZE: usize = 16;
+const INDEX_SIZE: usize = 4;
+
+/// The position of the tables of a single tree within the mapped file, see `write_tree`
+struct MappedTree {
+    root: u32,
+    inner_offset: usize,
+    leaves_offset: usize,
+    indexes_offset: usize,
+}
+
+/// A read-only index searched directly from a file written by `ApproximateNearestNeighborsIndex::save`
+/// The file is memory-mapped instead of read into memory, so the operating system's page cache
+/// decides which vectors and tree nodes are resident, and the index can be larger than the
+/// available memory. Only the position of each tree within the file is held in memory
+pub struct MmapIndex<const N: usize, T: VectorScalar = f32> {
+    mmap: Mmap,
+    metric: DistanceMetric,
+    num_vectors: usize,
+    vectors_offset: usize,
+    ids_offset: usize,
+    /// The ids of vectors kept for several ids, the only ids read into memory
+    aliases: HashMap<usize, Vec<i32>>,
+    trees: Vec<MappedTree>,
+    marker: PhantomData<T>,
+}
+
+impl<const N: usize, T: VectorScalar> MmapIndex<N, T> {
+    /// Every scalar is stored in `size_of::<T>()` little-endian bytes
+    const VECTOR_SIZE: usize = N * size_of::<T>();
+    /// An inner node is its two children references followed by its hyperplane
+    const INNER_SIZE: usize = 8 + (N + 1) * size_of::<T>();
+
+    /// Memory-map the index file at `path`
+    /// Returns an `InvalidData` error under the same conditions as `ApproximateNearestNeighborsIndex::load`,
+    /// the structure of every tree is validated up front so searches never read out of bounds
+    ///
+    /// NOTE:
+    /// The file must not be modified while it is mapped, the index reads whatever the file holds
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only, and every offset read through it is validated below
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut reader: &[u8] = &mmap;
+        let Header {
+            metric,
+            num_vectors,
+            num_aliases,
+            num_trees,
+            ..
+        } = read_header::<_, N, T>(&mut reader)?;
+        let vectors_offset = mmap.len() - reader.len();
+        let ids_offset = table_end(&mmap, vectors_offset, num_vectors, Self::VECTOR_SIZE)?;
+        let aliases_offset = table_end(&mmap, ids_offset, num_vectors, ID_SIZE)?;
+        let mut offset = table_end(&mmap, aliases_offset, num_aliases, ALIAS_SIZE)?;
+
+        let mut aliases: HashMap<usize, Vec<i32>> = HashMap::new();
+        let mut reader = &mmap[aliases_offset..offset];
+        for _ in 0..num_aliases {
+            let index = read_alias_index(&mut reader, num_vectors)?;
+            aliases
+                .entry(index)
+                .or_default()
+                .push(read_i32(&mut reader)?);
+        }
+
+        let mut trees = Vec::with_capacity(num_trees.min(1 << 16));
+        for _ in 0..num_trees {
+            let (tree, end) = Self::map_tree(&mmap, offset, num_vectors)?;
+            trees.push(tree);
+            offset = end;
+        }
+
+        Ok(MmapIndex {
+            mmap,
+            metric,
+            num_vectors,
+            vectors_offset,
+            ids_offset,
+            aliases,
+            trees,
+            marker: PhantomData,
+        })
+    }
+
+    /// Locate and validate the tables of the tree starting at `offset`, returning the tree
+    /// and the offset just past its tables
+    fn map_tree(
+        bytes: &[u8],
+        offset: usize,
+        num_vectors: usize,
+    ) -> io::Result<(MappedTree, usize)> {
+        let inner_offset = table_end(bytes, offset, 1, 12)?;
+        let (root, num_inner) = (u32_at(bytes, offset), len_at(bytes, offset + 4)?);
+        let leaves_header = table_end(bytes, inner_offset, num_inner, Self::INNER_SIZE)?;
+        let leaves_offset = table_end(bytes, leaves_header, 1, 8)?;
+        let num_leaves = len_at(bytes, leaves_header)?;
+        let indexes_header = table_end(bytes, leaves_offset, num_leaves, LEAF_SIZE)?;
+        let indexes_offset = table_end(bytes, indexes_header, 1, 8)?;
+        let num_indexes = len_at(bytes, indexes_header)?;
+        let end = table_end(bytes, indexes_offset, num_indexes, INDEX_SIZE)?;
+
+        // children always follow their parent, which also guarantees the tree has no cycles
+        let check_node = |node: u32, parent: Option<usize>| {
+            let valid = match node & LEAF_FLAG != 0 {
+                true => ((node & !LEAF_FLAG) as usize) < num_leaves,
+                false => {
+                    let position = node as usize;
+                    position < num_inner && parent.is_none_or(|parent| position > parent)
+                }
+            };
+            match valid {
+                true => Ok(()),
+                false => Err(invalid_data("node reference is invalid")),
+            }
+        };
+        check_node(root, None)?;
+        for position in 0..num_inner {
+            let inner = inner_offset + position * Self::INNER_SIZE;
+            check_node(u32_at(bytes, inner), Some(position))?;
+            check_node(u32_at(bytes, inner + 4), Some(position))?;
+        }
+        for leaf in 0..num_leaves {
+            let leaf = leaves_offset + leaf * LEAF_SIZE;
+            let (start, len) = (len_at(bytes, leaf)?, len_at(bytes, leaf + 8)?);
+            if start.checked_add(len).is_none_or(|end| end > num_indexes) {
+                return Err(invalid_data("leaf values are out of range"));
+            }
+        }
+        for index in 0..num_indexes {
+            let index = u32_at(bytes, indexes_offset + index * INDEX_SIZE);
+            if index as usize >= num_vectors {
+                return Err(invalid_data(format!(
+                    "leaf index {} is out of range for {} vectors",
+                    index, num_vectors
+                )));
+            }
+        }
+
+        let tree = MappedTree {
+            root,
+            inner_offset,
+            leaves_offset,
+            indexes_offset,
+        };
+        Ok((tree, end))
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector
+    /// See `ApproximateNearestNeighborsIndex::search`, the results are identical to searching the
+    /// loaded index, but vectors and tree nodes are read from the mapped file as they are visited
+    pub fn search(
+        &self,
+        query: Vector<N, T>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        let num_results = self.num_vectors + self.aliases.values().map(Vec::len).sum::<usize>();
+        let Some(top_k) = clamp_top_k(top_k, num_results) else {
+            return vec![];
+        };
+        // without trees every vector is a candidate, the same as the loaded index
+        let candidates = match self.trees.is_empty() {
+            true => (0..self.num_vectors).collect_vec(),
+            false => {
+                let mut candidates = HashSet::new();
+                merge_per_tree(
+                    &self.trees,
+                    is_sequential_search(self.trees.len(), top_k as usize),
+                    |tree| self.query_node(tree, tree.root, &query, top_k),
+                    |found| {
+                        candidates.extend(found);
+                        true
+                    },
+                );
+                candidates.into_iter().collect_vec()
+            }
+        };
+        let ranking = self.metric.ranking();
+        let distances: Vec<_> = candidates
+            .into_par_iter()
+            .map(|idx| {
+                (
+                    (self.id(idx), idx),
+                    ranking.distance(&self.vector(idx), &query),
+                )
+            })
+            .collect();
+        // equal distances are ordered by id, then position, the same as the loaded index
+        smallest_k(distances, top_k as usize)
+            .into_iter()
+            .flat_map(|((id, idx), distance)| {
+                let aliases = self.aliases.get(&idx).into_iter().flatten().copied();
+                std::iter::once(id)
+                    .chain(aliases)
+                    .map(move |id| (idx, id, distance))
+            })
+            .sorted_by(|a, b| {
+                compare_distances(&a.2, &b.2)
+                    .then(a.1.cmp(&b.1))
+                    .then(a.0.cmp(&b.0))
+            })
+            .take(top_k as usize)
+            .map(
+                |(idx, id, distance)| ApproximateNearestNeighborsSearchResult {
+                    vector_id: id,
+                    distance: self.metric.unrank(distance),
+                    vector: self.vector(idx),
+                },
+            )
+            .collect_vec()
+    }
+
+    /// Gather up to `n` candidates below `node`, following the same order as `query_tree`
+    fn query_node(&self, tree: &MappedTree, node: u32, query: &Vector<N, T>, n: i32) -> Vec<usize> {
+        let mut candidates = vec![];
+        let mut found = 0;
+        let mut nodes = vec![node];
+        while let Some(node) = nodes.pop() {
+            if found >= n {
+                break;
+            }
+            if node & LEAF_FLAG != 0 {
+                let leaf = tree.leaves_offset + (node & !LEAF_FLAG) as usize * LEAF_SIZE;
+                let (start, len) = (self.u64_at(leaf) as usize, self.u64_at(leaf + 8) as usize);
+                let num_candidates_found = min((n - found) as usize, len);
+                for index in start..start + num_candidates_found {
+                    candidates.push(u32_at(&self.mmap, tree.indexes_offset + index * INDEX_SIZE) as usize);
+                }
+                found += num_candidates_found as i32;
+                continue;
+            }
+
+            let inner = tree.inner_offset + node as usize * Self::INNER_SIZE;
+            let (left, right) = (u32_at(&self.mmap, inner), u32_at(&self.mmap, inner + 4));
+            let mut plane = &self.mmap[inner + 8..inner + Self::INNER_SIZE];
+            let hyperplane = HyperPlane::<Vector<N, T>>::read_from(&mut plane)
+                .expect("inner nodes are validated when the index is opened");
+            let (main, backup) = match hyperplane.is_point_above(query) {
+                true => (right, left),
+                false => (left, right),
+            };
+            nodes.extend([backup, main]);
+        }
+        candidates
+    }
+
+    /// Read the vector at position `idx` from the mapped file
+    fn vector(&self, idx: usize) -> Vector<N, T> {
+        let offset = self.vectors_offset + idx * Self::VECTOR_SIZE;
+        let mut bytes = &self.mmap[offset..offset + Self::VECTOR_SIZE];
+        Vector::read_from(&mut bytes).expect("vectors are validated when the index is opened")
+    }
+
+    fn id(&self, idx: usize) -> i32 {
+        let offset = self.ids_offset + idx * ID_SIZE;
+        i32::from_le_bytes(self.mmap[offset..offset + ID_SIZE].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.mmap[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The number of vectors in the index
+    pub fn len(&self) -> usize {
+        self.num_vectors
+    }
+
+    /// Whether the index holds no vectors
+    pub fn is_empty(&self) -> bool {
+        self.num_vectors == 0
+    }
+
+    /// The distance metric used to rank search results
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// The number of trees in the index
+    pub fn num_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// The dimension `N` of the vectors in the index
+    pub fn dimension(&self) -> usize {
+        N
+    }
+}
+
+/// The offset just past a table of `count` records of `size` bytes starting at `offset`,
+/// returning an `InvalidData` error if the table does not fit within `bytes`
+fn table_end(bytes: &[u8], offset: usize, count: usize, size: usize) -> io::Result<usize> {
+    count
+        .checked_mul(size)
+        .and_then(|len| offset.checked_add(len))
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| invalid_data("index file is truncated"))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read a u64 length at `offset`, rejecting lengths that cannot be addressed on this platform
+fn len_at(bytes: &[u8], offset: usize) -> io::Result<usize> {
+    let len = u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    usize::try_from(len).map_err(|_| invalid_data(format!("length {} is too large", len)))
+}
@@ -0,0 +1,45 @@
+//! The parallel iterators used throughout the crate: rayon's everywhere but `wasm32`, where threads
+//! cannot be spawned and the same calls run as ordinary iterators on the calling thread
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use rayon::prelude::*;
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) use serial::*;
+
+#[cfg(target_arch = "wasm32")]
+mod serial {
+    /// `into_par_iter` for anything that can be iterated, iterating it in order
+    pub(crate) trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<I: IntoIterator> IntoParallelIterator for I {}
+
+    /// `par_iter` and `par_iter_mut` for slices, iterating them in order
+    pub(crate) trait ParallelSlice<T> {
+        fn par_iter(&self) -> std::slice::Iter<'_, T>;
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T>;
+    }
+
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_iter(&self) -> std::slice::Iter<'_, T> {
+            self.iter()
+        }
+
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+            self.iter_mut()
+        }
+    }
+
+    /// The methods of rayon's parallel iterators that ordinary iterators lack
+    pub(crate) trait ParallelIterator: Iterator + Sized {
+        fn position_any(mut self, predicate: impl FnMut(Self::Item) -> bool) -> Option<usize> {
+            self.position(predicate)
+        }
+    }
+
+    impl<I: Iterator> ParallelIterator for I {}
+}
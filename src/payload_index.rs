@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::ann_index::{ApproximateNearestNeighborsIndex, ApproximateNearestNeighborsSearchResult};
+use crate::builder::IndexBuilder;
+use crate::error::{BuildError, InsertError};
+use crate::scalar::VectorScalar;
+use crate::vector::Vector;
+
+/// A search result from a `PayloadIndex`, the result of the underlying index together with
+/// the payload stored for its id
+#[derive(Debug, Clone)]
+pub struct PayloadSearchResult<'a, const N: usize, P, T = f32> {
+    pub result: ApproximateNearestNeighborsSearchResult<N, T>,
+    pub payload: &'a P,
+}
+
+/// An `ApproximateNearestNeighborsIndex` that stores a payload of type `P` (a document id, a URL,
+/// any metadata) alongside each vector and returns it with every search result
+/// Payloads are stored by id, the payload of an id is the payload of the first vector supplied
+/// with it
+pub struct PayloadIndex<const N: usize, P, T: VectorScalar = f32> {
+    index: ApproximateNearestNeighborsIndex<N, T>,
+    payloads: HashMap<i32, P>,
+}
+
+impl<const N: usize, P, T: VectorScalar> PayloadIndex<N, P, T> {
+    /// Build an index of vectors the same way as `ApproximateNearestNeighborsIndex::try_build`,
+    /// where `ids[i]` and `payloads[i]` belong to `vectors[i]`
+    /// The payloads of ids dropped as duplicates are dropped along with them, while the payload of
+    /// a duplicate kept under its own id with `IndexBuilder::keep_duplicates` is kept
+    /// Returns `BuildError::PayloadLengthMismatch` if the number of vectors and payloads differ
+    pub fn build(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+        payloads: Vec<P>,
+    ) -> Result<Self, BuildError> {
+        IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size)
+            .build_with_payloads(vectors, ids, payloads)
+    }
+
+    /// Build an index with the settings of `builder`, validating the input first
+    pub(crate) fn from_builder(
+        builder: &IndexBuilder<N, T>,
+        vectors: &[Vector<N, T>],
+        ids: &[i32],
+        payloads: Vec<P>,
+    ) -> Result<Self, BuildError> {
+        if vectors.len() != payloads.len() {
+            return Err(BuildError::PayloadLengthMismatch {
+                vectors: vectors.len(),
+                payloads: payloads.len(),
+            });
+        }
+        let index = builder.build(vectors, ids)?;
+
+        let mut kept = HashMap::with_capacity(index.len());
+        for (&id, payload) in ids.iter().zip(payloads) {
+            if index.contains_id(id) {
+                kept.entry(id).or_insert(payload);
+            }
+        }
+        Ok(PayloadIndex {
+            index,
+            payloads: kept,
+        })
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector,
+    /// see `ApproximateNearestNeighborsIndex::search`
+    pub fn search(&self, query: Vector<N, T>, top_k: i32) -> Vec<PayloadSearchResult<'_, N, P, T>> {
+        self.with_payloads(self.index.search(query, top_k))
+    }
+
+    /// Attach the stored payloads to the `results` of a search of the underlying index
+    /// Useful with the other searches of the index, see `index`
+    /// Every id the index holds has a payload, results with any other id (from a search of another
+    /// index) are skipped
+    pub fn with_payloads(
+        &self,
+        results: Vec<ApproximateNearestNeighborsSearchResult<N, T>>,
+    ) -> Vec<PayloadSearchResult<'_, N, P, T>> {
+        results
+            .into_iter()
+            .filter_map(|result| {
+                let payload = self.payloads.get(&result.vector_id)?;
+                Some(PayloadSearchResult { payload, result })
+            })
+            .collect()
+    }
+
+    /// Insert a single vector and its payload, see `ApproximateNearestNeighborsIndex::insert`
+    pub fn insert(&mut self, vector: Vector<N, T>, id: i32, payload: P) -> Result<(), InsertError> {
+        self.index.insert(vector, id)?;
+        self.payloads.entry(id).or_insert(payload);
+        Ok(())
+    }
+
+    /// Remove every vector with the given `id`, returning its payload or `None` if the id was not found
+    pub fn remove(&mut self, id: i32) -> Option<P> {
+        self.index.remove(id);
+        self.payloads.remove(&id)
+    }
+
+    /// The payload stored for `id`, or `None` if no vector with the id is in the index
+    pub fn payload(&self, id: i32) -> Option<&P> {
+        self.payloads.get(&id)
+    }
+
+    /// The underlying index, for searches that do not need the payloads
+    pub fn index(&self) -> &ApproximateNearestNeighborsIndex<N, T> {
+        &self.index
+    }
+
+    /// The number of vectors in the index
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the index holds no vectors
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
@@ -0,0 +1,453 @@
+use std::io::{self, Read, Write};
+
+use crate::ann_index::MAX_VECTORS;
+use crate::hyperplane::HyperPlane;
+use crate::metric::DistanceMetric;
+use crate::scalar::VectorScalar;
+use crate::split::SplitStrategy;
+use crate::tree::{InnerNode, LeafNode, TreeNode};
+use crate::vector::Vector;
+
+/// Every serialized index starts with these magic bytes followed by the format version
+pub(crate) const MAGIC: &[u8; 4] = b"SIEV";
+pub(crate) const VERSION: u32 = 8;
+
+/// Tree nodes are referenced by a u32 where the high bit marks a leaf, the remaining bits
+/// are the position of the node within the inner node table or the leaf table of the tree
+pub(crate) const LEAF_FLAG: u32 = 1 << 31;
+
+pub(crate) fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_i32<W: Write>(writer: &mut W, value: i32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+/// Read a length prefix, rejecting lengths that cannot be addressed on this platform
+pub(crate) fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let len = read_u64(reader)?;
+    usize::try_from(len).map_err(|_| invalid_data(format!("length {} is too large", len)))
+}
+
+pub(crate) fn write_metric<W: Write>(writer: &mut W, metric: DistanceMetric) -> io::Result<()> {
+    let tag = match metric {
+        DistanceMetric::Euclidean => 0,
+        DistanceMetric::SquaredEuclidean => 1,
+        DistanceMetric::Cosine => 2,
+        DistanceMetric::Manhattan => 3,
+        DistanceMetric::DotProduct => 4,
+        DistanceMetric::MaxInnerProduct => 5,
+        DistanceMetric::Angular => 6,
+    };
+    write_u32(writer, tag)
+}
+
+pub(crate) fn read_metric<R: Read>(reader: &mut R) -> io::Result<DistanceMetric> {
+    match read_u32(reader)? {
+        0 => Ok(DistanceMetric::Euclidean),
+        1 => Ok(DistanceMetric::SquaredEuclidean),
+        2 => Ok(DistanceMetric::Cosine),
+        3 => Ok(DistanceMetric::Manhattan),
+        4 => Ok(DistanceMetric::DotProduct),
+        5 => Ok(DistanceMetric::MaxInnerProduct),
+        6 => Ok(DistanceMetric::Angular),
+        tag => Err(invalid_data(format!("unknown distance metric {}", tag))),
+    }
+}
+
+pub(crate) fn write_split_strategy<W: Write>(
+    writer: &mut W,
+    strategy: SplitStrategy,
+) -> io::Result<()> {
+    let tag = match strategy {
+        SplitStrategy::RandomProjection => 0,
+        SplitStrategy::KMeans2 => 1,
+    };
+    write_u32(writer, tag)
+}
+
+pub(crate) fn read_split_strategy<R: Read>(reader: &mut R) -> io::Result<SplitStrategy> {
+    match read_u32(reader)? {
+        0 => Ok(SplitStrategy::RandomProjection),
+        1 => Ok(SplitStrategy::KMeans2),
+        tag => Err(invalid_data(format!("unknown split strategy {}", tag))),
+    }
+}
+
+/// The fields stored at the start of every index file, after the magic bytes, the format version,
+/// the dimension `N`, and the scalar tag of `T`
+pub(crate) struct Header {
+    pub metric: DistanceMetric,
+    pub split_strategy: SplitStrategy,
+    pub split_sample_size: usize,
+    pub max_size: i32,
+    pub max_depth: Option<usize>,
+    pub keep_duplicates: bool,
+    pub num_vectors: usize,
+    pub num_aliases: usize,
+    pub num_trees: usize,
+}
+
+pub(crate) fn write_header<W: Write, const N: usize, T: VectorScalar>(
+    writer: &mut W,
+    header: &Header,
+) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    write_u32(writer, VERSION)?;
+    write_u32(writer, N as u32)?;
+    write_u32(writer, T::TAG)?;
+    write_metric(writer, header.metric)?;
+    write_split_strategy(writer, header.split_strategy)?;
+    write_u64(writer, header.split_sample_size as u64)?;
+    write_i32(writer, header.max_size)?;
+    // u64::MAX marks an index built without a depth limit
+    write_u64(
+        writer,
+        header.max_depth.map_or(u64::MAX, |depth| depth as u64),
+    )?;
+    write_u32(writer, header.keep_duplicates as u32)?;
+    write_u64(writer, header.num_vectors as u64)?;
+    write_u64(writer, header.num_aliases as u64)?;
+    write_u64(writer, header.num_trees as u64)
+}
+
+/// Read the header written by `write_header`
+/// Returns an `InvalidData` error if the file is not an index, was written by an unsupported
+/// version, or holds vectors of a different dimension or scalar type than `N` and `T`
+pub(crate) fn read_header<R: Read, const N: usize, T: VectorScalar>(
+    reader: &mut R,
+) -> io::Result<Header> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("file is not a sieve index"));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported index version {}, expected {}",
+            version, VERSION
+        )));
+    }
+    let dimension = read_u32(reader)? as usize;
+    if dimension != N {
+        return Err(invalid_data(format!(
+            "index has dimension {}, expected {}",
+            dimension, N
+        )));
+    }
+    let scalar = read_u32(reader)?;
+    if scalar != T::TAG {
+        return Err(invalid_data(format!(
+            "index has scalar type {}, expected {}",
+            scalar,
+            T::TAG
+        )));
+    }
+    Ok(Header {
+        metric: read_metric(reader)?,
+        split_strategy: read_split_strategy(reader)?,
+        split_sample_size: read_len(reader)?.max(2),
+        max_size: match read_i32(reader)? {
+            max_size if max_size < 1 => {
+                return Err(invalid_data(format!(
+                    "max leaf size {} is below 1",
+                    max_size
+                )))
+            }
+            max_size => max_size,
+        },
+        max_depth: match read_u64(reader)? {
+            u64::MAX => None,
+            depth => Some(usize::try_from(depth).unwrap_or(usize::MAX)),
+        },
+        keep_duplicates: read_u32(reader)? != 0,
+        num_vectors: match read_len(reader)? {
+            num_vectors if num_vectors > MAX_VECTORS => {
+                return Err(invalid_data(format!(
+                    "index has {} vectors, at most {} are supported",
+                    num_vectors, MAX_VECTORS
+                )))
+            }
+            num_vectors => num_vectors,
+        },
+        num_aliases: read_len(reader)?,
+        num_trees: read_len(reader)?,
+    })
+}
+
+/// Read the position of the vector an alias belongs to, see `ApproximateNearestNeighborsIndex::save`
+pub(crate) fn read_alias_index<R: Read>(reader: &mut R, num_vectors: usize) -> io::Result<usize> {
+    let index = read_len(reader)?;
+    if index >= num_vectors {
+        return Err(invalid_data(format!(
+            "alias index {} is out of range for {} vectors",
+            index, num_vectors
+        )));
+    }
+    Ok(index)
+}
+
+/// Write a tree as a flat set of tables so any node can be addressed by its offset:
+/// the root reference, the inner node table (children references, then the hyperplane),
+/// the leaf table (offset and length into the index table), and the index table itself, which
+/// holds every position as a u32 the same as the leaves do in memory
+pub(crate) fn write_tree<W: Write, const N: usize, T: VectorScalar>(
+    writer: &mut W,
+    tree: &TreeNode<Vector<N, T>>,
+) -> io::Result<()> {
+    let mut inner_nodes = vec![];
+    let mut leaves = vec![];
+    let mut indexes = vec![];
+    let root = flatten_tree(tree, &mut inner_nodes, &mut leaves, &mut indexes);
+
+    write_u32(writer, root)?;
+    write_u64(writer, inner_nodes.len() as u64)?;
+    for (left, right, hyperplane) in inner_nodes {
+        write_u32(writer, left)?;
+        write_u32(writer, right)?;
+        hyperplane.write_to(writer)?;
+    }
+    write_u64(writer, leaves.len() as u64)?;
+    for (offset, len) in leaves {
+        write_u64(writer, offset as u64)?;
+        write_u64(writer, len as u64)?;
+    }
+    write_u64(writer, indexes.len() as u64)?;
+    for index in indexes {
+        write_u32(writer, index)?;
+    }
+    Ok(())
+}
+
+type InnerRecord<'a, const N: usize, T> = (u32, u32, &'a HyperPlane<Vector<N, T>>);
+
+/// Where the reference to a flattened node is stored, the root or a side of an inner node
+enum NodeSlot {
+    Root,
+    Left(usize),
+    Right(usize),
+}
+
+/// Append `tree` to the tables of `write_tree`, returning the reference to its root
+/// Nodes are numbered in pre-order, left before right, so a parent always precedes its children
+/// in the table, the nodes still to flatten are kept on a heap allocated stack so deep trees
+/// cannot overflow the call stack
+fn flatten_tree<'a, const N: usize, T: VectorScalar>(
+    tree: &'a TreeNode<Vector<N, T>>,
+    inner_nodes: &mut Vec<InnerRecord<'a, N, T>>,
+    leaves: &mut Vec<(usize, usize)>,
+    indexes: &mut Vec<u32>,
+) -> u32 {
+    let mut root = 0;
+    let mut nodes = vec![(tree, NodeSlot::Root)];
+    while let Some((node, slot)) = nodes.pop() {
+        let reference = match node {
+            TreeNode::Leaf(leaf) => {
+                let values = leaf.value();
+                leaves.push((indexes.len(), values.len()));
+                indexes.extend_from_slice(values);
+                (leaves.len() - 1) as u32 | LEAF_FLAG
+            }
+            TreeNode::Branch(inner) => {
+                // the children references are filled in once the children are flattened
+                let position = inner_nodes.len();
+                inner_nodes.push((0, 0, inner.hyperplane()));
+                nodes.push((inner.right(), NodeSlot::Right(position)));
+                nodes.push((inner.left(), NodeSlot::Left(position)));
+                position as u32
+            }
+        };
+        match slot {
+            NodeSlot::Root => root = reference,
+            NodeSlot::Left(parent) => inner_nodes[parent].0 = reference,
+            NodeSlot::Right(parent) => inner_nodes[parent].1 = reference,
+        }
+    }
+    root
+}
+
+/// Read a tree written by `write_tree`, validating that every leaf index is below `num_vectors`
+pub(crate) fn read_tree<R: Read, const N: usize, T: VectorScalar>(
+    reader: &mut R,
+    num_vectors: usize,
+) -> io::Result<TreeNode<Vector<N, T>>> {
+    let root = read_u32(reader)?;
+    let num_inner = read_len(reader)?;
+    let mut inner_nodes = Vec::with_capacity(num_inner.min(1 << 16));
+    for _ in 0..num_inner {
+        let left = read_u32(reader)?;
+        let right = read_u32(reader)?;
+        inner_nodes.push(Some((left, right, HyperPlane::read_from(reader)?)));
+    }
+    let num_leaves = read_len(reader)?;
+    let mut leaves = Vec::with_capacity(num_leaves.min(1 << 16));
+    for _ in 0..num_leaves {
+        leaves.push((read_len(reader)?, read_len(reader)?));
+    }
+    let num_indexes = read_len(reader)?;
+    let mut indexes = Vec::with_capacity(num_indexes.min(1 << 16));
+    for _ in 0..num_indexes {
+        let index = read_u32(reader)?;
+        if index as usize >= num_vectors {
+            return Err(invalid_data(format!(
+                "leaf index {} is out of range for {} vectors",
+                index, num_vectors
+            )));
+        }
+        indexes.push(index);
+    }
+    unflatten_tree(root, &mut inner_nodes, &leaves, &indexes)
+}
+
+type InnerSlot<const N: usize, T> = Option<(u32, u32, HyperPlane<Vector<N, T>>)>;
+
+/// A pending step of `unflatten_tree`, either rebuilding the node referenced from the inner node
+/// at `parent`, or joining the two most recently rebuilt subtrees under a branch
+enum UnflattenStep<const N: usize, T: VectorScalar> {
+    Node(u32, Option<usize>),
+    Join(HyperPlane<Vector<N, T>>),
+}
+
+/// Rebuild the tree whose root is referenced by `root` from the tables read by `read_tree`
+/// The pending nodes are kept on a heap allocated stack rather than the call stack, so deep
+/// trees load without overflowing it
+fn unflatten_tree<const N: usize, T: VectorScalar>(
+    root: u32,
+    inner_nodes: &mut [InnerSlot<N, T>],
+    leaves: &[(usize, usize)],
+    indexes: &[u32],
+) -> io::Result<TreeNode<Vector<N, T>>> {
+    let mut steps = vec![UnflattenStep::Node(root, None)];
+    let mut built = vec![];
+    while let Some(step) = steps.pop() {
+        let (node, parent) = match step {
+            UnflattenStep::Node(node, parent) => (node, parent),
+            UnflattenStep::Join(hyperplane) => {
+                let right = built.pop().expect("both sides are rebuilt before joining");
+                let left = built.pop().expect("both sides are rebuilt before joining");
+                built.push(TreeNode::Branch(Box::new(InnerNode::new(
+                    hyperplane, left, right,
+                ))));
+                continue;
+            }
+        };
+        if node & LEAF_FLAG != 0 {
+            let &(offset, len) = leaves
+                .get((node & !LEAF_FLAG) as usize)
+                .ok_or_else(|| invalid_data("leaf reference is out of range"))?;
+            let values = offset
+                .checked_add(len)
+                .and_then(|end| indexes.get(offset..end))
+                .ok_or_else(|| invalid_data("leaf values are out of range"))?;
+            built.push(TreeNode::Leaf(Box::new(LeafNode::new(values.to_vec()))));
+            continue;
+        }
+
+        let position = node as usize;
+        // children always follow their parent, which also guarantees the tree has no cycles
+        if parent.is_some_and(|parent| position <= parent) {
+            return Err(invalid_data("inner node references are not in tree order"));
+        }
+        let (left, right, hyperplane) = inner_nodes
+            .get_mut(position)
+            .and_then(Option::take)
+            .ok_or_else(|| invalid_data("inner node reference is invalid"))?;
+        // the left side is popped first, and rebuilt completely before the right side
+        steps.push(UnflattenStep::Join(hyperplane));
+        steps.push(UnflattenStep::Node(right, Some(position)));
+        steps.push(UnflattenStep::Node(left, Some(position)));
+    }
+    Ok(built.pop().expect("the root is the last node rebuilt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::tree::{build_tree, tree_stats, validate_tree, TreeConfig};
+
+    #[test]
+    fn test_leaf_indexes_are_written_as_u32() {
+        let tree: TreeNode<Vector<2>> = TreeNode::leaf((0..100).collect());
+        let mut bytes = vec![];
+        write_tree(&mut bytes, &tree).unwrap();
+        // the root, the empty inner table, a single leaf, then the index table
+        assert_eq!(bytes.len(), 4 + 8 + (8 + 16) + (8 + 100 * 4));
+
+        let loaded: TreeNode<Vector<2>> = read_tree(&mut bytes.as_slice(), 100).unwrap();
+        let TreeNode::Leaf(leaf) = &loaded else {
+            panic!("a single leaf should load as a leaf");
+        };
+        assert_eq!(leaf.value(), (0..100).collect::<Vec<u32>>());
+        let error = read_tree::<_, 2, f32>(&mut bytes.as_slice(), 99).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_deep_tree_saves_and_loads() {
+        // every split of scaled basis vectors peels off a single vector, so the tree is a chain
+        let vectors: Vec<Vector<512>> = (0..512)
+            .map(|i| Vector::from_fn(|j| if i == j { (i + 1) as f32 } else { 0.0 }))
+            .collect();
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+        let config = TreeConfig {
+            max_size: 1,
+            max_depth: None,
+            metric: DistanceMetric::default(),
+            strategy: SplitStrategy::default(),
+            sample_size: 2,
+        };
+
+        std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                let mut rng = StdRng::seed_from_u64(0);
+                let tree = build_tree(config, 0, &indexes, &vectors, &mut rng);
+                assert!(tree_stats(&tree).max_depth > 256);
+
+                let mut bytes = vec![];
+                write_tree(&mut bytes, &tree).unwrap();
+                let loaded: TreeNode<Vector<512>> =
+                    read_tree(&mut bytes.as_slice(), vectors.len()).unwrap();
+                assert_eq!(validate_tree(0, &loaded, vectors.len()), Ok(()));
+                assert_eq!(tree_stats(&loaded), tree_stats(&tree));
+
+                let mut reloaded = vec![];
+                write_tree(&mut reloaded, &loaded).unwrap();
+                assert_eq!(reloaded, bytes);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
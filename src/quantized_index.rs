@@ -0,0 +1,438 @@
+use itertools::Itertools;
+use rayon::ThreadPool;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::ann_index::{clamp_top_k, install, ApproximateNearestNeighborsSearchResult, IndexParts};
+use crate::builder::IndexBuilder;
+use crate::error::BuildError;
+use crate::metric::{compare_distances, smallest_k, DistanceMetric};
+use crate::parallel::*;
+use crate::query::inverse_norm;
+use crate::tree::{candidates_or_scan, collect_candidates, TreeNode};
+use crate::vector::Vector;
+
+/// A linear mapping of every component of a set of vectors onto the values of an `i8`
+/// The range of the components is centered on `offset` and divided into 254 steps of `scale`,
+/// so every component within the range is reconstructed to within `scale / 2`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantizer {
+    scale: f32,
+    offset: f32,
+}
+
+impl Quantizer {
+    /// The quantizer covering the smallest to the largest component of `vectors`
+    /// Components outside of that range are clamped when they are quantized
+    pub fn fit<const N: usize>(vectors: &[Vector<N>]) -> Self {
+        let (min, max) = vectors
+            .iter()
+            .flat_map(|vector| vector.as_slice())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            });
+        if min > max {
+            return Quantizer {
+                scale: 1.0,
+                offset: 0.0,
+            };
+        }
+        // a single distinct value is reconstructed exactly with any scale
+        let scale = match max > min {
+            true => (max - min) / 254.0,
+            false => 1.0,
+        };
+        Quantizer {
+            scale,
+            offset: min / 2.0 + max / 2.0,
+        }
+    }
+
+    /// The distance between two consecutive quantized values
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// The value reconstructed from a quantized zero, the center of the quantized range
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    /// Quantize every component of `vector` to the nearest of the 255 values from -127 to 127
+    pub fn quantize<const N: usize>(&self, vector: &Vector<N>) -> [i8; N] {
+        let values = vector.as_slice();
+        std::array::from_fn(|i| {
+            ((values[i] - self.offset) / self.scale)
+                .round()
+                .clamp(-127.0, 127.0) as i8
+        })
+    }
+
+    /// Reconstruct the vector that `codes` were quantized from
+    pub fn dequantize<const N: usize>(&self, codes: &[i8; N]) -> Vector<N> {
+        Vector::from_fn(|i| codes[i] as f32 * self.scale + self.offset)
+    }
+
+    /// The dot product of the vectors reconstructed from two sets of `N` codes, from the dot
+    /// product of the codes and the sum of each set of codes
+    /// Every reconstructed component is `code * scale + offset`, so expanding the product of two
+    /// components leaves only the offset terms to add to the scaled dot product of the codes
+    fn dot<const N: usize>(&self, codes_dot: i32, sum_a: i32, sum_b: i32) -> f32 {
+        let scale = self.scale;
+        let offset = self.offset;
+        scale * scale * codes_dot as f32
+            + scale * offset * (sum_a + sum_b) as f32
+            + N as f32 * offset * offset
+    }
+}
+
+/// A vector quantized for ranking, with the statistics of its codes that dot products and cosine
+/// distances need besides the codes themselves
+struct QuantizedVector<'a, const N: usize> {
+    codes: &'a [i8; N],
+    sum: i32,
+    inverse_norm: f32,
+}
+
+/// The sum of `codes`, and one over the norm of the vector `quantizer` reconstructs from them,
+/// zero for the zero vector
+fn code_stats<const N: usize>(quantizer: &Quantizer, codes: &[i8; N]) -> (i32, f32) {
+    let sum = codes.iter().map(|&code| code as i32).sum();
+    let squared_norm = quantizer.dot::<N>(codes_dot(codes, codes), sum, sum);
+    (sum, inverse_norm(squared_norm))
+}
+
+/// The dot product of two sets of codes
+fn codes_dot<const N: usize>(a: &[i8; N], b: &[i8; N]) -> i32 {
+    a.iter().zip(b).map(|(&a, &b)| a as i32 * b as i32).sum()
+}
+
+/// The sum of the squared differences of two sets of codes
+fn codes_squared_distance<const N: usize>(a: &[i8; N], b: &[i8; N]) -> i32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+        .sum()
+}
+
+/// The sum of the absolute differences of two sets of codes
+fn codes_manhattan_distance<const N: usize>(a: &[i8; N], b: &[i8; N]) -> i32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| (a as i32 - b as i32).abs())
+        .sum()
+}
+
+/// An index that stores every vector as `N` bytes quantized with a single `Quantizer` for the
+/// whole index, a quarter of the memory of the `f32` vectors of `ApproximateNearestNeighborsIndex`
+/// The trees are built from the original vectors, so the same candidates are gathered as for
+/// an index of the original vectors, but the candidates are ranked by their quantized vectors
+/// The original vectors can be retained to rerank the closest candidates exactly, see
+/// `IndexBuilder::build_quantized_with_originals`
+///
+/// NOTE:
+/// The trees are built by a full `ApproximateNearestNeighborsIndex` that is quantized afterwards,
+/// so building takes the memory of the full index
+/// Distances between codes are accumulated in an `i32`, which is exact for up to 33 000
+/// dimensions
+pub struct QuantizedIndex<const N: usize> {
+    codes: Vec<[i8; N]>,
+    /// The sum of the codes of every vector, at the same position as its codes
+    code_sums: Vec<i32>,
+    /// One over the norm of every reconstructed vector, zero for the zero vector
+    inverse_norms: Vec<f32>,
+    ids: Vec<i32>,
+    /// The ids of a vector beyond the one in `ids`, see `IndexBuilder::keep_duplicates`
+    aliases: HashMap<usize, Vec<i32>>,
+    originals: Option<Vec<Vector<N>>>,
+    quantizer: Quantizer,
+    trees: Vec<TreeNode<Vector<N>>>,
+    metric: DistanceMetric,
+    pool: Option<Arc<ThreadPool>>,
+}
+
+impl<const N: usize> QuantizedIndex<N> {
+    /// Build a quantized index of vectors the same way as `ApproximateNearestNeighborsIndex::try_build`,
+    /// without retaining the original vectors
+    pub fn build(
+        num_trees: i32,
+        max_size: i32,
+        vectors: &[Vector<N>],
+        ids: &[i32],
+    ) -> Result<Self, BuildError> {
+        IndexBuilder::new()
+            .num_trees(num_trees)
+            .max_leaf_size(max_size)
+            .build_quantized(vectors, ids)
+    }
+
+    /// Build an index with the settings of `builder` and quantize its vectors
+    pub(crate) fn from_builder(
+        builder: &IndexBuilder<N>,
+        vectors: &[Vector<N>],
+        ids: &[i32],
+        retain_originals: bool,
+    ) -> Result<Self, BuildError> {
+        let IndexParts {
+            vectors,
+            ids,
+            aliases,
+            trees,
+            metric,
+            pool,
+        } = builder.build(vectors, ids)?.into_parts();
+        let quantizer = Quantizer::fit(&vectors);
+        let codes: Vec<_> = vectors.iter().map(|v| quantizer.quantize(v)).collect();
+        let (code_sums, inverse_norms) = codes
+            .iter()
+            .map(|codes| code_stats(&quantizer, codes))
+            .unzip();
+        Ok(QuantizedIndex {
+            codes,
+            code_sums,
+            inverse_norms,
+            ids,
+            aliases,
+            originals: retain_originals.then_some(vectors),
+            quantizer,
+            trees,
+            metric,
+            pool,
+        })
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the `query` vector
+    /// Candidates are gathered the same way as `ApproximateNearestNeighborsIndex::search`, then ranked
+    /// by the distance between their codes and the codes of the query, quantized the same way
+    /// Results report that distance and the vector reconstructed from the quantized vector
+    pub fn search(
+        &self,
+        query: Vector<N>,
+        top_k: i32,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N>> {
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return vec![];
+        };
+        let closest = smallest_k(self.quantized_distances(&query, top_k), top_k as usize);
+        self.results(closest, top_k, |idx| {
+            self.quantizer.dequantize(&self.codes[idx])
+        })
+    }
+
+    /// Search the index the same way as `search`, but rerank the `rerank_k` candidates closest by
+    /// their quantized vectors by the exact distance to their original vectors, keeping the closest
+    /// `top_k`. Results report the exact distance and the original vector
+    /// A larger `rerank_k` recovers more neighbors the quantization ranked too far,
+    /// a `rerank_k` below `top_k` is treated as `top_k`
+    /// Returns `None` if the original vectors were not retained when the index was built
+    pub fn search_reranked(
+        &self,
+        query: Vector<N>,
+        top_k: i32,
+        rerank_k: i32,
+    ) -> Option<Vec<ApproximateNearestNeighborsSearchResult<N>>> {
+        let originals = self.originals.as_ref()?;
+        let Some(top_k) = clamp_top_k(top_k, self.num_results()) else {
+            return Some(vec![]);
+        };
+        let rerank_k = rerank_k.max(top_k);
+        let ranking = self.metric.ranking();
+        let reranked = smallest_k(
+            self.quantized_distances(&query, rerank_k),
+            rerank_k as usize,
+        )
+        .into_iter()
+        .map(|(key @ (_, idx), _)| (key, ranking.distance(&originals[idx], &query)));
+        let closest = smallest_k(reranked, top_k as usize);
+        Some(self.results(closest, top_k, |idx| originals[idx]))
+    }
+
+    /// The candidates gathered for `n` results keyed by id then position, with the distance from
+    /// `query` to their quantized vectors under the ranking metric
+    /// The distances are computed from the codes of the candidates and of the quantized query,
+    /// no vector is reconstructed
+    fn quantized_distances(&self, query: &Vector<N>, n: i32) -> Vec<((i32, usize), f32)> {
+        let query_codes = self.quantizer.quantize(query);
+        let (sum, inverse_norm) = code_stats(&self.quantizer, &query_codes);
+        let query_codes = QuantizedVector {
+            codes: &query_codes,
+            sum,
+            inverse_norm,
+        };
+        install(&self.pool, || {
+            let candidates = candidates_or_scan(&self.trees, self.codes.len(), || {
+                collect_candidates(&self.trees, query, n)
+                    .into_iter()
+                    .collect_vec()
+            });
+            candidates
+                .into_par_iter()
+                .map(|idx| ((self.ids[idx], idx), self.code_distance(idx, &query_codes)))
+                .collect()
+        })
+    }
+
+    /// The distance between the vector reconstructed from the codes at `idx` and the one
+    /// reconstructed from `query` under the ranking metric, computed from the codes alone
+    fn code_distance(&self, idx: usize, query: &QuantizedVector<N>) -> f32 {
+        let codes = &self.codes[idx];
+        let scale = self.quantizer.scale;
+        match self.metric.ranking() {
+            DistanceMetric::SquaredEuclidean => {
+                scale * scale * codes_squared_distance(codes, query.codes) as f32
+            }
+            DistanceMetric::Manhattan => {
+                scale * codes_manhattan_distance(codes, query.codes) as f32
+            }
+            ranking => {
+                let dot = self.quantizer.dot::<N>(
+                    codes_dot(codes, query.codes),
+                    self.code_sums[idx],
+                    query.sum,
+                );
+                match ranking {
+                    DistanceMetric::Cosine => {
+                        1.0 - dot * self.inverse_norms[idx] * query.inverse_norm
+                    }
+                    _ => -dot,
+                }
+            }
+        }
+    }
+
+    /// The results of the `closest` candidates, every id of a candidate being a separate result
+    /// Equal distances are ordered by id then position, the same as `ApproximateNearestNeighborsIndex`
+    fn results(
+        &self,
+        closest: Vec<((i32, usize), f32)>,
+        top_k: i32,
+        vector: impl Fn(usize) -> Vector<N>,
+    ) -> Vec<ApproximateNearestNeighborsSearchResult<N>> {
+        closest
+            .into_iter()
+            .flat_map(|((_, idx), distance)| self.ids_at(idx).map(move |id| (idx, id, distance)))
+            .sorted_by(|a, b| {
+                compare_distances(&a.2, &b.2)
+                    .then(a.1.cmp(&b.1))
+                    .then(a.0.cmp(&b.0))
+            })
+            .take(top_k as usize)
+            .map(
+                |(idx, id, distance)| ApproximateNearestNeighborsSearchResult {
+                    vector_id: id,
+                    distance: self.metric.unrank(distance),
+                    vector: vector(idx),
+                },
+            )
+            .collect()
+    }
+
+    /// Every id of the vector at `idx`, the id in `ids` followed by its aliases
+    fn ids_at(&self, idx: usize) -> impl Iterator<Item = i32> + '_ {
+        let aliases = self.aliases.get(&idx).into_iter().flatten().copied();
+        std::iter::once(self.ids[idx]).chain(aliases)
+    }
+
+    /// The number of results a search can return, one for every id of every vector
+    fn num_results(&self) -> usize {
+        self.ids.len() + self.aliases.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// The quantizer every vector of the index was quantized with
+    pub fn quantizer(&self) -> Quantizer {
+        self.quantizer
+    }
+
+    /// Whether the original vectors were retained, so `search_reranked` can rerank the results
+    pub fn has_originals(&self) -> bool {
+        self.originals.is_some()
+    }
+
+    /// The number of vectors in the index, after deduplication
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    /// Whether the index holds no vectors
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    /// The distance metric used to rank search results
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// The number of trees in the index
+    pub fn num_trees(&self) -> usize {
+        self.trees.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantizer_round_trip() {
+        let vectors: Vec<Vector<8>> = (0..100)
+            .map(|_| Vector::random(Some(-3.0), Some(5.0)))
+            .collect();
+        let quantizer = Quantizer::fit(&vectors);
+        for vector in &vectors {
+            let restored = quantizer.dequantize(&quantizer.quantize(vector));
+            for (a, b) in vector.as_slice().iter().zip(restored.as_slice()) {
+                assert!((a - b).abs() <= quantizer.scale() / 2.0 + 1e-6);
+            }
+        }
+
+        let constant = Quantizer::fit(&[Vector::new([2.5, 2.5])]);
+        let restored = constant.dequantize(&constant.quantize(&Vector::new([2.5, 2.5])));
+        assert_eq!(restored.as_slice(), &[2.5, 2.5]);
+    }
+
+    #[test]
+    fn test_code_distances_match_reconstructed_distances() {
+        let vectors: Vec<Vector<16>> = (0..50)
+            .map(|_| Vector::random(Some(-1.0), Some(3.0)))
+            .collect();
+        let ids: Vec<i32> = (0..50).collect();
+        let query = Vector::random(Some(-1.0), Some(3.0));
+        for metric in [
+            DistanceMetric::Euclidean,
+            DistanceMetric::SquaredEuclidean,
+            DistanceMetric::Cosine,
+            DistanceMetric::Angular,
+            DistanceMetric::Manhattan,
+            DistanceMetric::DotProduct,
+            DistanceMetric::MaxInnerProduct,
+        ] {
+            let index = IndexBuilder::new()
+                .num_trees(2)
+                .metric(metric)
+                .seed(7)
+                .build_quantized(&vectors, &ids)
+                .unwrap();
+            let query_codes = index.quantizer.quantize(&query);
+            let (sum, inverse_norm) = code_stats(&index.quantizer, &query_codes);
+            let quantized_query = QuantizedVector {
+                codes: &query_codes,
+                sum,
+                inverse_norm,
+            };
+            let reconstructed_query = index.quantizer.dequantize(&query_codes);
+            for idx in 0..index.len() {
+                let vector = index.quantizer.dequantize(&index.codes[idx]);
+                let expected = metric.ranking().distance(&vector, &reconstructed_query);
+                let distance = index.code_distance(idx, &quantized_query);
+                assert!(
+                    (distance - expected).abs() <= 1e-3 * expected.abs().max(1.0),
+                    "{:?}: {} != {}",
+                    metric,
+                    distance,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::ann_index::{ApproximateNearestNeighborsIndex, ApproximateNearestNeighborsSearchResult};
+use crate::scalar::VectorScalar;
+use crate::vector::Vector;
+
+/// A query vector with the quantities every search of it needs computed up front
+/// Searching with a prepared query skips recomputing them, and lets callers whose queries are
+/// already unit vectors (e.g. for `DistanceMetric::Cosine`) skip computing them at all
+#[derive(Debug, Clone, Copy)]
+pub struct PreparedQuery<const N: usize, T = f32> {
+    vector: Vector<N, T>,
+    squared_norm: T,
+    inverse_norm: T,
+    normalized: bool,
+}
+
+impl<const N: usize, T: VectorScalar> PreparedQuery<N, T> {
+    /// Prepare `vector` for searching, computing its squared norm and the inverse of its norm
+    pub fn new(vector: Vector<N, T>) -> Self {
+        let squared_norm = vector.dot(&vector);
+        Self {
+            vector,
+            squared_norm,
+            inverse_norm: inverse_norm(squared_norm),
+            normalized: false,
+        }
+    }
+
+    /// Prepare a `vector` the caller guarantees is already of unit length, without computing its norm
+    /// Cosine distances to the query skip scaling by its norm entirely
+    /// Searches trust the hint, so a vector that is not of unit length skews cosine distances
+    pub fn normalized(vector: Vector<N, T>) -> Self {
+        Self {
+            vector,
+            squared_norm: T::ONE,
+            inverse_norm: T::ONE,
+            normalized: true,
+        }
+    }
+
+    /// The query vector
+    pub fn vector(&self) -> &Vector<N, T> {
+        &self.vector
+    }
+
+    /// The squared euclidian length of the query vector
+    pub fn squared_norm(&self) -> T {
+        self.squared_norm
+    }
+
+    /// One over the euclidian length of the query vector, zero for the zero vector
+    pub fn inverse_norm(&self) -> T {
+        self.inverse_norm
+    }
+
+    /// Whether the query was prepared with `normalized`, trusting it to be of unit length
+    pub fn is_normalized(&self) -> bool {
+        self.normalized
+    }
+}
+
+/// One over the euclidian length of a vector with `squared_norm`, or zero for the zero vector so
+/// cosine similarities to it are zero
+pub(crate) fn inverse_norm<T: VectorScalar>(squared_norm: T) -> T {
+    match squared_norm == T::ZERO {
+        true => T::ZERO,
+        false => T::ONE / squared_norm.sqrt(),
+    }
+}
+
+/// A query prepared once for repeated searches of a single index, see
+/// `ApproximateNearestNeighborsIndex::prepare`
+#[derive(Clone, Copy)]
+pub struct PreparedSearch<'a, const N: usize, T: VectorScalar = f32> {
+    index: &'a ApproximateNearestNeighborsIndex<N, T>,
+    query: PreparedQuery<N, T>,
+}
+
+impl<'a, const N: usize, T: VectorScalar> PreparedSearch<'a, N, T> {
+    pub(crate) fn new(
+        index: &'a ApproximateNearestNeighborsIndex<N, T>,
+        query: PreparedQuery<N, T>,
+    ) -> Self {
+        Self { index, query }
+    }
+
+    /// Search the index for the `top_k` approximate nearest neighbors of the query,
+    /// see `ApproximateNearestNeighborsIndex::search`
+    pub fn search(&self, top_k: i32) -> Vec<ApproximateNearestNeighborsSearchResult<N, T>> {
+        self.index.search_prepared(&self.query, top_k)
+    }
+
+    /// The prepared query
+    pub fn query(&self) -> &PreparedQuery<N, T> {
+        &self.query
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// The numeric type stored in each component of a `Vector`
+/// All vector arithmetic and distances are computed in the scalar type itself, so an index of
+/// `f64` vectors measures distances in `f64` and an index of `f32` vectors in `f32`
+pub trait VectorScalar:
+    Copy
+    + Debug
+    + Default
+    + PartialOrd
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    /// Identifies the scalar type of the vectors in a serialized index
+    const TAG: u32;
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    /// Whether the value is neither NaN nor infinite
+    fn is_finite(self) -> bool;
+    /// The sum of the squared differences of the values of `a` and `b`, their squared euclidian distance
+    /// With the `simd` feature `f32` and `f64` sum several differences at a time in SIMD lanes
+    fn squared_differences(a: &[Self], b: &[Self]) -> Self {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| (a - b) * (a - b))
+            .fold(Self::ZERO, |sum, x| sum + x)
+    }
+    /// The raw bits of the value, used to hash the contents of a vector
+    fn to_bits(self) -> u64;
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()>;
+    fn read_le<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! impl_float_scalar {
+    ($float:ty, $tag:expr, $simd:ident) => {
+        impl VectorScalar for $float {
+            const TAG: u32 = $tag;
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+
+            fn from_f64(value: f64) -> Self {
+                value as $float
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn sqrt(self) -> Self {
+                <$float>::sqrt(self)
+            }
+
+            fn abs(self) -> Self {
+                <$float>::abs(self)
+            }
+
+            fn is_finite(self) -> bool {
+                <$float>::is_finite(self)
+            }
+
+            #[cfg(feature = "simd")]
+            fn squared_differences(a: &[Self], b: &[Self]) -> Self {
+                crate::simd::$simd(a, b)
+            }
+
+            fn to_bits(self) -> u64 {
+                <$float>::to_bits(self) as u64
+            }
+
+            fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+
+            fn read_le<R: Read>(reader: &mut R) -> io::Result<Self> {
+                let mut bytes = [0; std::mem::size_of::<$float>()];
+                reader.read_exact(&mut bytes)?;
+                Ok(<$float>::from_le_bytes(bytes))
+            }
+        }
+    };
+}
+
+impl_float_scalar!(f32, 0, squared_differences_f32);
+impl_float_scalar!(f64, 1, squared_differences_f64);
+
+#[cfg(feature = "half")]
+impl VectorScalar for half::f16 {
+    const TAG: u32 = 2;
+    const ZERO: Self = half::f16::ZERO;
+    const ONE: Self = half::f16::ONE;
+
+    fn from_f64(value: f64) -> Self {
+        half::f16::from_f64(value)
+    }
+
+    fn to_f64(self) -> f64 {
+        half::f16::to_f64(self)
+    }
+
+    fn sqrt(self) -> Self {
+        half::f16::from_f32(self.to_f32().sqrt())
+    }
+
+    fn abs(self) -> Self {
+        half::f16::from_bits(self.to_bits() & 0x7fff)
+    }
+
+    fn is_finite(self) -> bool {
+        half::f16::is_finite(self)
+    }
+
+    fn to_bits(self) -> u64 {
+        half::f16::to_bits(self) as u64
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+
+    fn read_le<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut bytes = [0; 2];
+        reader.read_exact(&mut bytes)?;
+        Ok(half::f16::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float_scalars() {
+        assert_eq!(f32::from_f64(0.5), 0.5f32);
+        assert_eq!(VectorScalar::sqrt(16.0f64), 4.0);
+        assert_eq!(VectorScalar::abs(-2.0f32), 2.0);
+        assert!(VectorScalar::is_finite(1.0f32));
+        assert!(!VectorScalar::is_finite(f64::NAN));
+        assert!(!VectorScalar::is_finite(f32::NEG_INFINITY));
+
+        let mut bytes = vec![];
+        1.25f64.write_le(&mut bytes).unwrap();
+        assert_eq!(f64::read_le(&mut bytes.as_slice()).unwrap(), 1.25);
+    }
+
+    #[cfg(feature = "half")]
+    #[test]
+    fn test_half_scalar() {
+        let value = half::f16::from_f64(-4.0);
+        assert_eq!(VectorScalar::abs(value).to_f64(), 4.0);
+        assert_eq!(VectorScalar::sqrt(VectorScalar::abs(value)).to_f64(), 2.0);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::ann_index::ApproximateNearestNeighborsSearchResult;
+
+/// How the candidates gathered from the trees are ordered before the closest `top_k` are kept
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CandidateScoring {
+    /// Order candidates by their distance to the query alone, this is the default scoring
+    #[default]
+    Distance,
+    /// Order candidates by distance, breaking ties in favour of candidates found in more trees
+    VoteTiebreak,
+    /// Order candidates by their distance minus `vote_weight` times the fraction of trees that
+    /// found them, so candidates most trees agree on can outrank slightly closer ones
+    /// The reported distance of a result is still its plain distance to the query
+    /// `vote_weight` is on the scale of the metric's raw distance (the negated dot product for
+    /// `DotProduct` and `MaxInnerProduct`), so the same weight means something different for each
+    /// metric and for vectors of different magnitudes
+    Blend { vote_weight: f32 },
+}
+
+/// The parameters of a single search, see `ApproximateNearestNeighborsIndex::search_with`
+/// By default up to `top_k` candidates are gathered from each tree, without revisiting any alternate
+/// branches, and ranked by distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchParams {
+    pub(crate) top_k: i32,
+    pub(crate) search_k: i32,
+    pub(crate) scoring: CandidateScoring,
+    pub(crate) probes: usize,
+    pub(crate) max_candidates: Option<usize>,
+    pub(crate) sequential: bool,
+}
+
+impl SearchParams {
+    pub fn new(top_k: i32) -> Self {
+        Self {
+            top_k,
+            search_k: top_k,
+            scoring: CandidateScoring::default(),
+            probes: 0,
+            max_candidates: None,
+            sequential: false,
+        }
+    }
+
+    /// The number of candidates gathered from each tree, a value below `top_k` is treated as `top_k`
+    pub fn search_k(mut self, search_k: i32) -> Self {
+        self.search_k = search_k;
+        self
+    }
+
+    /// How the gathered candidates are ordered
+    pub fn scoring(mut self, scoring: CandidateScoring) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// The number of alternate branches revisited in each tree after the query's own path, a
+    /// multi-probe factor trading latency for recall, 0 by default
+    /// The branches on the other side of the hyperplanes the query lies closest to are revisited
+    /// first, each contributing up to `search_k` more candidates
+    pub fn probes(mut self, probes: usize) -> Self {
+        self.probes = probes;
+        self
+    }
+
+    /// Stop gathering candidates once `max_candidates` distinct candidates were found across all
+    /// trees, bounding the memory and ranking work of a single search, unlimited by default
+    /// Trees still being visited when the cap is reached contribute no more candidates, so a cap
+    /// below what the trees would gather trades recall for bounded memory under load
+    pub fn max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = Some(max_candidates);
+        self
+    }
+
+    /// Visit the trees one after another on the calling thread, gathering the candidates into a
+    /// single set, rather than in parallel, false by default
+    /// Searches gathering few candidates in total are always visited this way, this suits callers
+    /// that already spread their queries across threads, and with `max_candidates` the trees after
+    /// the one reaching the cap are never visited
+    pub fn sequential(mut self, sequential: bool) -> Self {
+        self.sequential = sequential;
+        self
+    }
+}
+
+/// The effort spent by a single search, see `ApproximateNearestNeighborsIndex::search_with_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SearchStats {
+    /// The number of distinct candidates gathered across all trees and ranked by distance
+    pub candidates: usize,
+    /// The number of leaves visited across all trees
+    pub leaves_visited: usize,
+    /// The number of nodes (branches and leaves) visited across all trees
+    pub nodes_traversed: usize,
+    /// The smallest absolute `HyperPlane::margin` of the query over the splits on its own path
+    /// through every tree, `None` if no tree has a split
+    /// A small margin means the query lies close to a split, so some of its true neighbors may be
+    /// on the other side, a signal of lower confidence in the results
+    /// Margins are scaled by the length of each hyperplane's normal, so only compare them within an index
+    pub min_margin: Option<f64>,
+}
+
+/// The outcome of an approximate search compared with the exact search for the same query,
+/// see `ApproximateNearestNeighborsIndex::explain_search`
+#[derive(Debug, Clone)]
+pub struct SearchExplanation<const N: usize, T = f32> {
+    /// The results of the approximate search
+    pub approximate: Vec<ApproximateNearestNeighborsSearchResult<N, T>>,
+    /// The true nearest neighbors found by the exact search
+    pub exact: Vec<ApproximateNearestNeighborsSearchResult<N, T>>,
+    /// The ids of the true nearest neighbors the approximate search missed, nearest first
+    pub missed: Vec<i32>,
+    /// The fraction of the true nearest neighbors the approximate search found
+    pub recall: f32,
+}
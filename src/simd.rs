@@ -0,0 +1,61 @@
+use wide::{f32x8, f64x4};
+
+/// Define a function summing the squared differences of two slices `$lanes` values at a time,
+/// with the values that do not fill a full set of lanes summed one at a time
+/// The lanes are summed in a different order than a sequential loop, so results may differ from the
+/// scalar sum in the last bits, but never enough to reorder distances that are not nearly equal
+macro_rules! simd_squared_differences {
+    ($name:ident, $float:ty, $simd:ty, $lanes:expr) => {
+        pub(crate) fn $name(a: &[$float], b: &[$float]) -> $float {
+            let (a_chunks, b_chunks) = (a.chunks_exact($lanes), b.chunks_exact($lanes));
+            let remainder: $float = a_chunks
+                .remainder()
+                .iter()
+                .zip(b_chunks.remainder())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+            let mut sum = <$simd>::ZERO;
+            for (a, b) in a_chunks.zip(b_chunks) {
+                let diff =
+                    <$simd>::new(a.try_into().unwrap()) - <$simd>::new(b.try_into().unwrap());
+                sum += diff * diff;
+            }
+            sum.reduce_add() + remainder
+        }
+    };
+}
+
+simd_squared_differences!(squared_differences_f32, f32, f32x8, 8);
+simd_squared_differences!(squared_differences_f64, f64, f64x4, 4);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_squared_differences() {
+        for len in [0, 1, 7, 8, 9, 17, 768] {
+            let a: Vec<f32> = (0..len).map(|i| (i as f32 * 0.37).sin()).collect();
+            let b: Vec<f32> = (0..len).map(|i| (i as f32 * 0.11).cos()).collect();
+            let scalar: f32 = a.iter().zip(&b).map(|(a, b)| (a - b) * (a - b)).sum();
+            let simd = squared_differences_f32(&a, &b);
+            assert!(
+                (simd - scalar).abs() <= scalar * 1e-5,
+                "{} != {}",
+                simd,
+                scalar
+            );
+
+            let a: Vec<f64> = a.iter().map(|&a| a as f64).collect();
+            let b: Vec<f64> = b.iter().map(|&b| b as f64).collect();
+            let scalar: f64 = a.iter().zip(&b).map(|(a, b)| (a - b) * (a - b)).sum();
+            let simd = squared_differences_f64(&a, &b);
+            assert!(
+                (simd - scalar).abs() <= scalar * 1e-12,
+                "{} != {}",
+                simd,
+                scalar
+            );
+        }
+    }
+}
@@ -0,0 +1,29 @@
+/// How the vectors of an oversized node are divided between its two children
+/// Either way the node is split by a hyperplane bisecting two points, the strategies differ
+/// in how those points are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SplitStrategy {
+    /// Bisect two randomly sampled vectors of the node, this is the default strategy
+    #[default]
+    RandomProjection,
+    /// Run a 2-means clustering on a sample of the node's vectors and bisect the two centroids
+    /// This is slower to build, but gives more balanced splits on clustered data
+    KMeans2,
+}
+
+/// The shape of a single tree, see `ApproximateNearestNeighborsIndex::tree_stats`
+/// Badly unbalanced trees (a large `max_depth` or a wide spread of leaf sizes) search slower,
+/// which may call for a different `SplitStrategy` or `max_size`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TreeStats {
+    /// The number of branches from the root to the deepest leaf, 0 if the root is a leaf
+    pub max_depth: usize,
+    /// The number of leaves in the tree
+    pub leaf_count: usize,
+    /// The number of vectors in the smallest leaf
+    pub min_leaf_size: usize,
+    /// The average number of vectors in a leaf
+    pub avg_leaf_size: f32,
+    /// The number of vectors in the largest leaf
+    pub max_leaf_size: usize,
+}
@@ -1,32 +1,213 @@
+use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::cmp::{min, Ordering};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+
+use crate::error::ValidationError;
 use crate::hyperplane::HyperPlane;
+use crate::metric::DistanceMetric;
+use crate::parallel::*;
+use crate::scalar::VectorScalar;
+use crate::split::{SplitStrategy, TreeStats};
+use crate::vector::VectorOps;
+
+/// The number of hyperplanes sampled for a node before giving up on splitting it
+const MAX_SPLIT_ATTEMPTS: usize = 8;
+
+/// The number of vectors of a node sampled to find the centroids of a `SplitStrategy::KMeans2` split
+const KMEANS_SAMPLE_SIZE: usize = 128;
 
-pub(crate) enum TreeNode<const N: usize> {
-    Branch(Box<InnerNode<N>>),
-    Leaf(Box<LeafNode<N>>),
+/// The number of assignment and update rounds of a `SplitStrategy::KMeans2` split
+const KMEANS_ITERATIONS: usize = 8;
+
+/// A node of a tree of the index, either a branch splitting its vectors by a hyperplane or a leaf
+/// holding the positions of its vectors in the index
+/// Trees are built by the index itself, but can also be assembled node by node by a custom
+/// construction and handed to `ApproximateNearestNeighborsIndex::from_parts`
+/// Cloning, formatting and dropping a tree walk it with a heap allocated stack rather than the
+/// call stack, so they work on trees of any depth
+pub enum TreeNode<V: VectorOps> {
+    Branch(Box<InnerNode<V>>),
+    Leaf(Box<LeafNode>),
+}
+
+impl<V: VectorOps> TreeNode<V> {
+    /// A leaf holding the vectors at the positions `indexes`
+    pub fn leaf(indexes: Vec<u32>) -> Self {
+        TreeNode::Leaf(Box::new(LeafNode::new(indexes)))
+    }
+
+    /// A branch sending the points above `hyperplane` to `right` and the others to `left`
+    pub fn branch(hyperplane: HyperPlane<V>, left: TreeNode<V>, right: TreeNode<V>) -> Self {
+        TreeNode::Branch(Box::new(InnerNode::new(hyperplane, left, right)))
+    }
 }
 
-pub(crate) struct LeafNode<const N: usize> {
-    value: Vec<usize>,
+/// A pending step of cloning a tree, either cloning a node or joining the two most recently
+/// cloned subtrees under a branch
+enum CloneStep<'a, V: VectorOps> {
+    Node(&'a TreeNode<V>),
+    Join(HyperPlane<V>),
 }
 
-impl<const N: usize> LeafNode<N> {
-    pub fn new(value: Vec<usize>) -> Self {
+impl<V: VectorOps> Clone for TreeNode<V> {
+    fn clone(&self) -> Self {
+        let mut steps = vec![CloneStep::Node(self)];
+        let mut built = vec![];
+        while let Some(step) = steps.pop() {
+            match step {
+                CloneStep::Node(TreeNode::Leaf(leaf)) => built.push(TreeNode::Leaf(leaf.clone())),
+                // the left side is popped first, and cloned completely before the right side
+                CloneStep::Node(TreeNode::Branch(inner)) => {
+                    steps.push(CloneStep::Join(inner.hyperplane.clone()));
+                    steps.push(CloneStep::Node(&inner.right_node));
+                    steps.push(CloneStep::Node(&inner.left_node));
+                }
+                CloneStep::Join(hyperplane) => {
+                    let right = built.pop().expect("both sides are cloned before joining");
+                    let left = built.pop().expect("both sides are cloned before joining");
+                    built.push(TreeNode::branch(hyperplane, left, right));
+                }
+            }
+        }
+        built.pop().expect("the root is the last node cloned")
+    }
+}
+
+/// A pending step of formatting a tree, either formatting a node or writing the text between
+/// the parts of a branch
+enum DebugStep<'a, V: VectorOps> {
+    Node(&'a TreeNode<V>),
+    Text(&'static str),
+}
+
+/// Formats the same way as a derived `Debug`, on a single line even for `{:#?}`
+impl<V: VectorOps> fmt::Debug for TreeNode<V>
+where
+    HyperPlane<V>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut steps = vec![DebugStep::Node(self)];
+        while let Some(step) = steps.pop() {
+            match step {
+                DebugStep::Text(text) => f.write_str(text)?,
+                DebugStep::Node(TreeNode::Leaf(leaf)) => write!(f, "Leaf({:?})", leaf)?,
+                DebugStep::Node(TreeNode::Branch(inner)) => {
+                    write!(
+                        f,
+                        "Branch(InnerNode {{ hyperplane: {:?}, left_node: ",
+                        inner.hyperplane
+                    )?;
+                    steps.push(DebugStep::Text(" })"));
+                    steps.push(DebugStep::Node(&inner.right_node));
+                    steps.push(DebugStep::Text(", right_node: "));
+                    steps.push(DebugStep::Node(&inner.left_node));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<V: VectorOps> Drop for TreeNode<V> {
+    /// Detach the branches below this node onto a heap allocated stack, so every node is dropped
+    /// once its children were detached and dropping never recurses more than a level
+    fn drop(&mut self) {
+        let mut detached = vec![];
+        detach_branches(self, &mut detached);
+        while let Some(mut node) = detached.pop() {
+            detach_branches(&mut node, &mut detached);
+        }
+    }
+}
+
+/// Move the children of `node` that are branches onto `detached`, leaving empty leaves in their place
+fn detach_branches<V: VectorOps>(node: &mut TreeNode<V>, detached: &mut Vec<TreeNode<V>>) {
+    if let TreeNode::Branch(inner) = node {
+        for child in [&mut inner.left_node, &mut inner.right_node] {
+            if let TreeNode::Branch(_) = child {
+                detached.push(std::mem::replace(child, TreeNode::leaf(vec![])));
+            }
+        }
+    }
+}
+
+/// Positions are stored as `u32`, half the size of a `usize` on 64-bit targets, since leaves
+/// hold every vector once per tree, see `MAX_VECTORS` for the resulting cap on the index size
+#[derive(Debug, Clone)]
+pub struct LeafNode {
+    value: Vec<u32>,
+}
+
+impl LeafNode {
+    pub fn new(value: Vec<u32>) -> Self {
         Self { value }
     }
 
-    pub fn value(&self) -> Vec<usize> {
-        self.value.clone()
+    /// A leaf holding the positions `indexes`, each below `MAX_VECTORS`
+    pub(crate) fn from_positions(indexes: &[usize]) -> Self {
+        Self::new(indexes.iter().map(|&index| narrow(index)).collect())
+    }
+
+    /// The positions of the vectors of the leaf in the index
+    pub fn value(&self) -> &[u32] {
+        &self.value
+    }
+
+    /// The positions of the vectors of the leaf, widened to index the vectors directly
+    pub(crate) fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.value.iter().map(|&index| index as usize)
     }
+
+    pub(crate) fn push(&mut self, index: usize) {
+        self.value.push(narrow(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) {
+        self.value.retain(|&i| i as usize != index)
+    }
+
+    pub(crate) fn replace(&mut self, old: usize, new: usize) {
+        for value in self.value.iter_mut().filter(|i| **i as usize == old) {
+            *value = narrow(new);
+        }
+    }
+}
+
+/// Narrow a position to the `u32` stored in a leaf
+/// Every index holds at most `MAX_VECTORS` vectors so positions always fit, debug builds check it
+fn narrow(index: usize) -> u32 {
+    debug_assert!(
+        u32::try_from(index).is_ok(),
+        "position {} does not fit in a leaf",
+        index
+    );
+    index as u32
 }
 
-pub(crate) struct InnerNode<const N: usize> {
-    hyperplane: HyperPlane<N>,
-    left_node: TreeNode<N>,
-    right_node: TreeNode<N>,
+#[derive(Debug, Clone)]
+pub struct InnerNode<V: VectorOps> {
+    hyperplane: HyperPlane<V>,
+    left_node: TreeNode<V>,
+    right_node: TreeNode<V>,
 }
 
-impl<const N: usize> InnerNode<N> {
-    pub fn new(hyperplane: HyperPlane<N>, left_node: TreeNode<N>, right_node: TreeNode<N>) -> Self {
+impl<V: VectorOps> InnerNode<V> {
+    pub fn new(hyperplane: HyperPlane<V>, left_node: TreeNode<V>, right_node: TreeNode<V>) -> Self {
         Self {
             hyperplane,
             left_node,
@@ -34,15 +215,1223 @@ impl<const N: usize> InnerNode<N> {
         }
     }
 
-    pub fn hyperplane(&self) -> &HyperPlane<N> {
+    pub fn hyperplane(&self) -> &HyperPlane<V> {
         &self.hyperplane
     }
 
-    pub fn left(&self) -> &TreeNode<N> {
+    pub fn left(&self) -> &TreeNode<V> {
         &self.left_node
     }
 
-    pub fn right(&self) -> &TreeNode<N> {
+    pub fn right(&self) -> &TreeNode<V> {
         &self.right_node
     }
+
+    pub(crate) fn left_mut(&mut self) -> &mut TreeNode<V> {
+        &mut self.left_node
+    }
+
+    pub(crate) fn right_mut(&mut self) -> &mut TreeNode<V> {
+        &mut self.right_node
+    }
+}
+
+/// The settings that decide how the vectors of a tree are split
+/// A node becomes a leaf once it holds at most `max_size` vectors or sits `max_depth` branches
+/// below the root, whichever comes first
+/// `sample_size` is the number of vectors a `SplitStrategy::RandomProjection` split is derived from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TreeConfig {
+    pub max_size: i32,
+    pub max_depth: Option<usize>,
+    pub metric: DistanceMetric,
+    pub strategy: SplitStrategy,
+    pub sample_size: usize,
+}
+
+impl TreeConfig {
+    /// The most vectors a leaf holds, a `max_size` below 1 is treated as 1 so it can never turn
+    /// into a huge size that keeps every vector in a single leaf
+    fn leaf_size(&self) -> usize {
+        self.max_size.max(1) as usize
+    }
+}
+
+/// A callback reporting the number of trees built so far out of the total number of trees
+pub(crate) type TreeProgress = dyn Fn(usize, usize) + Send + Sync;
+
+/// Build `num_trees` trees over all of `vectors` in parallel, splitting them as `config` describes
+/// Each tree derives its own sub-seed from `seed` so the result does not depend on the
+/// order rayon happens to build the trees in
+/// `on_tree_built` is called as each tree is completed, with the count of completed trees
+pub(crate) fn build_trees<V: VectorOps>(
+    num_trees: i32,
+    config: TreeConfig,
+    vectors: &[V],
+    seed: u64,
+    on_tree_built: Option<&TreeProgress>,
+) -> Vec<TreeNode<V>> {
+    let never = AtomicBool::new(false);
+    build_trees_until(num_trees, config, vectors, seed, on_tree_built, &never)
+        .expect("trees are only abandoned once cancelled")
+}
+
+/// Build the same trees as `build_trees`, or `None` as soon as `cancel` is set
+/// The flag is checked before every tree and before every node is split, the trees built so far
+/// are dropped
+pub(crate) fn build_trees_until<V: VectorOps>(
+    num_trees: i32,
+    config: TreeConfig,
+    vectors: &[V],
+    seed: u64,
+    on_tree_built: Option<&TreeProgress>,
+    cancel: &AtomicBool,
+) -> Option<Vec<TreeNode<V>>> {
+    let all_indexes: Vec<usize> = (0..vectors.len()).collect();
+    let seeds = tree_seeds(num_trees, seed);
+    let (total, built) = (seeds.len(), AtomicUsize::new(0));
+    seeds
+        .into_par_iter()
+        .enumerate()
+        .map(|(number, tree_seed)| {
+            let mut rng = StdRng::seed_from_u64(tree_seed);
+            let tree = build_tree_until(config, 0, &all_indexes, vectors, &mut rng, cancel)?;
+            debug_assert_eq!(validate_tree(number, &tree, vectors.len()), Ok(()));
+            if let Some(on_tree_built) = on_tree_built {
+                on_tree_built(built.fetch_add(1, AtomicOrdering::Relaxed) + 1, total);
+            }
+            Some(tree)
+        })
+        .collect()
+}
+
+/// Build the same trees as `build_trees` one after another on the calling thread
+pub(crate) fn build_trees_sequential<V: VectorOps>(
+    num_trees: i32,
+    config: TreeConfig,
+    vectors: &[V],
+    seed: u64,
+) -> Vec<TreeNode<V>> {
+    let all_indexes: Vec<usize> = (0..vectors.len()).collect();
+    tree_seeds(num_trees, seed)
+        .into_iter()
+        .enumerate()
+        .map(|(number, tree_seed)| {
+            let mut rng = StdRng::seed_from_u64(tree_seed);
+            let tree = build_tree(config, 0, &all_indexes, vectors, &mut rng);
+            debug_assert_eq!(validate_tree(number, &tree, vectors.len()), Ok(()));
+            tree
+        })
+        .collect()
+}
+
+/// The sub-seed of each of the `num_trees` trees built from `seed`
+fn tree_seeds(num_trees: i32, seed: u64) -> Vec<u64> {
+    let mut seed_rng = StdRng::seed_from_u64(seed);
+    (0..num_trees).map(|_| seed_rng.gen()).collect()
+}
+
+/// A pending step of `build_tree`, either splitting the vectors of a node `depth` branches below
+/// the root, or joining the two most recently built subtrees under a branch
+enum BuildStep<V: VectorOps> {
+    Split(Vec<usize>, usize),
+    Join(HyperPlane<V>),
+}
+
+/// Build the subtree of `indexes`, whose root sits `depth` branches below the root of the tree
+/// The pending nodes are kept on a heap allocated stack rather than the call stack, so data that
+/// splits off only a few vectors at every level builds arbitrarily deep trees without overflowing
+pub(crate) fn build_tree<V: VectorOps, R: Rng>(
+    config: TreeConfig,
+    depth: usize,
+    indexes: &[usize],
+    all_vecs: &[V],
+    rng: &mut R,
+) -> TreeNode<V> {
+    let never = AtomicBool::new(false);
+    build_tree_until(config, depth, indexes, all_vecs, rng, &never)
+        .expect("trees are only abandoned once cancelled")
+}
+
+/// Build the same subtree as `build_tree`, or `None` if `cancel` is set before it is complete
+fn build_tree_until<V: VectorOps, R: Rng>(
+    config: TreeConfig,
+    depth: usize,
+    indexes: &[usize],
+    all_vecs: &[V],
+    rng: &mut R,
+    cancel: &AtomicBool,
+) -> Option<TreeNode<V>> {
+    let mut steps = vec![BuildStep::Split(indexes.to_vec(), depth)];
+    let mut built = vec![];
+    while let Some(step) = steps.pop() {
+        if cancel.load(AtomicOrdering::Relaxed) {
+            return None;
+        }
+        match step {
+            BuildStep::Split(indexes, depth) => {
+                match split_node(config, depth, &indexes, all_vecs, rng) {
+                    None => {
+                        built.push(TreeNode::Leaf(Box::new(LeafNode::from_positions(&indexes))))
+                    }
+                    // the side above is popped first, and built completely before the side below
+                    Some((plane, above, below)) => {
+                        steps.push(BuildStep::Join(plane));
+                        steps.push(BuildStep::Split(below, depth + 1));
+                        steps.push(BuildStep::Split(above, depth + 1));
+                    }
+                }
+            }
+            BuildStep::Join(plane) => {
+                let node_below = built.pop().expect("both sides are built before joining");
+                let node_above = built.pop().expect("both sides are built before joining");
+                let inner = InnerNode::new(plane, node_below, node_above);
+                built.push(TreeNode::Branch(Box::new(inner)));
+            }
+        }
+    }
+    Some(built.pop().expect("the root is the last node built"))
+}
+
+/// Split the vectors of a node `depth` branches below the root, or `None` if they form a leaf
+fn split_node<V: VectorOps, R: Rng>(
+    config: TreeConfig,
+    depth: usize,
+    indexes: &[usize],
+    all_vecs: &[V],
+    rng: &mut R,
+) -> Option<(HyperPlane<V>, Vec<usize>, Vec<usize>)> {
+    let at_max_depth = config.max_depth.is_some_and(|max_depth| depth >= max_depth);
+    // a hyperplane is sampled from two vectors, so a single vector is a leaf whatever `max_size` is
+    if indexes.len() < 2 || indexes.len() <= config.leaf_size() || at_max_depth {
+        return None;
+    }
+    let (plane, above, below) = build_hyperplane(config, indexes, all_vecs, rng);
+    // a split that leaves every vector on one side would split the same input forever,
+    // so the vectors are kept together in a leaf even though it exceeds `max_size`
+    if above.len() == indexes.len() || below.len() == indexes.len() {
+        #[cfg(feature = "log")]
+        log::warn!(
+            "could not split {} vectors, keeping a leaf larger than max_size {}",
+            indexes.len(),
+            config.max_size
+        );
+        return None;
+    }
+    Some((plane, above, below))
+}
+
+/// Sample hyperplanes with the configured `SplitStrategy` until one splits `indexes` into two
+/// non-empty sides, giving up with the last sampled split after `MAX_SPLIT_ATTEMPTS` hyperplanes
+/// A hyperplane is degenerate when the sampled vectors coincide, its coefficients are all zero
+/// and every vector would be above it
+fn build_hyperplane<V: VectorOps, R: Rng>(
+    config: TreeConfig,
+    indexes: &[usize],
+    all_vecs: &[V],
+    rng: &mut R,
+) -> (HyperPlane<V>, Vec<usize>, Vec<usize>) {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let hyperplane = match config.strategy {
+            SplitStrategy::RandomProjection if config.sample_size <= 2 => {
+                let sample: Vec<_> = indexes.choose_multiple(rng, 2).collect();
+                let (a, b) = (&all_vecs[*sample[0]], &all_vecs[*sample[1]]);
+                bisecting_hyperplane(config.metric, a, b)
+            }
+            // a larger sample is split around two of its vectors, and the split bisects the
+            // centroids of the two halves, which varies less with noise than a single pair
+            SplitStrategy::RandomProjection => {
+                let (a, b) =
+                    two_means(config.metric, indexes, all_vecs, config.sample_size, 1, rng);
+                bisecting_hyperplane(config.metric, &a, &b)
+            }
+            SplitStrategy::KMeans2 => {
+                let (a, b) = two_means(
+                    config.metric,
+                    indexes,
+                    all_vecs,
+                    KMEANS_SAMPLE_SIZE,
+                    KMEANS_ITERATIONS,
+                    rng,
+                );
+                bisecting_hyperplane(config.metric, &a, &b)
+            }
+        };
+
+        let (mut above, mut below) = (vec![], vec![]);
+        for &id in indexes.iter() {
+            if hyperplane.is_point_above(&all_vecs[id]) {
+                above.push(id)
+            } else {
+                below.push(id)
+            };
+        }
+        if (!above.is_empty() && !below.is_empty()) || attempts == MAX_SPLIT_ATTEMPTS {
+            return (hyperplane, above, below);
+        }
+    }
+}
+
+/// The hyperplane halfway between `a` and `b`, with `b` above it
+/// For the `Cosine` and `Angular` metrics only the direction of a vector matters, so `a` and `b` are
+/// normalized and the hyperplane passes through the origin, splitting the vectors by angle
+fn bisecting_hyperplane<V: VectorOps>(metric: DistanceMetric, a: &V, b: &V) -> HyperPlane<V> {
+    // cartesian eq for hyperplane n * (x - x_0) = 0
+    // n (normal vector) is the coefs x_1 to x_n
+    match metric {
+        DistanceMetric::Cosine | DistanceMetric::Angular => {
+            let coefficients = b.normalized().sub(&a.normalized());
+            HyperPlane::new(coefficients, V::Scalar::ZERO)
+        }
+        _ => {
+            let coefficients = b.sub(a);
+            let point_on_plane = a.avg(b);
+            let constant = -coefficients.dot(&point_on_plane);
+            HyperPlane::new(coefficients, constant)
+        }
+    }
+}
+
+/// Cluster a sample of up to `sample_size` vectors of `indexes` into two clusters,
+/// returning their centroids after `iterations` rounds of 2-means
+/// A cluster that loses all of its vectors keeps its previous centroid
+fn two_means<V: VectorOps, R: Rng>(
+    metric: DistanceMetric,
+    indexes: &[usize],
+    all_vecs: &[V],
+    sample_size: usize,
+    iterations: usize,
+    rng: &mut R,
+) -> (V, V) {
+    let sample: Vec<V> = indexes
+        .choose_multiple(rng, sample_size)
+        .map(|&i| match metric {
+            DistanceMetric::Cosine | DistanceMetric::Angular => all_vecs[i].normalized(),
+            _ => all_vecs[i].clone(),
+        })
+        .collect();
+    if sample.len() < 2 {
+        let only = sample[0].clone();
+        return (only.clone(), only);
+    }
+
+    let first = rng.gen_range(0..sample.len());
+    let second = (first + rng.gen_range(1..sample.len())) % sample.len();
+    let mut centroids = [sample[first].clone(), sample[second].clone()];
+
+    for _ in 0..iterations {
+        let mut sums: [Option<V>; 2] = [None, None];
+        let mut counts = [0; 2];
+        for vector in sample.iter() {
+            let cluster = (vector.squared_euclidian_distance(&centroids[1])
+                < vector.squared_euclidian_distance(&centroids[0]))
+                as usize;
+            sums[cluster] = Some(match sums[cluster].take() {
+                Some(sum) => sum.add(vector),
+                None => vector.clone(),
+            });
+            counts[cluster] += 1;
+        }
+        for (cluster, sum) in sums.into_iter().enumerate() {
+            if let Some(sum) = sum {
+                centroids[cluster] = sum.scale(V::Scalar::from_f64(1.0 / counts[cluster] as f64));
+            }
+        }
+    }
+
+    let [a, b] = centroids;
+    (a, b)
+}
+
+/// Add the vector at `index` to the leaf of `tree` it falls into
+/// If the leaf grows beyond the configured `max_size` it is split by building a new subtree
+/// from its contents, unless the leaf already sits at the configured `max_depth`
+pub(crate) fn insert_into_tree<V: VectorOps, R: Rng>(
+    tree: &mut TreeNode<V>,
+    index: usize,
+    config: TreeConfig,
+    all_vecs: &[V],
+    rng: &mut R,
+) {
+    let (mut node, mut depth) = (tree, 0);
+    loop {
+        match node {
+            TreeNode::Leaf(leaf) => {
+                leaf.push(index);
+                if leaf.len() > config.leaf_size() {
+                    let indexes: Vec<usize> = leaf.positions().collect();
+                    *node = build_tree(config, depth, &indexes, all_vecs, rng);
+                }
+                return;
+            }
+            TreeNode::Branch(inner) => {
+                node = match inner.hyperplane().is_point_above(&all_vecs[index]) {
+                    true => inner.right_mut(),
+                    false => inner.left_mut(),
+                };
+                depth += 1;
+            }
+        }
+    }
+}
+
+/// Replace every index stored in the leaves of `tree` with `remap[index]`, used when the vectors
+/// the tree refers to move to new positions, such as when they are merged into another index
+pub(crate) fn remap_tree<V: VectorOps>(tree: &mut TreeNode<V>, remap: &[usize]) {
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        match node {
+            TreeNode::Leaf(leaf) => {
+                for index in leaf.value.iter_mut() {
+                    *index = narrow(remap[*index as usize]);
+                }
+            }
+            TreeNode::Branch(inner) => {
+                let InnerNode {
+                    left_node,
+                    right_node,
+                    ..
+                } = inner.as_mut();
+                nodes.extend([left_node, right_node]);
+            }
+        }
+    }
+}
+
+/// Remove `index` from the leaf of `tree` that `vector` (the vector stored at `index`) falls into
+pub(crate) fn remove_from_tree<V: VectorOps>(tree: &mut TreeNode<V>, index: usize, vector: &V) {
+    find_leaf_mut(tree, vector).remove(index)
+}
+
+/// Replace `old` with `new` in the leaf of `tree` that `vector` (the vector stored at `old`) falls into
+pub(crate) fn replace_in_tree<V: VectorOps>(
+    tree: &mut TreeNode<V>,
+    old: usize,
+    new: usize,
+    vector: &V,
+) {
+    find_leaf_mut(tree, vector).replace(old, new)
+}
+
+/// Every vector is placed in the leaf found by following its side of each hyperplane,
+/// so following the same path again finds the leaf that holds it
+fn find_leaf_mut<'a, V: VectorOps>(tree: &'a mut TreeNode<V>, vector: &V) -> &'a mut LeafNode {
+    let mut node = tree;
+    loop {
+        match node {
+            TreeNode::Leaf(leaf) => return leaf,
+            TreeNode::Branch(inner) => {
+                node = match inner.hyperplane().is_point_above(vector) {
+                    true => inner.right_mut(),
+                    false => inner.left_mut(),
+                }
+            }
+        }
+    }
+}
+
+/// The smallest absolute margin of `query` over the hyperplanes on its own path from the root of
+/// `tree` to a leaf, or `None` if the tree is a single leaf, see `HyperPlane::margin`
+pub(crate) fn min_path_margin<V: VectorOps>(query: &V, tree: &TreeNode<V>) -> Option<V::Scalar> {
+    let mut min_margin: Option<V::Scalar> = None;
+    let mut node = tree;
+    while let TreeNode::Branch(inner) = node {
+        let margin = inner.hyperplane().margin(query);
+        if min_margin.is_none_or(|min_margin| margin.abs() < min_margin) {
+            min_margin = Some(margin.abs());
+        }
+        node = match margin >= V::Scalar::ZERO {
+            true => inner.right(),
+            false => inner.left(),
+        };
+    }
+    min_margin
+}
+
+/// Tallies of the work done while gathering candidates, shared by the trees searched in parallel
+#[derive(Default)]
+pub(crate) struct TraversalCounters {
+    pub nodes: AtomicUsize,
+    pub leaves: AtomicUsize,
+}
+
+/// The candidates gathered by `collect`, or every one of the `num_vectors` vectors if there are
+/// no `trees`
+/// An index built with `num_trees` zero has no trees to traverse, so its searches fall back to
+/// a linear scan of every vector and return the exact nearest neighbors
+pub(crate) fn candidates_or_scan<V: VectorOps, C: FromIterator<usize>>(
+    trees: &[TreeNode<V>],
+    num_vectors: usize,
+    collect: impl FnOnce() -> C,
+) -> C {
+    match trees.is_empty() {
+        true => (0..num_vectors).collect(),
+        false => collect(),
+    }
+}
+
+/// Searches gathering at most this many candidates in total, `n` from each tree, visit the trees
+/// on the calling thread, as spreading so little work across threads costs more than it saves
+const SEQUENTIAL_CANDIDATES: usize = 1024;
+
+/// Whether gathering up to `per_tree` candidates from each of `num_trees` trees is small enough to
+/// visit the trees on the calling thread, see `SEQUENTIAL_CANDIDATES`
+pub(crate) fn is_sequential_search(num_trees: usize, per_tree: usize) -> bool {
+    num_trees.saturating_mul(per_tree) <= SEQUENTIAL_CANDIDATES
+}
+
+/// Gather the candidates of every tree with `gather`, passing them to `merge` in tree order until
+/// `merge` returns `false`
+/// With `sequential` the trees are visited one after another on the calling thread, stopping as
+/// soon as `merge` does, otherwise they are visited in parallel, each tree collecting its own
+/// candidates which are merged once all trees are visited, so the trees never contend over a
+/// shared concurrent set
+pub(crate) fn merge_per_tree<Tree: Sync, C: Send>(
+    trees: &[Tree],
+    sequential: bool,
+    gather: impl Fn(&Tree) -> C + Sync + Send,
+    mut merge: impl FnMut(C) -> bool,
+) {
+    if sequential {
+        for tree in trees {
+            if !merge(gather(tree)) {
+                return;
+            }
+        }
+        return;
+    }
+    let per_tree: Vec<C> = trees.par_iter().map(gather).collect();
+    for candidates in per_tree {
+        if !merge(candidates) {
+            return;
+        }
+    }
+}
+
+/// Gather the indexes of up to `n` candidates from every tree for the `query` vector
+pub(crate) fn collect_candidates<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+) -> HashSet<usize> {
+    collect_counted_candidates(trees, query, n, None)
+}
+
+/// Gather the same candidates as `collect_candidates`, visiting the trees one after another
+/// on the calling thread
+pub(crate) fn collect_candidates_sequential<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+) -> HashSet<usize> {
+    visit_trees(trees, query, n, None, true)
+}
+
+/// Gather candidates the same way as `collect_candidates`, tallying the nodes and leaves
+/// visited in `counters` when they are supplied
+/// Small searches visit the trees on the calling thread, see `SEQUENTIAL_CANDIDATES`, larger ones
+/// visit the trees in parallel, see `merge_per_tree`
+pub(crate) fn collect_counted_candidates<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+    counters: Option<&TraversalCounters>,
+) -> HashSet<usize> {
+    let sequential = is_sequential_search(trees.len(), n.max(0) as usize);
+    visit_trees(trees, query, n, counters, sequential)
+}
+
+fn visit_trees<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+    counters: Option<&TraversalCounters>,
+    sequential: bool,
+) -> HashSet<usize> {
+    let mut candidates = HashSet::new();
+    let gather = |tree: &TreeNode<V>| {
+        let found = RefCell::new(vec![]);
+        let visit = |index| found.borrow_mut().push(index);
+        query_tree(query, n, tree, &visit, counters);
+        found.into_inner()
+    };
+    merge_per_tree(trees, sequential, gather, |found| {
+        candidates.extend(found);
+        true
+    });
+    candidates
+}
+
+/// Gather candidates the same way as `collect_candidates`, then revisit up to `probes` alternate
+/// branches of every tree, see `probe_tree`
+/// Gathering stops once `max_candidates` distinct candidates were found, the candidates of the
+/// trees are merged in tree order, so trees after the one that reached the cap contribute none
+/// With `sequential`, or for small searches, the trees are visited on the calling thread and the
+/// trees after the cap are not visited at all, see `merge_per_tree`
+pub(crate) fn collect_probed_candidates<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+    probes: usize,
+    max_candidates: usize,
+    sequential: bool,
+) -> HashSet<usize> {
+    let per_tree = (n.max(0) as usize).saturating_mul(probes.saturating_add(1));
+    let sequential = sequential || is_sequential_search(trees.len(), per_tree);
+    let mut candidates = HashSet::new();
+    let gather = |tree: &TreeNode<V>| probe_tree(query, n, tree, probes);
+    merge_per_tree(trees, sequential, gather, |found| {
+        for index in found {
+            if candidates.len() >= max_candidates {
+                return false;
+            }
+            candidates.insert(index);
+        }
+        candidates.len() < max_candidates
+    });
+    candidates
+}
+
+/// Gather the indexes of up to `n` candidates from every tree for the `query` vector, revisiting
+/// up to `probes` alternate branches of every tree, along with the number of trees that voted
+/// for each candidate by returning it
+/// Once `max_candidates` distinct candidates were found, only the candidates already gathered
+/// still collect votes from the remaining trees, which are merged in tree order
+/// The trees are visited the same way as `collect_probed_candidates`
+pub(crate) fn collect_candidate_votes<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    n: i32,
+    probes: usize,
+    max_candidates: usize,
+    sequential: bool,
+) -> HashMap<usize, usize> {
+    let per_tree = (n.max(0) as usize).saturating_mul(probes.saturating_add(1));
+    let sequential = sequential || is_sequential_search(trees.len(), per_tree);
+    let mut votes = HashMap::new();
+    let gather = |tree: &TreeNode<V>| probe_tree(query, n, tree, probes);
+    merge_per_tree(trees, sequential, gather, |found| {
+        for index in found {
+            let room = votes.len() < max_candidates;
+            match votes.entry(index) {
+                Entry::Occupied(mut count) => *count.get_mut() += 1,
+                Entry::Vacant(count) if room => {
+                    count.insert(1);
+                }
+                Entry::Vacant(_) => {}
+            }
+        }
+        true
+    });
+    votes
+}
+
+/// Gather up to `n` candidates of `tree` the same way as `query_tree`, then revisit the `probes`
+/// most promising alternate branches along the query's path from the root, gathering up to `n`
+/// more candidates from each
+/// The alternate branches are ranked by how close the query lies to the hyperplane separating
+/// them from its path, as those are the branches most likely to hold neighbors of the query
+fn probe_tree<V: VectorOps>(query: &V, n: i32, tree: &TreeNode<V>, probes: usize) -> Vec<usize> {
+    let candidates = RefCell::new(vec![]);
+    let visit = |index| candidates.borrow_mut().push(index);
+    query_tree(query, n, tree, &visit, None);
+
+    let mut alternates = vec![];
+    let mut node = tree;
+    while let TreeNode::Branch(inner) = node {
+        let margin = inner.hyperplane().margin(query).to_f64();
+        let (main, backup) = match inner.hyperplane().is_point_above(query) {
+            true => (inner.right(), inner.left()),
+            false => (inner.left(), inner.right()),
+        };
+        alternates.push((margin.abs(), backup));
+        node = main;
+    }
+    alternates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    for (_, backup) in alternates.into_iter().take(probes) {
+        query_tree(query, n, backup, &visit, None);
+    }
+    // a probed branch may have been visited by the query's own traversal already, the first
+    // visit is kept so the candidates are in the order they were found
+    let mut seen = HashSet::new();
+    let mut candidates = candidates.into_inner();
+    candidates.retain(|&index| seen.insert(index));
+    candidates
+}
+
+/// A node waiting to be explored by `collect_budgeted_candidates`
+/// The priority of a node is the smallest margin on the path to it, measured towards the node,
+/// so nodes on the query's side of every hyperplane rank first
+struct PendingNode<'a, V: VectorOps> {
+    priority: f64,
+    node: &'a TreeNode<V>,
+}
+
+impl<V: VectorOps> PartialEq for PendingNode<'_, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<V: VectorOps> Eq for PendingNode<'_, V> {}
+
+impl<V: VectorOps> PartialOrd for PendingNode<'_, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<V: VectorOps> Ord for PendingNode<'_, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority)
+    }
+}
+
+/// Gather candidates for the `query` vector by exploring the nodes of all trees best-first,
+/// stopping once `max_nodes` nodes (branches and leaves) have been visited
+/// Every leaf visited contributes all of its indexes
+pub(crate) fn collect_budgeted_candidates<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+    max_nodes: usize,
+) -> HashSet<usize> {
+    let mut queue: BinaryHeap<_> = trees
+        .iter()
+        .map(|node| PendingNode {
+            priority: f64::INFINITY,
+            node,
+        })
+        .collect();
+    let mut candidates = HashSet::new();
+
+    let mut visited = 0;
+    while let Some(PendingNode { priority, node }) = queue.pop() {
+        if visited == max_nodes {
+            break;
+        }
+        visited += 1;
+        match node {
+            TreeNode::Leaf(leaf) => candidates.extend(leaf.positions()),
+            TreeNode::Branch(inner) => {
+                let margin = inner.hyperplane().margin(query).to_f64();
+                queue.push(PendingNode {
+                    priority: priority.min(margin),
+                    node: inner.right(),
+                });
+                queue.push(PendingNode {
+                    priority: priority.min(-margin),
+                    node: inner.left(),
+                });
+            }
+        }
+    }
+    candidates
+}
+
+/// Gather every index in the query's leaf of each tree, plus the leaf the query would fall into
+/// within the backup side of every branch along its path
+/// Unlike `collect_candidates` this is not limited by a count, which suits radius searches
+/// The trees are visited in parallel, see `merge_per_tree`
+pub(crate) fn collect_path_candidates<V: VectorOps>(
+    trees: &[TreeNode<V>],
+    query: &V,
+) -> HashSet<usize> {
+    let mut candidates = HashSet::new();
+    merge_per_tree(
+        trees,
+        false,
+        |tree| probe_path(query, tree),
+        |found| {
+            candidates.extend(found);
+            true
+        },
+    );
+    candidates
+}
+
+fn probe_path<V: VectorOps>(query: &V, tree: &TreeNode<V>) -> Vec<usize> {
+    let mut candidates = vec![];
+    let mut node = tree;
+    while let TreeNode::Branch(inner) = node {
+        let (main, backup) = match inner.hyperplane().is_point_above(query) {
+            true => (inner.right(), inner.left()),
+            false => (inner.left(), inner.right()),
+        };
+        candidates.extend(find_leaf(backup, query).positions());
+        node = main;
+    }
+    candidates.extend(find_leaf(node, query).positions());
+    candidates
+}
+
+fn find_leaf<'a, V: VectorOps>(tree: &'a TreeNode<V>, vector: &V) -> &'a LeafNode {
+    let mut node = tree;
+    loop {
+        match node {
+            TreeNode::Leaf(leaf) => return leaf,
+            TreeNode::Branch(inner) => {
+                node = match inner.hyperplane().is_point_above(vector) {
+                    true => inner.right(),
+                    false => inner.left(),
+                }
+            }
+        }
+    }
+}
+
+/// Pass up to `n` candidate indexes of `tree` for the `query` vector to `visit`,
+/// each index is passed at most once as it is stored in a single leaf of the tree
+/// The query's side of every branch is searched before the other side, which is only visited
+/// while fewer than `n` candidates were found, the branches still to visit are kept on a heap
+/// allocated stack so deep trees cannot overflow the call stack
+/// The nodes and leaves visited are tallied in `counters` when they are supplied
+fn query_tree<V: VectorOps>(
+    query: &V,
+    n: i32,
+    tree: &TreeNode<V>,
+    visit: &impl Fn(usize),
+    counters: Option<&TraversalCounters>,
+) -> i32 {
+    let mut found = 0;
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        if found >= n {
+            break;
+        }
+        if let Some(counters) = counters {
+            counters.nodes.fetch_add(1, AtomicOrdering::Relaxed);
+            if let TreeNode::Leaf(_) = node {
+                counters.leaves.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+        }
+        match node {
+            TreeNode::Leaf(box_leaf) => {
+                let num_candidates_found = min((n - found) as usize, box_leaf.len());
+                for item in box_leaf.positions().take(num_candidates_found) {
+                    visit(item);
+                }
+                found += num_candidates_found as i32;
+            }
+            TreeNode::Branch(inner) => {
+                let (main, backup) = match inner.hyperplane().is_point_above(query) {
+                    true => (inner.right(), inner.left()),
+                    false => (inner.left(), inner.right()),
+                };
+                nodes.push(backup);
+                nodes.push(main);
+            }
+        }
+    }
+    found
+}
+
+/// Measure the depth and leaf sizes of `tree`
+pub(crate) fn tree_stats<V: VectorOps>(tree: &TreeNode<V>) -> TreeStats {
+    let mut leaf_sizes = vec![];
+    let max_depth = walk_leaves(tree, &mut leaf_sizes);
+    let total: usize = leaf_sizes.iter().sum();
+    TreeStats {
+        max_depth,
+        leaf_count: leaf_sizes.len(),
+        min_leaf_size: leaf_sizes.iter().copied().min().unwrap_or(0),
+        avg_leaf_size: total as f32 / leaf_sizes.len() as f32,
+        max_leaf_size: leaf_sizes.iter().copied().max().unwrap_or(0),
+    }
+}
+
+/// Check that the leaves of `tree`, the `number`th tree of an index of `num_vectors` vectors,
+/// hold every position from 0 to `num_vectors` exactly once
+pub(crate) fn validate_tree<V: VectorOps>(
+    number: usize,
+    tree: &TreeNode<V>,
+    num_vectors: usize,
+) -> Result<(), ValidationError> {
+    let mut seen = vec![false; num_vectors];
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        let leaf = match node {
+            TreeNode::Branch(inner) => {
+                nodes.extend([inner.left(), inner.right()]);
+                continue;
+            }
+            TreeNode::Leaf(leaf) => leaf,
+        };
+        for index in leaf.positions() {
+            match seen.get_mut(index) {
+                None => {
+                    return Err(ValidationError::IndexOutOfRange {
+                        tree: number,
+                        index,
+                        vectors: num_vectors,
+                    })
+                }
+                Some(true) => {
+                    return Err(ValidationError::DuplicateIndex {
+                        tree: number,
+                        index,
+                    })
+                }
+                Some(seen) => *seen = true,
+            }
+        }
+    }
+    match seen.iter().position(|&seen| !seen) {
+        Some(index) => Err(ValidationError::MissingIndex {
+            tree: number,
+            index,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The leaves of `tree` from left to right, the leaves below the hyperplane of a branch first
+pub(crate) fn leaves<V: VectorOps>(tree: &TreeNode<V>) -> Vec<&LeafNode> {
+    let mut leaves = vec![];
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        match node {
+            TreeNode::Branch(inner) => nodes.extend([inner.right(), inner.left()]),
+            TreeNode::Leaf(leaf) => leaves.push(leaf.as_ref()),
+        }
+    }
+    leaves
+}
+
+/// The number of distinct vector positions stored in the leaves of `tree`
+pub(crate) fn reachable_count<V: VectorOps>(tree: &TreeNode<V>) -> usize {
+    let mut reachable: HashSet<usize> = HashSet::new();
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        match node {
+            TreeNode::Branch(inner) => nodes.extend([inner.left(), inner.right()]),
+            TreeNode::Leaf(leaf) => reachable.extend(leaf.positions()),
+        }
+    }
+    reachable.len()
+}
+
+/// An estimate of the bytes `tree` occupies: every node, the hyperplane held inline by each
+/// branch, and the positions allocated by each leaf
+/// Allocator overhead and padding of the heap allocations are not counted
+pub(crate) fn tree_memory_usage<V: VectorOps>(tree: &TreeNode<V>) -> usize {
+    let mut bytes = size_of::<TreeNode<V>>();
+    let mut nodes = vec![tree];
+    while let Some(node) = nodes.pop() {
+        match node {
+            TreeNode::Branch(inner) => {
+                bytes += size_of::<InnerNode<V>>();
+                nodes.extend([inner.left(), inner.right()]);
+            }
+            TreeNode::Leaf(leaf) => {
+                bytes += size_of::<LeafNode>() + leaf.value.capacity() * size_of::<u32>();
+            }
+        }
+    }
+    bytes
+}
+
+/// Push the size of every leaf below `tree` onto `leaf_sizes`, returning the depth of the deepest one
+fn walk_leaves<V: VectorOps>(tree: &TreeNode<V>, leaf_sizes: &mut Vec<usize>) -> usize {
+    let mut max_depth = 0;
+    let mut nodes = vec![(tree, 0)];
+    while let Some((node, depth)) = nodes.pop() {
+        match node {
+            TreeNode::Leaf(leaf) => {
+                leaf_sizes.push(leaf.len());
+                max_depth = max_depth.max(depth);
+            }
+            TreeNode::Branch(inner) => {
+                nodes.extend([(inner.right(), depth + 1), (inner.left(), depth + 1)]);
+            }
+        }
+    }
+    max_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vector;
+
+    fn config(max_size: i32) -> TreeConfig {
+        TreeConfig {
+            max_size,
+            max_depth: None,
+            metric: DistanceMetric::default(),
+            strategy: SplitStrategy::default(),
+            sample_size: 2,
+        }
+    }
+
+    fn depth<V: VectorOps>(tree: &TreeNode<V>) -> usize {
+        match tree {
+            TreeNode::Leaf(_) => 0,
+            TreeNode::Branch(inner) => 1 + depth(inner.left()).max(depth(inner.right())),
+        }
+    }
+
+    #[test]
+    fn test_build_tree_with_duplicate_vectors() {
+        let mut vectors = vec![Vector::new([1.0, 1.0]); 500];
+        vectors.push(Vector::new([2.0, 2.0]));
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+
+        let tree = build_tree(
+            config(4),
+            0,
+            &indexes,
+            &vectors,
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert!(depth(&tree) <= 1);
+
+        let candidates = collect_candidates(&[tree], &Vector::new([1.0, 1.0]), 1000);
+        assert_eq!(candidates.len(), vectors.len());
+    }
+
+    #[test]
+    fn test_build_tree_with_zero_max_size() {
+        let vectors: Vec<Vector<2>> = (0..50).map(|_| Vector::random(None, None)).collect();
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+        let mut rng = StdRng::seed_from_u64(0);
+        let tree = build_tree(config(0), 0, &indexes, &vectors, &mut rng);
+
+        let mut leaf_sizes = vec![];
+        walk_leaves(&tree, &mut leaf_sizes);
+        assert_eq!(leaf_sizes.len(), vectors.len());
+        assert!(leaf_sizes.iter().all(|&size| size == 1));
+
+        let config = TreeConfig {
+            sample_size: 8,
+            ..config(0)
+        };
+        build_tree(config, 0, &indexes, &vectors, &mut rng);
+        for indexes in [vec![0], vec![0, 1]] {
+            two_means(
+                DistanceMetric::default(),
+                &indexes,
+                &vectors,
+                8,
+                1,
+                &mut rng,
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_tree_with_max_depth() {
+        let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+        let config = TreeConfig {
+            max_depth: Some(2),
+            ..config(1)
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut tree = build_tree(config, 0, &indexes, &vectors, &mut rng);
+        assert_eq!(depth(&tree), 2);
+        assert_eq!(tree_stats(&tree).leaf_count, 4);
+
+        let vectors: Vec<Vector<3>> = (0..300).map(|_| Vector::random(None, None)).collect();
+        for index in 200..300 {
+            insert_into_tree(&mut tree, index, config, &vectors, &mut rng);
+        }
+        assert_eq!(
+            depth(&tree),
+            2,
+            "Inserts should not split leaves at max_depth"
+        );
+    }
+
+    #[test]
+    fn test_cosine_hyperplanes_pass_through_origin() {
+        let vectors: Vec<Vector<3>> = (0..100).map(|_| Vector::random(None, None)).collect();
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let config = TreeConfig {
+            metric: DistanceMetric::Cosine,
+            ..config(4)
+        };
+        let tree = build_tree(config, 0, &indexes, &vectors, &mut rng);
+        let TreeNode::Branch(inner) = &tree else {
+            panic!("100 vectors should not fit in a single leaf of 4");
+        };
+        let plane = inner.hyperplane();
+
+        // only the direction of a vector decides its side of the hyperplane
+        for vector in vectors.iter() {
+            let above = plane.is_point_above(vector);
+            assert_eq!(above, plane.is_point_above(&vector.scale(10.0)));
+            assert_eq!(above, plane.is_point_above(&vector.scale(0.1)));
+        }
+    }
+
+    #[test]
+    fn test_kmeans_split_separates_clusters() {
+        let mut vectors: Vec<Vector<2>> = (0..50)
+            .map(|_| Vector::random(Some(-1.0), Some(1.0)))
+            .collect();
+        vectors.extend((0..50).map(|_| Vector::random(Some(99.0), Some(101.0))));
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+        let config = TreeConfig {
+            strategy: SplitStrategy::KMeans2,
+            ..config(50)
+        };
+
+        let tree = build_tree(config, 0, &indexes, &vectors, &mut StdRng::seed_from_u64(0));
+        let TreeNode::Branch(inner) = &tree else {
+            panic!("100 vectors should not fit in a single leaf of 50");
+        };
+        let plane = inner.hyperplane();
+        let near_origin = plane.is_point_above(&vectors[0]);
+        assert!(vectors[..50]
+            .iter()
+            .all(|v| plane.is_point_above(v) == near_origin));
+        assert!(vectors[50..]
+            .iter()
+            .all(|v| plane.is_point_above(v) != near_origin));
+    }
+
+    #[test]
+    fn test_budgeted_candidates() {
+        let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+        let config = config(8);
+        let trees = build_trees(3, config, &vectors, 0, None);
+        let query = Vector::random(None, None);
+
+        assert!(collect_budgeted_candidates(&trees, &query, 0).is_empty());
+        // nodes on the query's side of every hyperplane rank first, so the query's own path in
+        // every tree is explored before any backup side
+        let depth = trees.iter().map(depth).max().unwrap();
+        let budget = trees.len() * (depth + 1);
+        assert!(!collect_budgeted_candidates(&trees, &query, budget).is_empty());
+        let all = collect_budgeted_candidates(&trees, &query, usize::MAX);
+        assert_eq!(all.len(), vectors.len());
+    }
+
+    #[test]
+    fn test_parallel_and_sequential_candidates_agree() {
+        let vectors: Vec<Vector<3>> = (0..2000).map(|_| Vector::random(None, None)).collect();
+        let trees = build_trees(8, config(8), &vectors, 0, None);
+        let query = Vector::random(None, None);
+
+        // 8 trees of 4 candidates are gathered on the calling thread, 8 of 400 in parallel
+        for n in [4, 400] {
+            let counters = TraversalCounters::default();
+            let candidates = collect_counted_candidates(&trees, &query, n, Some(&counters));
+            assert_eq!(candidates, collect_candidates_sequential(&trees, &query, n));
+            assert!(counters.leaves.into_inner() >= trees.len());
+        }
+    }
+
+    #[test]
+    fn test_probed_candidates() {
+        let vectors: Vec<Vector<3>> = (0..500).map(|_| Vector::random(None, None)).collect();
+        let trees = build_trees(3, config(8), &vectors, 0, None);
+        let query = Vector::random(None, None);
+
+        let unprobed: HashSet<usize> = collect_candidates(&trees, &query, 8).into_iter().collect();
+        let probed: HashSet<usize> =
+            collect_probed_candidates(&trees, &query, 8, 0, usize::MAX, false)
+                .into_iter()
+                .collect();
+        assert_eq!(probed, unprobed);
+
+        let probed: HashSet<usize> =
+            collect_probed_candidates(&trees, &query, 8, 4, usize::MAX, false)
+                .into_iter()
+                .collect();
+        assert!(probed.is_superset(&unprobed));
+        assert!(probed.len() > unprobed.len());
+    }
+
+    #[test]
+    fn test_max_candidates() {
+        let vectors: Vec<Vector<3>> = (0..500).map(|_| Vector::random(None, None)).collect();
+        let trees = build_trees(8, config(8), &vectors, 0, None);
+        let query = Vector::random(None, None);
+
+        for sequential in [false, true] {
+            for max_candidates in [0, 1, 5, 20] {
+                let candidates =
+                    collect_probed_candidates(&trees, &query, 16, 2, max_candidates, sequential);
+                assert!(candidates.len() <= max_candidates);
+                let votes =
+                    collect_candidate_votes(&trees, &query, 16, 2, max_candidates, sequential);
+                assert!(votes.len() <= max_candidates);
+            }
+            let candidates = collect_probed_candidates(&trees, &query, 16, 2, 20, sequential);
+            assert_eq!(candidates.len(), 20);
+        }
+    }
+
+    #[test]
+    fn test_deep_tree_does_not_overflow_the_stack() {
+        // every split of scaled basis vectors peels off a single vector, so the tree is a chain
+        let vectors: Vec<Vector<512>> = (0..512)
+            .map(|i| Vector::from_fn(|j| if i == j { (i + 1) as f32 } else { 0.0 }))
+            .collect();
+        let indexes: Vec<usize> = (0..vectors.len()).collect();
+
+        let stats = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                let mut rng = StdRng::seed_from_u64(0);
+                let tree = build_tree(config(1), 0, &indexes, &vectors, &mut rng);
+                assert_eq!(validate_tree(0, &tree, vectors.len()), Ok(()));
+                let candidates =
+                    collect_candidates_sequential(std::slice::from_ref(&tree), &vectors[0], 512);
+                assert_eq!(candidates.len(), vectors.len());
+                tree_stats(&tree)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(stats.leaf_count, 512);
+        assert!(stats.max_depth > 256, "depth {}", stats.max_depth);
+    }
+
+    #[cfg(all(debug_assertions, target_pointer_width = "64"))]
+    #[test]
+    #[should_panic(expected = "does not fit in a leaf")]
+    fn test_positions_beyond_u32_are_caught() {
+        LeafNode::from_positions(&[u32::MAX as usize + 1]);
+    }
+
+    #[test]
+    fn test_deep_tree_clones_formats_and_drops() {
+        // a chain of branches each holding a single vector on its left side
+        let plane = HyperPlane::new(Vector::new([1.0, 0.0]), 0.0);
+        let mut tree = TreeNode::leaf(vec![0]);
+        for i in 1..50_000 {
+            tree = TreeNode::branch(plane.clone(), TreeNode::leaf(vec![i]), tree);
+        }
+
+        let stats = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                let cloned = tree.clone();
+                let formatted = format!("{:?}", cloned);
+                assert_eq!(formatted, format!("{:?}", tree));
+                assert_eq!(formatted.matches("Leaf(").count(), 50_000);
+                drop(tree);
+                tree_stats(&cloned)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(stats.leaf_count, 50_000);
+        assert_eq!(stats.max_depth, 49_999);
+
+        let small = TreeNode::branch(plane, TreeNode::leaf(vec![1]), TreeNode::leaf(vec![2]));
+        assert_eq!(
+            format!("{:?}", small.clone()),
+            "Branch(InnerNode { hyperplane: HyperPlane { coefficients: Vector { values: [1.0, 0.0] }, \
+             constant: 0.0 }, left_node: Leaf(LeafNode { value: [1] }), \
+             right_node: Leaf(LeafNode { value: [2] }) })"
+        );
+    }
 }
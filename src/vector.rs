@@ -1,101 +1,442 @@
 use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::ops;
 
+use crate::error::{DimError, VectorError};
+use crate::query::PreparedQuery;
+use crate::scalar::VectorScalar;
+
+/// A vector of `N` values, laid out exactly like `[T; N]` so a slice of vectors can be viewed
+/// as a flat slice of values, see `flatten`
 #[derive(Copy, Clone, Debug)]
-pub struct Vector<const N: usize> {
-    values: [f32; N],
+#[repr(transparent)]
+pub struct Vector<const N: usize, T = f32> {
+    values: [T; N],
 }
 
-impl<const N: usize> Vector<N> {
-    pub fn new(values: [f32; N]) -> Self {
+/// A vector of `N` `f64` values, for data that needs more precision than `f32`
+/// An index of `Vector64` vectors computes distances and splits its trees in `f64` throughout
+pub type Vector64<const N: usize> = Vector<N, f64>;
+
+impl<const N: usize, T: VectorScalar> Vector<N, T> {
+    pub fn new(values: [T; N]) -> Self {
         Self { values }
     }
 
-    pub fn random(lower_bound: Option<f32>, upper_bound: Option<f32>) -> Self {
-        let lower_bound = lower_bound.unwrap_or(-1.0);
-        let upper_bound = upper_bound.unwrap_or(1.0);
-        let mut rng = rand::thread_rng();
-        let values = (0..N)
-            // .map(|_| rng.gen_range(-1.0..1.0))
-            .map(|_| rng.gen_range(lower_bound..upper_bound))
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+    /// Build a vector the same way as `new`, rejecting NaN and infinite values
+    /// Such values compare false against every hyperplane and would silently corrupt an index
+    /// Returns `VectorError::NonFinite` with the index of the first value that is not finite
+    pub fn try_new(values: [T; N]) -> Result<Self, VectorError> {
+        match values.iter().position(|value| !value.is_finite()) {
+            Some(index) => Err(VectorError::NonFinite { index }),
+            None => Ok(Self { values }),
+        }
+    }
 
-        Self { values }
+    /// Whether every value of the vector is neither NaN nor infinite
+    pub fn is_finite(&self) -> bool {
+        self.values.iter().all(|value| value.is_finite())
+    }
+
+    /// Build a vector from runtime data such as a parsed row
+    /// Returns `DimError` if the slice does not hold exactly `N` values
+    pub fn from_slice(values: &[T]) -> Result<Self, DimError> {
+        let values = values.try_into().map_err(|_| DimError {
+            expected: N,
+            found: values.len(),
+        })?;
+        Ok(Self { values })
+    }
+
+    /// Build a vector whose value at each index `i` is `f(i)`, e.g. a ramp, a one-hot or a basis vector
+    pub fn from_fn(f: impl FnMut(usize) -> T) -> Self {
+        Self {
+            values: std::array::from_fn(f),
+        }
+    }
+
+    /// The values of the vector
+    pub fn as_slice(&self) -> &[T] {
+        &self.values
     }
 
-    pub fn dot(&self, vector: &Vector<N>) -> f32 {
+    /// Consume the vector, returning its values
+    pub fn into_array(self) -> [T; N] {
         self.values
-            .iter()
-            .zip(vector.values)
-            .map(|(a, b)| a * b)
-            .sum::<f32>()
     }
 
-    pub fn avg(&self, vector: &Vector<N>) -> Vector<N> {
-        let average = self
-            .values
-            .iter()
-            .zip(vector.values)
-            .map(|(a, b)| (a + b) / 2.0)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+    /// A vector of values drawn uniformly from `lower_bound..upper_bound`, -1 to 1 by default
+    pub fn random(lower_bound: Option<T>, upper_bound: Option<T>) -> Self {
+        let lower_bound = lower_bound.unwrap_or(T::from_f64(-1.0));
+        let upper_bound = upper_bound.unwrap_or(T::ONE);
+        Self::random_with(&mut rand::thread_rng(), lower_bound, upper_bound)
+    }
 
-        Vector { values: average }
+    /// A vector of values drawn uniformly from `lower_bound..upper_bound` with `rng`, so a seeded
+    /// rng generates the same vectors every time
+    pub fn random_with<R: Rng>(rng: &mut R, lower_bound: T, upper_bound: T) -> Self {
+        let (lower_bound, upper_bound) = (lower_bound.to_f64(), upper_bound.to_f64());
+        Self::from_fn(|_| T::from_f64(rng.gen_range(lower_bound..upper_bound)))
     }
 
-    pub fn sub(&self, vector: &Vector<N>) -> Vector<N> {
-        let difference = self
-            .values
-            .iter()
-            .zip(vector.values)
-            .map(|(a, b)| a - b)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+    /// A vector of values drawn with `rng` from a normal distribution with `mean` and `std_dev`,
+    /// which resembles real embeddings far better than uniform values, e.g. to benchmark recall
+    ///
+    /// Panics if `std_dev` is negative or not finite, the same as `random_with` panics on an empty range
+    pub fn random_normal<R: Rng>(rng: &mut R, mean: T, std_dev: T) -> Self {
+        let std_dev = std_dev.to_f64();
+        assert!(
+            std_dev >= 0.0 && std_dev.is_finite(),
+            "std_dev {} is not a finite non-negative number",
+            std_dev
+        );
+        let normal = Normal::new(mean.to_f64(), std_dev).expect("std_dev is finite");
+        Self::from_fn(|_| T::from_f64(normal.sample(rng)))
+    }
 
-        Vector { values: difference }
+    /// `count` vectors drawn the same way as `random_with`, for benchmarks and tests that need
+    /// many vectors at once
+    /// The batch is allocated once, and every value is written straight into its vector
+    pub fn random_batch<R: Rng>(
+        rng: &mut R,
+        count: usize,
+        lower_bound: T,
+        upper_bound: T,
+    ) -> Vec<Self> {
+        (0..count)
+            .map(|_| Self::random_with(rng, lower_bound, upper_bound))
+            .collect()
     }
 
-    pub fn add(&self, vector: &Vector<N>) -> Vector<N> {
-        let total = self
-            .values
+    pub fn dot(&self, vector: &Vector<N, T>) -> T {
+        self.values
             .iter()
             .zip(vector.values)
-            .map(|(a, b)| a + b)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+            .map(|(&a, b)| a * b)
+            .fold(T::ZERO, |sum, x| sum + x)
+    }
+
+    pub fn avg(&self, vector: &Vector<N, T>) -> Vector<N, T> {
+        let two = T::from_f64(2.0);
+        self.zip_with(vector, |a, b| (a + b) / two)
+    }
+
+    /// The elementwise mean of `vectors`, or `None` if there are no vectors
+    /// The values are summed in `f64` and divided once at the end, so long or low precision
+    /// inputs do not accumulate rounding error while summing
+    pub fn centroid(vectors: &[Vector<N, T>]) -> Option<Vector<N, T>> {
+        if vectors.is_empty() {
+            return None;
+        }
+        let mut sums = [0.0; N];
+        for vector in vectors {
+            for (sum, value) in sums.iter_mut().zip(vector.values) {
+                *sum += value.to_f64();
+            }
+        }
+        let count = vectors.len() as f64;
+        Some(Vector {
+            values: sums.map(|sum| T::from_f64(sum / count)),
+        })
+    }
+
+    pub fn sub(&self, vector: &Vector<N, T>) -> Vector<N, T> {
+        self.zip_with(vector, |a, b| a - b)
+    }
+
+    pub fn add(&self, vector: &Vector<N, T>) -> Vector<N, T> {
+        self.zip_with(vector, |a, b| a + b)
+    }
+
+    pub fn scale(&self, factor: T) -> Vector<N, T> {
+        Vector {
+            values: self.values.map(|a| a * factor),
+        }
+    }
+
+    /// The elementwise product of two vectors
+    pub fn hadamard(&self, vector: &Vector<N, T>) -> Vector<N, T> {
+        self.zip_with(vector, |a, b| a * b)
+    }
+
+    pub fn neg(&self) -> Vector<N, T> {
+        Vector {
+            values: self.values.map(|a| -a),
+        }
+    }
+
+    /// Combine the values of two vectors pairwise with `f`, building the result array in place
+    fn zip_with(&self, vector: &Vector<N, T>, f: impl Fn(T, T) -> T) -> Vector<N, T> {
+        Vector::from_fn(|i| f(self.values[i], vector.values[i]))
+    }
+
+    pub fn squared_euclidian_distance(&self, vector: &Vector<N, T>) -> T {
+        T::squared_differences(&self.values, &vector.values)
+    }
+
+    /// The euclidian (L2) distance, the square root of `squared_euclidian_distance`
+    /// Prefer the squared distance when only comparing distances, it ranks the same and skips the root
+    pub fn euclidian_distance(&self, vector: &Vector<N, T>) -> T {
+        self.squared_euclidian_distance(vector).sqrt()
+    }
+
+    /// The cosine of the angle between the two vectors, from -1 (opposite) to 1 (same direction)
+    /// The zero vector has no direction, so the similarity is defined as 0 if either vector is zero
+    pub fn cosine_similarity(&self, vector: &Vector<N, T>) -> T {
+        let magnitudes = self.magnitude() * vector.magnitude();
+        if magnitudes == T::ZERO {
+            return T::ZERO;
+        }
+        self.dot(vector) / magnitudes
+    }
+
+    /// One minus the cosine similarity, from 0 (same direction) to 2 (opposite)
+    pub fn cosine_distance(&self, vector: &Vector<N, T>) -> T {
+        T::ONE - self.cosine_similarity(vector)
+    }
+
+    /// The euclidian length (L2 norm) of the vector
+    pub fn magnitude(&self) -> T {
+        self.dot(self).sqrt()
+    }
+
+    /// A unit length copy of the vector pointing in the same direction
+    /// The zero vector has no direction, so it is returned unchanged rather than divided by zero
+    pub fn normalized(&self) -> Vector<N, T> {
+        let mut vector = *self;
+        vector.normalize_mut();
+        vector
+    }
+
+    /// Normalize the vector once and prepare it as a query for `DistanceMetric::Cosine` searches,
+    /// so the search never has to divide by the magnitude of the query
+    /// A query that is already of unit length can skip the normalization with `PreparedQuery::normalized`
+    pub fn prepare_for_cosine(&self) -> PreparedQuery<N, T> {
+        PreparedQuery::normalized(self.normalized())
+    }
 
-        Vector { values: total }
+    /// Scale the vector to unit length in place, leaving the zero vector unchanged
+    pub fn normalize_mut(&mut self) {
+        let magnitude = self.magnitude();
+        if magnitude == T::ZERO {
+            return;
+        }
+        for value in self.values.iter_mut() {
+            *value = *value / magnitude;
+        }
     }
 
-    pub fn squared_euclidian_distance(&self, vector: &Vector<N>) -> f32 {
+    /// The manhattan (L1) distance, the sum of the absolute differences of the values
+    pub fn manhattan_distance(&self, vector: &Vector<N, T>) -> T {
         self.values
             .iter()
             .zip(vector.values)
-            .map(|(a, b)| (a - b).powi(2))
-            .sum()
+            .map(|(&a, b)| (a - b).abs())
+            .fold(T::ZERO, |sum, x| sum + x)
     }
 
-    /// Rust does not implement hash for the f32 type. This is a workaround
+    /// Rust does not implement hash for floating point types. This is a workaround
     /// since we need to be able to identify a vector's contents for deduplication
     pub fn hashkey(&self) -> HashKey<N> {
-        let hash = self
-            .values
-            .iter()
-            .map(|a| a.to_bits())
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
+        HashKey(self.values.map(|a| a.to_bits()))
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for value in self.values {
+            value.write_le(writer)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut values = [T::ZERO; N];
+        for value in values.iter_mut() {
+            *value = T::read_le(reader)?;
+        }
+        Ok(Self { values })
+    }
+}
+
+// The operators are implemented through `ops::` paths rather than imported, so the trait methods
+// never shadow the inherent `add`, `sub`, and `neg` methods that take their arguments by reference
+impl<const N: usize, T: VectorScalar> ops::Add for Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn add(self, vector: Vector<N, T>) -> Vector<N, T> {
+        Vector::add(&self, &vector)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> ops::Add for &Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn add(self, vector: &Vector<N, T>) -> Vector<N, T> {
+        Vector::add(self, vector)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> ops::Sub for Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn sub(self, vector: Vector<N, T>) -> Vector<N, T> {
+        Vector::sub(&self, &vector)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> ops::Sub for &Vector<N, T> {
+    type Output = Vector<N, T>;
 
-        HashKey(hash)
+    fn sub(self, vector: &Vector<N, T>) -> Vector<N, T> {
+        Vector::sub(self, vector)
     }
 }
 
+impl<const N: usize, T: VectorScalar> ops::Mul<T> for Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn mul(self, factor: T) -> Vector<N, T> {
+        self.scale(factor)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> ops::Mul<T> for &Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn mul(self, factor: T) -> Vector<N, T> {
+        self.scale(factor)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> ops::Neg for Vector<N, T> {
+    type Output = Vector<N, T>;
+
+    fn neg(self) -> Vector<N, T> {
+        Vector::neg(&self)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> TryFrom<&[T]> for Vector<N, T> {
+    type Error = DimError;
+
+    fn try_from(values: &[T]) -> Result<Self, DimError> {
+        Vector::from_slice(values)
+    }
+}
+
+impl<const N: usize, T: VectorScalar> TryFrom<Vec<T>> for Vector<N, T> {
+    type Error = DimError;
+
+    fn try_from(values: Vec<T>) -> Result<Self, DimError> {
+        Vector::from_slice(&values)
+    }
+}
+
+/// Vectors serialize as a sequence of their `N` values
+#[cfg(feature = "serde")]
+impl<const N: usize, T: serde::Serialize> serde::Serialize for Vector<N, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.values.iter())
+    }
+}
+
+/// Deserializing a sequence that does not hold exactly `N` values is an `invalid_length` error
+#[cfg(feature = "serde")]
+impl<'de, const N: usize, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Vector<N, T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<T>::deserialize(deserializer)?;
+        let values = values.try_into().map_err(|values: Vec<T>| {
+            let expected = format!("a vector of {} values", N);
+            serde::de::Error::invalid_length(values.len(), &expected.as_str())
+        })?;
+        Ok(Vector { values })
+    }
+}
+
+/// View `vectors` as a single row-major slice of `vectors.len() * N` values, where the values of
+/// `vectors[i]` are at `i * N..(i + 1) * N`
+pub fn flatten<const N: usize, T>(vectors: &[Vector<N, T>]) -> &[T] {
+    // SAFETY: `Vector` is `repr(transparent)` over `[T; N]`, so the slice is `vectors.len()`
+    // contiguous arrays of `N` values without padding
+    unsafe { std::slice::from_raw_parts(vectors.as_ptr() as *const T, vectors.len() * N) }
+}
+
 #[derive(Eq, PartialEq, Hash, Debug)]
-pub struct HashKey<const N: usize>([u32; N]);
+pub struct HashKey<const N: usize>([u64; N]);
+
+/// The operations an index needs from a vector type to build and search its trees
+/// This is implemented by both the const-generic `Vector<N, T>` and the runtime sized `DynVector`
+pub trait VectorOps: Clone + Send + Sync {
+    type Scalar: VectorScalar;
+    type HashKey: Eq + Hash;
+
+    fn dimension(&self) -> usize;
+    fn dot(&self, vector: &Self) -> Self::Scalar;
+    fn sub(&self, vector: &Self) -> Self;
+    fn avg(&self, vector: &Self) -> Self;
+    fn add(&self, vector: &Self) -> Self;
+    fn scale(&self, factor: Self::Scalar) -> Self;
+    fn normalized(&self) -> Self;
+    fn squared_euclidian_distance(&self, vector: &Self) -> Self::Scalar;
+    fn euclidian_distance(&self, vector: &Self) -> Self::Scalar;
+    fn cosine_distance(&self, vector: &Self) -> Self::Scalar;
+    fn manhattan_distance(&self, vector: &Self) -> Self::Scalar;
+    fn hashkey(&self) -> Self::HashKey;
+}
+
+impl<const N: usize, T: VectorScalar> VectorOps for Vector<N, T> {
+    type Scalar = T;
+    type HashKey = HashKey<N>;
+
+    fn dimension(&self) -> usize {
+        N
+    }
+
+    fn dot(&self, vector: &Self) -> T {
+        Vector::dot(self, vector)
+    }
+
+    fn sub(&self, vector: &Self) -> Self {
+        Vector::sub(self, vector)
+    }
+
+    fn avg(&self, vector: &Self) -> Self {
+        Vector::avg(self, vector)
+    }
+
+    fn add(&self, vector: &Self) -> Self {
+        Vector::add(self, vector)
+    }
+
+    fn scale(&self, factor: T) -> Self {
+        Vector::scale(self, factor)
+    }
+
+    fn normalized(&self) -> Self {
+        Vector::normalized(self)
+    }
+
+    fn squared_euclidian_distance(&self, vector: &Self) -> T {
+        Vector::squared_euclidian_distance(self, vector)
+    }
+
+    fn euclidian_distance(&self, vector: &Self) -> T {
+        Vector::euclidian_distance(self, vector)
+    }
+
+    fn cosine_distance(&self, vector: &Self) -> T {
+        Vector::cosine_distance(self, vector)
+    }
+
+    fn manhattan_distance(&self, vector: &Self) -> T {
+        Vector::manhattan_distance(self, vector)
+    }
+
+    fn hashkey(&self) -> Self::HashKey {
+        Vector::hashkey(self)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -107,6 +448,67 @@ mod tests {
         assert_eq!(vector.values, [0.0, 0.0, 0.0]);
     }
 
+    #[test]
+    fn test_try_new() {
+        let vector = Vector::<3>::try_new([1.0, 2.0, 3.0]).unwrap();
+        assert!(vector.is_finite());
+
+        let error = Vector::<3>::try_new([1.0, f32::NAN, 3.0]).unwrap_err();
+        assert_eq!(error, VectorError::NonFinite { index: 1 });
+        let error = Vector::<2>::try_new([f32::INFINITY, 0.0]).unwrap_err();
+        assert_eq!(error, VectorError::NonFinite { index: 0 });
+        assert!(!Vector::<2>::new([0.0, f32::NAN]).is_finite());
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let vector = Vector::<3>::from_slice(&[1.0, 2.0, 3.0]).unwrap();
+        assert_eq!(vector.values, [1.0, 2.0, 3.0]);
+
+        let error = Vector::<3>::from_slice(&[1.0, 2.0]).unwrap_err();
+        assert_eq!(
+            error,
+            DimError {
+                expected: 3,
+                found: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_try_from() {
+        let vector = Vector::<3>::try_from(vec![1.0, 2.0, 3.0]).unwrap();
+        assert_eq!(vector.values, [1.0, 2.0, 3.0]);
+        let vector: Vector<2> = [4.0, 5.0][..].try_into().unwrap();
+        assert_eq!(vector.values, [4.0, 5.0]);
+
+        let error = Vector::<3>::try_from(vec![1.0; 4]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "expected 3 values for the vector but found 4"
+        );
+    }
+
+    #[test]
+    fn test_as_slice() {
+        let vector = Vector::new([1.0, 2.0, 3.0]);
+        assert_eq!(vector.as_slice(), &[1.0, 2.0, 3.0]);
+        assert_eq!(vector.into_array(), [1.0, 2.0, 3.0]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let vector = Vector::new([1.0, 2.5, 3.0]);
+        let json = serde_json::to_string(&vector).unwrap();
+        assert_eq!(json, "[1.0,2.5,3.0]");
+        let parsed: Vector<3> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.values, vector.values);
+
+        let error = serde_json::from_str::<Vector<3>>("[1.0,2.0]").unwrap_err();
+        assert!(error.to_string().contains("a vector of 3 values"));
+    }
+
     #[test]
     fn test_rand() {
         let a: Vector<1> = Vector::random(None, None);
@@ -123,6 +525,69 @@ mod tests {
         assert!(a.values[0] <= 2.0, "Should be less than or equal to 2.0");
     }
 
+    #[test]
+    fn test_from_fn() {
+        let ramp: Vector<4> = Vector::from_fn(|i| i as f32);
+        assert_eq!(ramp.values, [0.0, 1.0, 2.0, 3.0]);
+        let one_hot: Vector<3, f64> = Vector::from_fn(|i| if i == 1 { 1.0 } else { 0.0 });
+        assert_eq!(one_hot.values, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_random_with() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let a: Vector<8> = Vector::random_with(&mut StdRng::seed_from_u64(4), 0.0, 2.0);
+        let b: Vector<8> = Vector::random_with(&mut StdRng::seed_from_u64(4), 0.0, 2.0);
+        assert_eq!(
+            a.values, b.values,
+            "The same seed should give the same vector"
+        );
+        assert!(a.values.iter().all(|v| (0.0..2.0).contains(v)));
+
+        let batch: Vec<Vector<8>> =
+            Vector::random_batch(&mut StdRng::seed_from_u64(4), 100, 0.0, 2.0);
+        assert_eq!(batch.len(), 100);
+        assert_eq!(batch[0].values, a.values);
+        assert!(flatten(&batch).iter().all(|v| (0.0..2.0).contains(v)));
+    }
+
+    #[test]
+    fn test_random_normal() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let vector: Vector<2000> = Vector::random_normal(&mut StdRng::seed_from_u64(4), 3.0, 2.0);
+        let mean = vector.values.iter().sum::<f32>() / 2000.0;
+        let variance = vector
+            .values
+            .iter()
+            .map(|v| (v - mean).powi(2))
+            .sum::<f32>()
+            / 2000.0;
+        assert!((mean - 3.0).abs() < 0.3, "mean {}", mean);
+        assert!(
+            (variance.sqrt() - 2.0).abs() < 0.2,
+            "std_dev {}",
+            variance.sqrt()
+        );
+        // unlike uniform values, a normal sample reaches past two standard deviations
+        assert!(vector.values.iter().any(|&v| v > 3.0 + 2.0 * 2.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "not a finite non-negative number")]
+    fn test_random_normal_negative_std_dev() {
+        Vector::<4>::random_normal(&mut rand::thread_rng(), 0.0, -1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a finite non-negative number")]
+    fn test_random_normal_nan_std_dev() {
+        Vector::<4>::random_normal(&mut rand::thread_rng(), 0.0, f32::NAN);
+    }
+
     #[test]
     fn test_add() {
         let a = Vector::new([1.0, 2.0, 3.0]);
@@ -162,6 +627,53 @@ mod tests {
         assert_eq!(c.values, [1.5, 1.5, 1.5]);
     }
 
+    #[test]
+    fn test_centroid() {
+        let vectors = [
+            Vector::new([1.0, 2.0, 3.0]),
+            Vector::new([4.0, 5.0, 6.0]),
+            Vector::new([-2.0, 2.0, 0.0]),
+        ];
+        let centroid = Vector::<3>::centroid(&vectors).unwrap();
+        assert_eq!(centroid.values, [1.0, 3.0, 3.0]);
+
+        assert!(Vector::<3>::centroid(&[]).is_none());
+    }
+
+    #[test]
+    fn test_scale() {
+        let a: Vector<3> = Vector::new([1.0, -2.0, 3.0]);
+        assert_eq!(a.scale(2.0).values, [2.0, -4.0, 6.0]);
+        assert_eq!(a.scale(0.0).values, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_hadamard() {
+        let a: Vector<3> = Vector::new([1.0, 2.0, 3.0]);
+        let b = Vector::new([4.0, -5.0, 6.0]);
+        assert_eq!(a.hadamard(&b).values, [4.0, -10.0, 18.0]);
+    }
+
+    #[test]
+    fn test_neg() {
+        let a: Vector<3> = Vector::new([1.0, -2.0, 0.0]);
+        assert_eq!(a.neg().values, [-1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_operators() {
+        let a: Vector<3> = Vector::new([1.0, 2.0, 3.0]);
+        let b = Vector::new([4.0, 5.0, 6.0]);
+        let (ref_a, ref_b) = (&a, &b);
+        assert_eq!((a + b).values, [5.0, 7.0, 9.0]);
+        assert_eq!((ref_a + ref_b).values, [5.0, 7.0, 9.0]);
+        assert_eq!((a - b).values, [-3.0, -3.0, -3.0]);
+        assert_eq!((ref_a - ref_b).values, [-3.0, -3.0, -3.0]);
+        assert_eq!((a * 2.0).values, [2.0, 4.0, 6.0]);
+        assert_eq!((ref_a * 2.0).values, [2.0, 4.0, 6.0]);
+        assert_eq!((-a).values, [-1.0, -2.0, -3.0]);
+    }
+
     #[test]
     fn test_dot() {
         let a = Vector::new([1.0, 2.0, 3.0]);
@@ -188,6 +700,75 @@ mod tests {
         assert_eq!(c, 89.0);
     }
 
+    #[test]
+    fn test_euclidian_distance() {
+        let a: Vector<2> = Vector::new([1.0, 2.0]);
+        let b: Vector<2> = Vector::new([4.0, 6.0]);
+        assert_eq!(a.euclidian_distance(&b), 25.0f32.sqrt());
+        assert_eq!(a.euclidian_distance(&b), 5.0);
+        assert_eq!(a.euclidian_distance(&a), 0.0);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let a: Vector<2> = Vector::new([1.0, 0.0]);
+        let b: Vector<2> = Vector::new([0.0, 2.0]);
+        let c: Vector<2> = Vector::new([3.0, 0.0]);
+        let d: Vector<2> = Vector::new([-1.0, 0.0]);
+        assert_eq!(a.cosine_similarity(&b), 0.0);
+        assert_eq!(a.cosine_similarity(&c), 1.0);
+        assert_eq!(a.cosine_similarity(&d), -1.0);
+        assert_eq!(a.cosine_distance(&c), 0.0);
+        assert_eq!(a.cosine_distance(&d), 2.0);
+
+        let zero: Vector<2> = Vector::new([0.0, 0.0]);
+        assert_eq!(a.cosine_similarity(&zero), 0.0);
+        assert_eq!(zero.cosine_similarity(&zero), 0.0);
+        assert_eq!(zero.cosine_distance(&a), 1.0);
+    }
+
+    #[test]
+    fn test_flatten() {
+        let vectors: [Vector<2>; 3] = [
+            Vector::new([1.0, 2.0]),
+            Vector::new([3.0, 4.0]),
+            Vector::new([5.0, 6.0]),
+        ];
+        assert_eq!(flatten(&vectors), &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(flatten(&vectors[1..2]), vectors[1].as_slice());
+        assert!(flatten::<2, f32>(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_manhattan_distance() {
+        let a: Vector<2> = Vector::new([1.0, 2.0]);
+        let b: Vector<2> = Vector::new([4.0, 6.0]);
+        assert_eq!(a.manhattan_distance(&b), 7.0);
+
+        let a: Vector<2> = Vector::new([-1.0, -2.0]);
+        assert_eq!(a.manhattan_distance(&b), 13.0);
+        assert_eq!(b.manhattan_distance(&a), 13.0);
+    }
+
+    #[test]
+    fn test_normalized() {
+        let a: Vector<2> = Vector::new([3.0, 4.0]);
+        assert_eq!(a.magnitude(), 5.0);
+        assert_eq!(a.normalized().values, [0.6, 0.8]);
+
+        let mut b: Vector<2> = Vector::new([0.0, -2.0]);
+        b.normalize_mut();
+        assert_eq!(b.values, [0.0, -1.0]);
+
+        let zero: Vector<2> = Vector::new([0.0, 0.0]);
+        assert_eq!(zero.magnitude(), 0.0);
+        assert_eq!(
+            zero.normalized().values,
+            [0.0, 0.0],
+            "Zero vector should be unchanged"
+        );
+    }
+
     #[test]
     fn test_hashkey() {
         let a = Vector::new([1.0, 2.0, 3.0]);
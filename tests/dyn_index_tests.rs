@@ -0,0 +1,79 @@
+use sieve::dyn_index::DynApproximateNearestNeighborsIndex;
+use sieve::dyn_vector::DynVector;
+use sieve::error::{BuildError, DimError};
+
+#[test]
+fn test_simple_dyn_index() {
+    let vectors = vec![
+        DynVector::new(vec![1.0, 2.0, 3.0]),
+        DynVector::new(vec![3.0, 4.0, 5.0]),
+    ];
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+
+    let index = DynApproximateNearestNeighborsIndex::build(2, 2, &vectors, &ids).unwrap();
+    assert_eq!(index.dimension(), 3);
+
+    let query = DynVector::new(vec![1.0, 2.0, 3.0]);
+    let results = index.search(&query, 2).unwrap();
+
+    assert!(results.len() == 2, "Should only return 2 results");
+
+    let top_1 = results.first().unwrap();
+    assert!(
+        top_1.vector_id == 0,
+        "Top search result should be the first vector"
+    );
+    assert!(
+        top_1.distance == 0.0,
+        "Top search result should be the first vector"
+    );
+}
+
+#[test]
+fn test_dyn_index_without_trees_scans_every_vector() {
+    let vectors: Vec<DynVector> = (0..20)
+        .map(|i| DynVector::new(vec![i as f32, (i * i) as f32]))
+        .collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32 * 10).collect();
+
+    let index = DynApproximateNearestNeighborsIndex::build(0, 4, &vectors, &ids).unwrap();
+    let results = index.search(&DynVector::new(vec![5.2, 27.0]), 3).unwrap();
+    let found: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+    assert_eq!(found, vec![50, 60, 40]);
+    assert!((results[0].distance - 4.04).abs() < 1e-4);
+}
+
+#[test]
+fn test_dyn_index_dimension_mismatch() {
+    let vectors = vec![
+        DynVector::new(vec![1.0, 2.0, 3.0]),
+        DynVector::new(vec![3.0, 4.0]),
+    ];
+
+    let result = DynApproximateNearestNeighborsIndex::build(2, 2, &vectors, &[0, 1]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::DimensionMismatch {
+            expected: 3,
+            found: 2
+        })
+    );
+}
+
+#[test]
+fn test_dyn_index_query_dimension_mismatch() {
+    let vectors = vec![
+        DynVector::new(vec![1.0, 2.0, 3.0]),
+        DynVector::new(vec![3.0, 4.0, 5.0]),
+    ];
+    let index = DynApproximateNearestNeighborsIndex::build(2, 2, &vectors, &[0, 1]).unwrap();
+
+    let result = index.search(&DynVector::new(vec![1.0, 2.0]), 2);
+    assert_eq!(
+        result.err(),
+        Some(DimError {
+            expected: 3,
+            found: 2
+        })
+    );
+}
@@ -1,5 +1,29 @@
-use sieve::ann_index::ApproximateNearestNeighborsIndex;
-use sieve::vector::Vector;
+// the baseline tests read the top result with `get(0)`
+#![allow(clippy::get_first)]
+
+use sieve::ann_index::{
+    merge_results, ApproximateNearestNeighborsIndex, ApproximateNearestNeighborsSearchResult,
+    DedupReport,
+};
+use sieve::builder::IndexBuilder;
+use sieve::error::{BuildError, InsertError, MergeError, ValidationError};
+use sieve::hyperplane::HyperPlane;
+use sieve::metric::DistanceMetric;
+use sieve::query::PreparedQuery;
+use sieve::search::{CandidateScoring, SearchParams};
+use sieve::split::SplitStrategy;
+use sieve::tree::TreeNode;
+use sieve::vector::{Vector, Vector64};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+// on wasm32 the tests run under wasm-bindgen-test, except those that need a filesystem or threads
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test as test;
 
 #[test]
 fn test_simple_index() {
@@ -48,3 +72,2056 @@ fn test_top_k_index() {
         "Top search result should be the first vector"
     );
 }
+
+#[test]
+fn test_metric_index() {
+    let vectors = vec![
+        Vector::new([1.0, 0.0]),
+        Vector::new([10.0, 1.0]),
+        Vector::new([0.0, 1.0]),
+    ];
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+
+    let index = ApproximateNearestNeighborsIndex::build_with_metric(
+        2,
+        4,
+        &vectors,
+        &ids,
+        DistanceMetric::Cosine,
+    );
+    assert_eq!(index.metric(), DistanceMetric::Cosine);
+
+    let query = Vector::new([5.0, 0.5]);
+    let results = index.search(query, 3);
+
+    let top_1 = results.first().unwrap();
+    assert!(
+        top_1.vector_id == 1,
+        "Top cosine search result should be the most aligned vector"
+    );
+    assert!(
+        top_1.distance < 0.001,
+        "Distance should be reported using the cosine metric"
+    );
+}
+
+#[test]
+fn test_seeded_index() {
+    let vectors: Vec<Vector<4>> = (0..500).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+
+    let index_a = ApproximateNearestNeighborsIndex::build_with_seed(8, 10, &vectors, &ids, 42);
+    let index_b = ApproximateNearestNeighborsIndex::build_with_seed(8, 10, &vectors, &ids, 42);
+
+    for query in vectors.iter().take(50) {
+        let results_a = index_a.search(*query, 10);
+        let results_b = index_b.search(*query, 10);
+        assert_eq!(results_a.len(), results_b.len());
+        for (a, b) in results_a.iter().zip(results_b.iter()) {
+            assert_eq!(
+                a.vector_id, b.vector_id,
+                "Indexes built with the same seed should return the same results"
+            );
+            assert_eq!(a.distance, b.distance);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_save_and_load_index() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32 * 10).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 8, &vectors, &ids);
+
+    let path = std::env::temp_dir().join(format!("sieve_save_{}.idx", std::process::id()));
+    index.save(&path).unwrap();
+    let loaded = ApproximateNearestNeighborsIndex::<4>::load(&path).unwrap();
+
+    for query in vectors.iter().take(20) {
+        let expected = index.search(*query, 5);
+        let actual = loaded.search(*query, 5);
+        assert_eq!(expected.len(), actual.len());
+        for (a, b) in expected.iter().zip(actual.iter()) {
+            assert_eq!(
+                a.vector_id, b.vector_id,
+                "Loaded index should return the same results"
+            );
+            assert_eq!(a.distance, b.distance);
+        }
+    }
+
+    let mismatched = ApproximateNearestNeighborsIndex::<3>::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    let error = mismatched.expect_err("Loading a mismatched dimension should fail");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_negative_max_size_is_treated_as_one() {
+    let vectors: Vec<Vector<3>> = (0..100).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..100).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(2, -1, &vectors, &ids);
+    assert_eq!(index.max_leaf_size(), 1);
+    for stats in index.tree_stats() {
+        assert!(
+            stats.leaf_count > 1,
+            "every vector ended up in a single leaf"
+        );
+    }
+
+    index.rebuild_trees(2, 16);
+    index.rebuild_trees(3, -1);
+    assert_eq!(index.max_leaf_size(), 1);
+    assert_eq!(index.tree_stats().len(), 3);
+    for stats in index.tree_stats() {
+        assert!(
+            stats.leaf_count > 1,
+            "every vector ended up in a single leaf"
+        );
+    }
+
+    // inserted vectors still split the leaves they land in
+    let before: usize = index.tree_stats().iter().map(|s| s.leaf_count).sum();
+    index.insert(Vector::new([0.25, 0.5, 0.75]), 100).unwrap();
+    let after: usize = index.tree_stats().iter().map(|s| s.leaf_count).sum();
+    assert!(after > before);
+}
+
+#[test]
+fn test_top_k_bounds() {
+    let vectors: Vec<Vector<3>> = (0..10).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..10).collect();
+    let index = ApproximateNearestNeighborsIndex::build(2, 4, &vectors, &ids);
+    let query = Vector::random(None, None);
+
+    assert!(index.search(query, 0).is_empty());
+    assert!(index.search(query, -5).is_empty());
+    assert!(index.exact_search(query, -1).is_empty());
+    assert_eq!(index.exact_search(query, 1000).len(), 10);
+    assert!(index.search(query, 1000).len() <= 10);
+}
+
+#[test]
+fn test_try_build_errors() {
+    let vectors = vec![Vector::new([1.0, 2.0]), Vector::new([3.0, 4.0])];
+
+    let result = ApproximateNearestNeighborsIndex::try_build(2, 2, &vectors, &[0]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::LengthMismatch { vectors: 2, ids: 1 })
+    );
+
+    let result = ApproximateNearestNeighborsIndex::<2>::try_build(2, 2, &[], &[]);
+    assert_eq!(result.err(), Some(BuildError::Empty));
+
+    let result = IndexBuilder::new()
+        .max_leaf_size(0)
+        .build(&vectors, &[0, 1]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::InvalidLeafSize { max_size: 0 })
+    );
+    let result = ApproximateNearestNeighborsIndex::try_build(2, -1, &vectors, &[0, 1]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::InvalidLeafSize { max_size: -1 })
+    );
+
+    let index = ApproximateNearestNeighborsIndex::try_build(2, 2, &vectors, &[0, 1]).unwrap();
+    assert_eq!(index.search(Vector::new([1.0, 2.0]), 1)[0].vector_id, 0);
+}
+
+#[test]
+fn test_insert_index() {
+    let vectors: Vec<Vector<3>> = (0..50).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+
+    for i in 0..50 {
+        let vector = Vector::random(Some(5.0), Some(6.0));
+        index.insert(vector, 100 + i).unwrap();
+    }
+
+    let query = Vector::new([10.0, 10.0, 10.0]);
+    index.insert(query, 1000).unwrap();
+    let top_1 = index.search(query, 5);
+    assert_eq!(
+        top_1[0].vector_id, 1000,
+        "Inserted vector should be found by search"
+    );
+    assert_eq!(top_1[0].distance, 0.0);
+
+    assert_eq!(
+        index.insert(query, 1001),
+        Err(InsertError::DuplicateVector { id: 1001 })
+    );
+    assert_eq!(index.all_vectors().len(), 101);
+}
+
+#[test]
+fn test_extend_index() {
+    let vectors: Vec<Vector<3>> = (0..50).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+
+    let mut batch: Vec<Vector<3>> = (0..30)
+        .map(|_| Vector::random(Some(5.0), Some(6.0)))
+        .collect();
+    // one vector already in the index, and one repeated within the batch
+    batch.push(vectors[0]);
+    batch.push(batch[0]);
+    let batch_ids: Vec<i32> = (100..100 + batch.len() as i32).collect();
+    assert_eq!(index.extend(&batch, &batch_ids), Ok(30));
+    assert_eq!(index.len(), 80);
+
+    for (vector, &id) in batch.iter().zip(&batch_ids).take(30) {
+        assert_eq!(
+            index.get(id).map(|v| v.into_array()),
+            Some(vector.into_array())
+        );
+        let results = index.search(*vector, 5);
+        assert_eq!(
+            results[0].vector_id, id,
+            "Extended vectors should be searchable"
+        );
+    }
+    for (vector, &id) in vectors.iter().zip(&ids) {
+        assert_eq!(
+            index.get(id).map(|v| v.into_array()),
+            Some(vector.into_array())
+        );
+    }
+
+    assert_eq!(
+        index.extend(&batch, &batch_ids[1..]),
+        Err(InsertError::LengthMismatch {
+            vectors: 32,
+            ids: 31
+        })
+    );
+}
+
+#[test]
+fn test_remove_index() {
+    let vectors: Vec<Vector<3>> = (0..100).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+
+    for id in (0..100).step_by(2) {
+        assert!(index.remove(id), "Indexed id should be removed");
+    }
+    assert!(!index.remove(0), "Removed id should not be found again");
+    assert_eq!(index.all_vectors().len(), 50);
+
+    for (i, query) in vectors.iter().enumerate() {
+        let results = index.search(*query, 10);
+        assert!(
+            results.iter().all(|r| r.vector_id % 2 == 1),
+            "Search should never return a removed id"
+        );
+        if i % 2 == 1 {
+            assert_eq!(results[0].vector_id, i as i32);
+        }
+    }
+}
+
+#[test]
+fn test_remove_repeated_id() {
+    let vectors: Vec<Vector<3>> = (0..20).map(|_| Vector::random(None, None)).collect();
+    let mut ids: Vec<i32> = (0..20).collect();
+    ids[15] = 3;
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+    assert!(index.remove(3));
+    assert_eq!(
+        index.len(),
+        18,
+        "every vector with the id should be removed"
+    );
+    assert!(index.iter().all(|(id, _)| id != 3));
+
+    // an id added as an alias before it is inserted for another vector
+    let mut index = IndexBuilder::new()
+        .keep_duplicates(true)
+        .build(&vectors, &(0..20).collect::<Vec<_>>())
+        .unwrap();
+    index.insert(vectors[0], 100).unwrap();
+    index.insert(Vector::new([9.0, 9.0, 9.0]), 100).unwrap();
+    assert_eq!(index.len(), 21);
+    assert!(index.remove(100));
+    assert_eq!(index.len(), 20);
+    assert!(!index.contains_id(100));
+    assert_eq!(index.search(vectors[0], 1)[0].vector_id, 0);
+}
+
+#[test]
+fn test_search_batch() {
+    let vectors: Vec<Vector<3>> = (0..100).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+
+    let results = index.search_batch(&vectors, 5);
+    assert_eq!(results.len(), vectors.len());
+    for (i, query) in vectors.iter().enumerate() {
+        let expected = index.search(*query, 5);
+        assert_eq!(
+            results[i][0].vector_id, i as i32,
+            "Batch results should be in query order"
+        );
+        assert_eq!(results[i].len(), expected.len());
+    }
+}
+
+#[test]
+fn test_exact_search() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(2, 8, &vectors, &ids);
+
+    let query = Vector::random(None, None);
+    let results = index.exact_search(query, 10);
+    assert_eq!(results.len(), 10);
+
+    let mut distances: Vec<f32> = vectors
+        .iter()
+        .map(|v| v.squared_euclidian_distance(&query))
+        .collect();
+    distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    for (result, expected) in results.iter().zip(distances.iter()) {
+        assert_eq!(
+            result.distance, *expected,
+            "Exact search should return the true nearest neighbors"
+        );
+    }
+}
+
+#[test]
+fn test_zero_trees_search_exactly() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build(0, 8, &vectors, &ids);
+    assert_eq!(index.num_trees(), 0);
+
+    let ids_of = |results: Vec<ApproximateNearestNeighborsSearchResult<4>>| {
+        results.iter().map(|r| r.vector_id).collect::<Vec<_>>()
+    };
+    for query in vectors.iter().take(10) {
+        let exact = ids_of(index.exact_search(*query, 10));
+        assert_eq!(ids_of(index.search(*query, 10)), exact);
+        assert_eq!(ids_of(index.search_sequential(*query, 10)), exact);
+        assert_eq!(ids_of(index.search_with_stats(*query, 10).0), exact);
+        let params = SearchParams::new(10).scoring(CandidateScoring::Blend { vote_weight: 1.0 });
+        assert_eq!(ids_of(index.search_with(*query, &params)), exact);
+    }
+    assert_eq!(index.recall_at_k(&vectors[..10], 10), 1.0);
+}
+
+#[test]
+fn test_recall_at_k() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([5.0, 6.0]),
+    ];
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(2, 4, &vectors, &ids);
+
+    let recall = index.recall_at_k(&vectors, 10);
+    assert_eq!(
+        recall, 1.0,
+        "Recall should be normalized against the index size when k is larger"
+    );
+
+    let vectors: Vec<Vector<8>> = (0..500).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 16, &vectors, &ids);
+    let recall = index.recall_at_k(&vectors[..20], 10);
+    assert!((0.0..=1.0).contains(&recall));
+}
+
+#[test]
+fn test_search_with_params() {
+    let vectors: Vec<Vector<8>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(2, 16, &vectors, &ids, 7);
+
+    let (mut found, mut found_wide) = (0, 0);
+    for query in vectors.iter().take(50) {
+        let exact: Vec<i32> = index
+            .exact_search(*query, 10)
+            .iter()
+            .map(|r| r.vector_id)
+            .collect();
+        let count = |results: Vec<ApproximateNearestNeighborsSearchResult<8>>| {
+            results
+                .iter()
+                .filter(|r| exact.contains(&r.vector_id))
+                .count()
+        };
+        found += count(index.search(*query, 10));
+        found_wide += count(index.search_with_params(*query, 10, 100));
+    }
+    assert!(
+        found_wide > found,
+        "Gathering more candidates should improve recall"
+    );
+}
+
+#[test]
+fn test_f64_index() {
+    let vectors: Vec<Vector<2, f64>> = vec![Vector::new([1.0, 2.0]), Vector::new([3.0, 4.0])];
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+
+    let index = ApproximateNearestNeighborsIndex::build(2, 2, &vectors, &ids);
+    let results = index.search(Vector::new([3.0, 4.5]), 2);
+
+    let top_1: &ApproximateNearestNeighborsSearchResult<2, f64> = results.first().unwrap();
+    assert_eq!(top_1.vector_id, 1);
+    assert_eq!(top_1.distance, 0.25);
+}
+
+#[test]
+fn test_f64_precision() {
+    // offsets of 1e-7 around 1 are below the spacing of `f32` values at that magnitude
+    let values: Vec<f64> = (0..10).map(|i| 1.0 + i as f64 * 1e-7).collect();
+    let ids: Vec<i32> = (0..10).collect();
+
+    let vectors: Vec<Vector64<2>> = values.iter().map(|&v| Vector::new([v, 0.0])).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 2, &vectors, &ids);
+    assert_eq!(index.len(), 10);
+    let query = Vector::new([1.0 + 7.1e-7, 0.0]);
+    let exact = index.exact_search(query, 1);
+    assert_eq!(exact[0].vector_id, 7);
+    assert!((exact[0].distance - 1e-16).abs() < 1e-20);
+    let results = index.search(query, 10);
+    assert_eq!(results.len(), 10, "every vector should be reachable");
+    assert_eq!(results[0].vector_id, 7);
+
+    let vectors: Vec<Vector<2>> = values
+        .iter()
+        .map(|&v| Vector::new([v as f32, 0.0]))
+        .collect();
+    let index = ApproximateNearestNeighborsIndex::build(2, 2, &vectors, &ids);
+    assert!(index.len() < 10, "f32 should not tell every vector apart");
+}
+
+#[test]
+fn test_iter_index() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([1.0, 2.0]),
+    ];
+    let ids = vec![10, 20, 30];
+    let index = ApproximateNearestNeighborsIndex::build(2, 2, &vectors, &ids);
+
+    assert_eq!(index.len(), 2, "Duplicate vectors should not be counted");
+    assert!(!index.is_empty());
+
+    let contents: Vec<(i32, f32)> = index
+        .iter()
+        .map(|(id, v)| (id, v.squared_euclidian_distance(&vectors[0])))
+        .collect();
+    assert_eq!(contents, vec![(10, 0.0), (20, 8.0)]);
+}
+
+#[test]
+fn test_get_by_id() {
+    let vectors: Vec<Vector<3>> = (0..20).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32 * 3).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(2, 4, &vectors, &ids);
+
+    let vector = index.get(9).expect("Indexed id should be found");
+    assert_eq!(vector.squared_euclidian_distance(&vectors[3]), 0.0);
+    assert!(index.get(10).is_none(), "Unknown id should not be found");
+
+    index.remove(0);
+    let vector = index.get(57).expect("Moved vector should still be found");
+    assert_eq!(vector.squared_euclidian_distance(&vectors[19]), 0.0);
+    assert!(index.get(0).is_none(), "Removed id should not be found");
+}
+
+#[test]
+fn test_search_radius() {
+    let vectors: Vec<Vector<2>> = (0..100)
+        .map(|i| Vector::new([(i % 10) as f32, (i / 10) as f32]))
+        .collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 8, &vectors, &ids);
+
+    let results = index.search_radius(Vector::new([5.0, 5.0]), 1.0);
+    assert!(!results.is_empty());
+    assert_eq!(results[0].vector_id, 55, "Closest result should come first");
+    assert!(results.iter().all(|r| r.distance <= 1.0));
+    assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+    let results = index.search_radius(Vector::new([100.0, 100.0]), 1.0);
+    assert!(results.is_empty(), "Nothing should be within the radius");
+}
+
+#[test]
+fn test_index_parameters() {
+    let vectors: Vec<Vector<5>> = (0..50).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+    let index = ApproximateNearestNeighborsIndex::build(3, 7, &vectors, &ids);
+
+    assert_eq!(index.num_trees(), 3);
+    assert_eq!(index.max_leaf_size(), 7);
+    assert_eq!(index.dimension(), 5);
+}
+
+#[test]
+fn test_index_builder() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32).collect();
+
+    let index = IndexBuilder::new().build(&vectors, &ids).unwrap();
+    assert_eq!(index.num_trees(), 10);
+    assert_eq!(index.max_leaf_size(), 16);
+    assert_eq!(index.metric(), DistanceMetric::SquaredEuclidean);
+
+    let builder = IndexBuilder::new()
+        .num_trees(4)
+        .max_leaf_size(8)
+        .seed(3)
+        .metric(DistanceMetric::Manhattan);
+    let index_a = builder.build(&vectors, &ids).unwrap();
+    let index_b = builder.build(&vectors, &ids).unwrap();
+    assert_eq!(index_a.num_trees(), 4);
+    assert_eq!(index_a.max_leaf_size(), 8);
+    assert_eq!(index_a.metric(), DistanceMetric::Manhattan);
+
+    let query = Vector::random(None, None);
+    let ids_a: Vec<i32> = index_a
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let ids_b: Vec<i32> = index_b
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    assert_eq!(
+        ids_a, ids_b,
+        "Seeded builders should build identical indexes"
+    );
+
+    assert_eq!(
+        builder.build(&vectors, &ids[1..]).err(),
+        Some(BuildError::LengthMismatch {
+            vectors: 200,
+            ids: 199
+        })
+    );
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_thread_pool_index() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap(),
+    );
+
+    let builder = IndexBuilder::new().num_trees(4).max_leaf_size(8).seed(11);
+    let pooled = builder
+        .clone()
+        .thread_pool(pool.clone())
+        .build(&vectors, &ids)
+        .unwrap();
+    let mut global = builder.build(&vectors, &ids).unwrap();
+
+    let query = Vector::random(None, None);
+    let pooled_ids: Vec<i32> = pooled
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let global_ids: Vec<i32> = global
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    assert_eq!(
+        pooled_ids, global_ids,
+        "Building inside a pool should not change the index"
+    );
+
+    global.set_thread_pool(Some(pool));
+    let batch = global.search_batch(&[query], 5);
+    let batch_ids: Vec<i32> = batch[0].iter().map(|r| r.vector_id).collect();
+    assert_eq!(batch_ids, global_ids);
+}
+
+#[test]
+fn test_kmeans_split_recall() {
+    // tight clusters scattered through the space, with queries drawn from the same clusters
+    let mut rng = StdRng::seed_from_u64(7);
+    let centers: Vec<[f32; 16]> = (0..20)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(-10.0..10.0)))
+        .collect();
+    let mut clustered = |count: usize| -> Vec<Vector<16>> {
+        (0..count)
+            .map(|i| {
+                let center = centers[i % centers.len()];
+                Vector::new(std::array::from_fn(|d| {
+                    center[d] + rng.gen_range(-0.5..0.5)
+                }))
+            })
+            .collect()
+    };
+    let vectors = clustered(2000);
+    let queries = clustered(100);
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+
+    let builder = IndexBuilder::new().num_trees(2).max_leaf_size(16).seed(1);
+    let random = builder.build(&vectors, &ids).unwrap();
+    let kmeans = builder
+        .clone()
+        .split_strategy(SplitStrategy::KMeans2)
+        .build(&vectors, &ids)
+        .unwrap();
+    assert_eq!(random.split_strategy(), SplitStrategy::RandomProjection);
+    assert_eq!(kmeans.split_strategy(), SplitStrategy::KMeans2);
+
+    let random_recall = random.recall_at_k(&queries, 10);
+    let kmeans_recall = kmeans.recall_at_k(&queries, 10);
+    assert!(
+        kmeans_recall >= random_recall,
+        "KMeans2 recall {} should not be below RandomProjection recall {}",
+        kmeans_recall,
+        random_recall
+    );
+}
+
+#[test]
+fn test_angular_recall() {
+    // embeddings whose magnitudes vary widely, compared by direction only
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut embeddings = |count: usize| -> Vec<Vector<16>> {
+        (0..count)
+            .map(|_| {
+                let direction =
+                    Vector::<16>::new(std::array::from_fn(|_| rng.gen_range(-1.0..1.0)));
+                direction.normalized().scale(rng.gen_range(0.1..10.0))
+            })
+            .collect()
+    };
+    let vectors = embeddings(2000);
+    let queries = embeddings(50);
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+
+    let builder = IndexBuilder::new().num_trees(10).max_leaf_size(16).seed(1);
+    let euclidean = builder.build(&vectors, &ids).unwrap();
+    let angular = builder.clone().angular().build(&vectors, &ids).unwrap();
+    assert_eq!(angular.metric(), DistanceMetric::Angular);
+
+    let cosine_recall = |index: &ApproximateNearestNeighborsIndex<16>| {
+        let mut found = 0;
+        for query in &queries {
+            let mut truth: Vec<(f32, i32)> = vectors
+                .iter()
+                .zip(&ids)
+                .map(|(vector, &id)| (query.cosine_distance(vector), id))
+                .collect();
+            truth.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let truth: HashSet<i32> = truth[..10].iter().map(|&(_, id)| id).collect();
+            found += index
+                .search(*query, 10)
+                .iter()
+                .filter(|r| truth.contains(&r.vector_id))
+                .count();
+        }
+        found as f32 / (queries.len() * 10) as f32
+    };
+    let (euclidean_recall, angular_recall) = (cosine_recall(&euclidean), cosine_recall(&angular));
+    assert!(
+        angular_recall > euclidean_recall,
+        "Angular recall {} should beat Euclidean recall {} for cosine ground truth",
+        angular_recall,
+        euclidean_recall
+    );
+
+    let query = queries[0];
+    for result in angular.search(query, 5) {
+        let expected = (2.0 - 2.0 * query.cosine_similarity(&result.vector))
+            .max(0.0)
+            .sqrt();
+        assert!((result.distance - expected).abs() < 1e-3);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_split_sample_size() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let vectors: Vec<Vector<8>> = (0..1000)
+        .map(|_| Vector::new(std::array::from_fn(|_| rng.gen_range(-1.0..1.0))))
+        .collect();
+    let ids: Vec<i32> = (0..1000).collect();
+    let builder = IndexBuilder::new().num_trees(4).max_leaf_size(8).seed(3);
+    assert_eq!(
+        builder.build(&vectors, &ids).unwrap().split_sample_size(),
+        2
+    );
+    assert_eq!(
+        builder
+            .clone()
+            .split_sample_size(0)
+            .build(&vectors, &ids)
+            .unwrap()
+            .split_sample_size(),
+        2
+    );
+
+    let mut index = builder.split_sample_size(16).build(&vectors, &ids).unwrap();
+    assert_eq!(index.split_sample_size(), 16);
+    assert_eq!(index.validate(), Ok(()));
+    for stats in index.tree_stats() {
+        assert!(stats.max_leaf_size <= 8);
+    }
+    for (query, &id) in vectors.iter().zip(&ids).take(50) {
+        assert_eq!(index.search(*query, 1)[0].vector_id, id);
+    }
+
+    // leaves split on insert and the saved index keep the sample size
+    let extra: Vec<Vector<8>> = (0..100).map(|_| Vector::random(None, None)).collect();
+    index
+        .extend(&extra, &(1000..1100).collect::<Vec<_>>())
+        .unwrap();
+    assert_eq!(index.validate(), Ok(()));
+    let path = std::env::temp_dir().join(format!("sieve_sample_{}.idx", std::process::id()));
+    index.save(&path).unwrap();
+    let loaded = ApproximateNearestNeighborsIndex::<8>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.split_sample_size(), 16);
+}
+
+#[test]
+fn test_search_results_are_ordered_by_distance() {
+    let vectors: Vec<Vector<4>> = (0..400).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..400).collect();
+    let shards = [
+        ApproximateNearestNeighborsIndex::build(4, 8, &vectors[..200], &ids[..200]),
+        ApproximateNearestNeighborsIndex::build(4, 8, &vectors[200..], &ids[200..]),
+    ];
+    let query = Vector::random(None, None);
+
+    for shard in &shards {
+        let results = shard.search(query, 10);
+        assert!(results.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+    let mut merged: Vec<_> = shards.iter().flat_map(|s| s.search(query, 10)).collect();
+    merged.sort();
+    assert!(merged
+        .windows(2)
+        .all(|pair| pair[0].distance <= pair[1].distance));
+
+    let heap: BinaryHeap<_> = merged.iter().cloned().map(Reverse).collect();
+    assert_eq!(
+        heap.peek().map(|r| r.0.vector_id),
+        Some(merged[0].vector_id)
+    );
+
+    let result = |vector_id, distance| ApproximateNearestNeighborsSearchResult {
+        vector_id,
+        distance,
+        vector: Vector::new([0.0; 4]),
+    };
+    assert!(result(2, 1.0) < result(1, 2.0));
+    assert!(result(1, 1.0) < result(2, 1.0));
+    assert!(result(1, 5.0) < result(0, f32::NAN));
+    assert_eq!(result(1, 1.0), result(1, 1.0));
+}
+
+#[test]
+fn test_merge_results() {
+    let vectors: Vec<Vector<4>> = (0..600).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..600).collect();
+    let full = ApproximateNearestNeighborsIndex::build(4, 8, &vectors, &ids);
+    // the shards overlap, so some ids are returned by two of them
+    let shards = [
+        ApproximateNearestNeighborsIndex::build(4, 8, &vectors[..250], &ids[..250]),
+        ApproximateNearestNeighborsIndex::build(4, 8, &vectors[200..450], &ids[200..450]),
+        ApproximateNearestNeighborsIndex::build(4, 8, &vectors[400..], &ids[400..]),
+    ];
+
+    for query in vectors.iter().take(20) {
+        let per_shard = shards.iter().map(|s| s.exact_search(*query, 10)).collect();
+        let merged = merge_results(per_shard, 10);
+        let expected = full.exact_search(*query, 10);
+        assert_eq!(
+            merged.iter().map(|r| r.vector_id).collect::<Vec<_>>(),
+            expected.iter().map(|r| r.vector_id).collect::<Vec<_>>()
+        );
+    }
+
+    let per_shard = shards.iter().map(|s| s.search(vectors[210], 3)).collect();
+    let merged = merge_results(per_shard, 100);
+    let unique: HashSet<i32> = merged.iter().map(|r| r.vector_id).collect();
+    assert_eq!(unique.len(), merged.len(), "Ids should be kept once");
+    assert!(merged.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(merge_results::<4, f32>(vec![], 5).is_empty());
+    assert!(merge_results(vec![shards[0].search(vectors[0], 3)], 0).is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_search_result() {
+    let vectors = vec![Vector::new([1.0, 2.0]), Vector::new([3.0, 4.0])];
+    let index = ApproximateNearestNeighborsIndex::build(2, 2, &vectors, &[7, 8]);
+
+    let results = index.search(Vector::new([1.0, 2.0]), 2);
+    let json = serde_json::to_string(&results[0]).unwrap();
+    assert_eq!(json, r#"{"vector_id":7,"distance":0.0,"vector":[1.0,2.0]}"#);
+
+    let parsed: ApproximateNearestNeighborsSearchResult<2> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.vector_id, 7);
+    assert_eq!(parsed.vector.as_slice(), &[1.0, 2.0]);
+}
+
+#[test]
+fn test_search_with_scoring() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut random_vectors = |count: usize| -> Vec<Vector<8>> {
+        (0..count)
+            .map(|_| Vector::new(std::array::from_fn(|_| rng.gen_range(-1.0..1.0))))
+            .collect()
+    };
+    let vectors = random_vectors(1000);
+    let queries = random_vectors(50);
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+    let index = IndexBuilder::new()
+        .num_trees(8)
+        .seed(3)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    let recall = |params: SearchParams| -> f32 {
+        let found: usize = queries
+            .iter()
+            .map(|query| {
+                let exact: Vec<i32> = index
+                    .exact_search(*query, 10)
+                    .iter()
+                    .map(|r| r.vector_id)
+                    .collect();
+                index
+                    .search_with(*query, &params)
+                    .iter()
+                    .filter(|r| exact.contains(&r.vector_id))
+                    .count()
+            })
+            .sum();
+        found as f32 / (queries.len() * 10) as f32
+    };
+    let params = SearchParams::new(10).search_k(20);
+    let distance_recall = recall(params.scoring(CandidateScoring::Distance));
+    let tiebreak_recall = recall(params.scoring(CandidateScoring::VoteTiebreak));
+    let blend_recall = recall(params.scoring(CandidateScoring::Blend { vote_weight: 0.5 }));
+    let probed_recall = recall(params.probes(3));
+
+    // votes only reorder candidates with equal distances
+    assert_eq!(distance_recall, tiebreak_recall);
+    // letting votes outweigh distance trades recall for agreement between the trees
+    assert!(blend_recall <= distance_recall);
+    // probing alternate branches only adds candidates
+    assert!(probed_recall >= distance_recall);
+
+    let params = SearchParams::new(10).scoring(CandidateScoring::Blend { vote_weight: 0.5 });
+    for result in index.search_with(queries[0], &params) {
+        let expected = DistanceMetric::SquaredEuclidean.distance(&result.vector, &queries[0]);
+        assert_eq!(result.distance, expected);
+    }
+}
+
+#[test]
+fn test_sequential_search_with() {
+    let vectors: Vec<Vector<8>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+    let index = IndexBuilder::new()
+        .num_trees(8)
+        .seed(9)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    // enough candidates from every tree that the parallel search visits the trees in parallel
+    let params = SearchParams::new(10).search_k(200).probes(2);
+    for params in [
+        params,
+        params.max_candidates(300),
+        params.scoring(CandidateScoring::VoteTiebreak),
+        params
+            .scoring(CandidateScoring::Blend { vote_weight: 0.5 })
+            .max_candidates(300),
+    ] {
+        for query in vectors.iter().take(10) {
+            let ids = |params: &SearchParams| -> Vec<i32> {
+                index
+                    .search_with(*query, params)
+                    .iter()
+                    .map(|r| r.vector_id)
+                    .collect()
+            };
+            // the trees are merged in the same order either way, so even capped searches agree
+            assert_eq!(ids(&params.sequential(true)), ids(&params));
+        }
+    }
+}
+
+#[test]
+fn test_search_budgeted() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut random_vectors = |count: usize| -> Vec<Vector<8>> {
+        (0..count)
+            .map(|_| Vector::new(std::array::from_fn(|_| rng.gen_range(-1.0..1.0))))
+            .collect()
+    };
+    let vectors = random_vectors(1000);
+    let queries = random_vectors(50);
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+    let index = IndexBuilder::new()
+        .num_trees(8)
+        .seed(5)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    let recall = |max_nodes: usize| -> f32 {
+        let found: usize = queries
+            .iter()
+            .map(|query| {
+                let exact: Vec<i32> = index
+                    .exact_search(*query, 10)
+                    .iter()
+                    .map(|r| r.vector_id)
+                    .collect();
+                index
+                    .search_budgeted(*query, 10, max_nodes)
+                    .iter()
+                    .filter(|r| exact.contains(&r.vector_id))
+                    .count()
+            })
+            .sum();
+        found as f32 / (queries.len() * 10) as f32
+    };
+    let small = recall(50);
+    let large = recall(400);
+    assert!(small < large, "A larger node budget should improve recall");
+    assert_eq!(
+        recall(usize::MAX),
+        1.0,
+        "An unlimited budget visits every leaf"
+    );
+    assert!(index.search_budgeted(queries[0], 10, 0).is_empty());
+}
+
+#[test]
+fn test_search_with_stats() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let index = IndexBuilder::new()
+        .num_trees(4)
+        .max_leaf_size(8)
+        .seed(9)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    let query = Vector::random(None, None);
+    let (results, stats) = index.search_with_stats(query, 5);
+    let expected: Vec<i32> = index.search(query, 5).iter().map(|r| r.vector_id).collect();
+    assert_eq!(
+        results.iter().map(|r| r.vector_id).collect::<Vec<_>>(),
+        expected
+    );
+
+    // every tree is descended to at least one leaf, and 200 vectors need branches above it
+    assert!(stats.leaves_visited >= 4);
+    assert!(stats.nodes_traversed > stats.leaves_visited);
+    assert!(stats.candidates >= results.len());
+    assert!(stats.candidates <= 4 * 5);
+    assert!(stats.min_margin.is_some_and(|margin| margin >= 0.0));
+
+    // an index whose trees are single leaves has no splits to measure a margin to
+    let leaves = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[..4], &ids[..4]);
+    assert_eq!(leaves.search_with_stats(query, 2).1.min_margin, None);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_open_mmap_index() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..vectors.len()).map(|i| i as i32 * 10).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 8, &vectors, &ids);
+
+    let path = std::env::temp_dir().join(format!("sieve_mmap_{}.idx", std::process::id()));
+    index.save(&path).unwrap();
+    let mapped = ApproximateNearestNeighborsIndex::<4>::open_mmap(&path).unwrap();
+    assert_eq!(mapped.len(), 200);
+    assert_eq!(mapped.num_trees(), 4);
+    assert_eq!(mapped.metric(), index.metric());
+
+    for query in vectors.iter().take(20) {
+        let expected = index.search(*query, 5);
+        let actual = mapped.search(*query, 5);
+        assert_eq!(expected.len(), actual.len());
+        for (a, b) in expected.iter().zip(actual.iter()) {
+            assert_eq!(
+                a.vector_id, b.vector_id,
+                "Mapped index should return the same results"
+            );
+            assert_eq!(a.distance, b.distance);
+        }
+    }
+
+    // a truncated file is rejected when it is opened rather than when it is searched
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+    let truncated = ApproximateNearestNeighborsIndex::<4>::open_mmap(&path);
+    std::fs::remove_file(&path).unwrap();
+    let error = truncated
+        .err()
+        .expect("Opening a truncated index should fail");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_explain_search() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(2, 8, &vectors, &ids, 4);
+
+    let query = Vector::random(None, None);
+    let explanation = index.explain_search(query, 10);
+    assert_eq!(explanation.exact.len(), 10);
+    let found: Vec<i32> = explanation
+        .approximate
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    for result in explanation.exact.iter() {
+        assert_eq!(
+            found.contains(&result.vector_id),
+            !explanation.missed.contains(&result.vector_id)
+        );
+    }
+    let expected_recall = (10 - explanation.missed.len()) as f32 / 10.0;
+    assert_eq!(explanation.recall, expected_recall);
+    assert_eq!(index.recall_at_k(&[query], 10), expected_recall);
+}
+
+#[test]
+fn test_search_with_nan_distance() {
+    let mut vectors: Vec<Vector<2>> = (0..20).map(|i| Vector::new([i as f32, 0.0])).collect();
+    vectors.push(Vector::new([f32::NAN, 0.0]));
+    let ids: Vec<i32> = (0..21).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(2, 4, &vectors, &ids, 5);
+
+    let query = Vector::new([0.0, 0.0]);
+    let results = index.exact_search(query, 21);
+    assert_eq!(results.len(), 21);
+    assert_eq!(results[0].vector_id, 0);
+    assert_eq!(results[20].vector_id, 20);
+    assert!(results[20].distance.is_nan());
+
+    let results = index.search(query, 21);
+    let first_nan = results
+        .iter()
+        .position(|r| r.distance.is_nan())
+        .unwrap_or(results.len());
+    assert!(results[first_nan..].iter().all(|r| r.distance.is_nan()));
+}
+
+#[test]
+fn test_tree_stats() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(3, 10, &vectors, &ids, 6);
+
+    let stats = index.tree_stats();
+    assert_eq!(stats.len(), 3);
+    for tree in stats {
+        assert!(tree.max_leaf_size <= 10);
+        assert!(tree.min_leaf_size <= tree.max_leaf_size);
+        assert!(tree.leaf_count >= 20);
+        assert!(1 << tree.max_depth >= tree.leaf_count);
+        let total = tree.avg_leaf_size * tree.leaf_count as f32;
+        assert!((total - 200.0).abs() < 1e-3);
+    }
+
+    let single = ApproximateNearestNeighborsIndex::build(1, 10, &vectors[..5], &ids[..5]);
+    let stats = single.tree_stats();
+    assert_eq!(stats[0].max_depth, 0);
+    assert_eq!(stats[0].leaf_count, 1);
+    assert_eq!(stats[0].avg_leaf_size, 5.0);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_max_depth_index() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = IndexBuilder::new()
+        .num_trees(3)
+        .max_leaf_size(1)
+        .max_depth(3)
+        .seed(7)
+        .build(&vectors, &ids)
+        .unwrap();
+    assert_eq!(index.max_depth(), Some(3));
+    for tree in index.tree_stats() {
+        assert_eq!(tree.max_depth, 3);
+        assert!(tree.leaf_count <= 8);
+    }
+    assert_eq!(index.search(vectors[0], 1)[0].vector_id, 0);
+
+    let path = std::env::temp_dir().join(format!("sieve_depth_{}.idx", std::process::id()));
+    index.save(&path).unwrap();
+    let loaded = ApproximateNearestNeighborsIndex::<4>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.max_depth(), Some(3));
+
+    let unlimited = ApproximateNearestNeighborsIndex::build(1, 1, &vectors, &ids);
+    assert_eq!(unlimited.max_depth(), None);
+    assert!(unlimited.tree_stats()[0].max_depth > 3);
+}
+
+#[test]
+fn test_merge_indexes() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let first =
+        ApproximateNearestNeighborsIndex::build_with_seed(2, 8, &vectors[..100], &ids[..100], 1);
+    let second =
+        ApproximateNearestNeighborsIndex::build_with_seed(3, 8, &vectors[100..], &ids[100..], 2);
+
+    let mut merged = first.merge(second).unwrap();
+    assert_eq!(merged.len(), 200);
+    assert_eq!(merged.num_trees(), 5);
+    for (vector, &id) in vectors.iter().zip(&ids).step_by(10) {
+        let results = merged.search_with_params(*vector, 1, 8);
+        assert_eq!(results[0].vector_id, id);
+    }
+    merged.insert(Vector::random(None, None), 200).unwrap();
+    assert_eq!(merged.len(), 201);
+
+    let first = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[..100], &ids[..100]);
+    let overlapping = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[100..], &ids[..100]);
+    assert_eq!(
+        first.merge(overlapping).err(),
+        Some(MergeError::IdCollision { id: 0 })
+    );
+
+    let first = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[..100], &ids[..100]);
+    let cosine = IndexBuilder::new()
+        .metric(DistanceMetric::Cosine)
+        .build(&vectors[100..], &ids[100..])
+        .unwrap();
+    assert!(matches!(
+        first.merge(cosine),
+        Err(MergeError::MetricMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_merge_duplicate_vectors() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    // the second shard holds vectors 90..100 again under the ids 1090..1100
+    let second_ids: Vec<i32> = (90..200)
+        .map(|i| if i < 100 { i + 1000 } else { i })
+        .collect();
+
+    let first = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[..100], &ids[..100]);
+    let second = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[90..], &second_ids);
+    assert_eq!(
+        first.merge(second).err(),
+        Some(MergeError::DuplicateVector { id: 1090 })
+    );
+
+    let builder = IndexBuilder::new().num_trees(2).keep_duplicates(true);
+    let first = builder.build(&vectors[..100], &ids[..100]).unwrap();
+    let second = builder.build(&vectors[90..], &second_ids).unwrap();
+    let mut merged = first.merge(second).unwrap();
+    assert_eq!(merged.len(), 200);
+    for i in 90..100 {
+        assert_eq!(
+            merged.get(i + 1000).unwrap().as_slice(),
+            vectors[i as usize].as_slice()
+        );
+        let results = merged.exact_search(vectors[i as usize], 2);
+        let found: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+        assert_eq!(found, vec![i, i + 1000]);
+    }
+    for (vector, &id) in vectors.iter().zip(&ids) {
+        assert_eq!(merged.get(id).unwrap().as_slice(), vector.as_slice());
+        // the trees of the second shard find its vectors at their merged positions
+        assert_eq!(merged.search_with_params(*vector, 1, 200)[0].vector_id, id);
+    }
+
+    // inserting the shared vector again finds its single copy
+    merged.insert(vectors[95], 5000).unwrap();
+    assert_eq!(merged.len(), 200);
+    assert_eq!(merged.get(5000).unwrap().as_slice(), vectors[95].as_slice());
+}
+
+#[test]
+fn test_search_by_id() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).map(|i| i * 2).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(4, 8, &vectors, &ids, 3);
+
+    for &id in ids.iter().step_by(7) {
+        let results = index.search_by_id(id, 5).unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(|r| r.vector_id != id));
+        assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+    }
+
+    let all = index.search_by_id(0, 1000).unwrap();
+    assert!(all.len() <= 199);
+    assert!(all.iter().all(|r| r.vector_id != 0));
+    assert!(index.search_by_id(1, 5).is_none());
+    assert_eq!(index.search_by_id(0, 0).map(|r| r.len()), Some(0));
+}
+
+#[test]
+fn test_search_filtered() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(3, 8, &vectors, &ids, 8);
+
+    let query = vectors[0];
+    let exclude: HashSet<i32> = index
+        .search(query, 20)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let results = index.search_filtered(query, 10, &exclude);
+    assert_eq!(results.len(), 10);
+    assert!(results.iter().all(|r| !exclude.contains(&r.vector_id)));
+    assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+    let everything_but_two: HashSet<i32> = (2..300).collect();
+    let results = index.search_filtered(query, 10, &everything_but_two);
+    let mut found: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+    found.sort();
+    assert_eq!(found, vec![0, 1]);
+
+    assert_eq!(index.search_filtered(query, 5, &HashSet::new()).len(), 5);
+}
+
+#[test]
+fn test_search_where() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(3, 8, &vectors, &ids, 9);
+
+    let query = vectors[0];
+    let even = index.search_where(query, 10, |id| id % 2 == 0);
+    assert_eq!(even.len(), 10);
+    assert!(even.iter().all(|r| r.vector_id % 2 == 0));
+    assert_eq!(even[0].vector_id, 0);
+
+    let rare = index.search_where(query, 10, |id| id % 100 == 7);
+    let mut found: Vec<i32> = rare.iter().map(|r| r.vector_id).collect();
+    found.sort();
+    assert_eq!(found, vec![7, 107, 207]);
+
+    assert!(index.search_where(query, 10, |_| false).is_empty());
+}
+
+#[test]
+fn test_dedup_epsilon() {
+    let a = Vector::new([0.5, 0.25]);
+    let b = Vector::new([0.5 + 1e-7, 0.25]);
+    let c = Vector::new([3.0, 4.0]);
+    assert_ne!(a.hashkey(), b.hashkey());
+    let vectors = vec![a, b, c];
+    let ids = vec![0, 1, 2];
+
+    let exact = IndexBuilder::new().build(&vectors, &ids).unwrap();
+    assert_eq!(exact.len(), 3);
+
+    let index = IndexBuilder::new()
+        .dedup_epsilon(1e-6)
+        .build(&vectors, &ids)
+        .unwrap();
+    assert_eq!(index.len(), 2);
+    assert!(index.get(0).is_some());
+    assert!(index.get(1).is_none());
+    assert!(index.get(2).is_some());
+}
+
+#[test]
+fn test_build_with_report() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([1.0, 2.0]),
+        Vector::new([5.0, 6.0]),
+    ];
+    let ids = vec![10, 11, 12, 13, 10, 15];
+    let (index, report) = ApproximateNearestNeighborsIndex::build_with_report(2, 2, &vectors, &ids);
+
+    assert_eq!(index.len(), 3);
+    assert_eq!(
+        report,
+        DedupReport {
+            dropped: vec![12, 13],
+            mapping: HashMap::from([(12, 10), (13, 11)]),
+        }
+    );
+
+    let vectors = vec![Vector::new([0.5, 0.25]), Vector::new([0.5 + 1e-7, 0.25])];
+    let (_, report) = IndexBuilder::new()
+        .dedup_epsilon(1e-6)
+        .build_with_report(&vectors, &[0, 1])
+        .unwrap();
+    assert_eq!(report.dropped, vec![1]);
+    assert_eq!(report.mapping[&1], 0);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_keep_duplicates() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+    ];
+    let ids = vec![1, 2, 3];
+    let (mut index, report) = IndexBuilder::new()
+        .keep_duplicates(true)
+        .build_with_report(&vectors, &ids)
+        .unwrap();
+    assert_eq!(index.len(), 2);
+    assert_eq!(report, DedupReport::default());
+
+    let result_ids = |index: &ApproximateNearestNeighborsIndex<2>| {
+        let results = index.exact_search(Vector::new([1.0, 2.0]), 10);
+        results.iter().map(|r| r.vector_id).collect::<Vec<_>>()
+    };
+    assert_eq!(result_ids(&index), vec![1, 2, 3]);
+    assert_eq!(index.search(Vector::new([1.0, 2.0]), 2).len(), 2);
+
+    index.insert(Vector::new([1.0, 2.0]), 4).unwrap();
+    assert_eq!(index.len(), 2);
+    assert_eq!(result_ids(&index), vec![1, 2, 4, 3]);
+    assert_eq!(index.get(4).map(|v| v.into_array()), Some([1.0, 2.0]));
+
+    let neighbors = index.search_by_id(2, 3).unwrap();
+    let neighbors: Vec<i32> = neighbors.iter().map(|r| r.vector_id).collect();
+    assert_eq!(neighbors, vec![1, 4, 3]);
+
+    let path = std::env::temp_dir().join("sieve_test_keep_duplicates.bin");
+    index.save(&path).unwrap();
+    let loaded = ApproximateNearestNeighborsIndex::<2>::load(&path).unwrap();
+    #[cfg(feature = "mmap")]
+    {
+        let mapped = ApproximateNearestNeighborsIndex::<2>::open_mmap(&path).unwrap();
+        let results = mapped.search(Vector::new([1.0, 2.0]), 10);
+        let mapped_ids: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+        assert_eq!(mapped_ids, vec![1, 2, 4, 3]);
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(result_ids(&loaded), vec![1, 2, 4, 3]);
+
+    assert!(index.remove(1));
+    assert_eq!(result_ids(&index), vec![2, 4, 3]);
+    assert!(index.get(1).is_none());
+    assert!(index.remove(2) && index.remove(4));
+    assert_eq!(index.len(), 1);
+    assert_eq!(result_ids(&index), vec![3]);
+}
+
+#[test]
+fn test_rebuild_trees() {
+    let vectors: Vec<Vector<3>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(2, 4, &vectors[..200], &ids[..200]);
+    index.extend(&vectors[200..], &ids[200..]).unwrap();
+    for id in 0..50 {
+        index.remove(id);
+    }
+
+    index.rebuild_trees(5, 16);
+    assert_eq!(index.num_trees(), 5);
+    assert_eq!(index.max_leaf_size(), 16);
+    assert_eq!(index.len(), 250);
+    for stats in index.tree_stats() {
+        assert!(stats.max_leaf_size <= 16);
+        assert_eq!(
+            (stats.avg_leaf_size * stats.leaf_count as f32).round() as usize,
+            250
+        );
+    }
+    for id in 50..300 {
+        let vector = index.get(id).unwrap();
+        let results = index.search_with_params(vector, 1, 16);
+        assert_eq!(results[0].vector_id, id);
+    }
+}
+
+#[test]
+fn test_clone_is_independent() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let index = ApproximateNearestNeighborsIndex::build(4, 8, &vectors, &ids);
+    let mut snapshot = index.clone();
+
+    for id in 0..50 {
+        snapshot.remove(id);
+    }
+    snapshot.insert(Vector::new([9.0, 9.0, 9.0]), 500).unwrap();
+    assert_eq!(snapshot.len(), 151);
+    assert_eq!(index.len(), 200);
+    assert!(index.get(500).is_none());
+
+    for id in 0..50 {
+        let results = index.search_with_params(vectors[id as usize], 1, 16);
+        assert_eq!(results[0].vector_id, id);
+        assert!(snapshot.get(id).is_none());
+    }
+}
+
+#[test]
+fn test_debug_summary() {
+    let vectors = vec![Vector::new([1.0, 2.0]), Vector::new([3.0, 4.0])];
+    let index = ApproximateNearestNeighborsIndex::build(3, 2, &vectors, &[0, 1]);
+    assert_eq!(
+        format!("{:?}", index),
+        "Index(dim=2, vectors=2, trees=3, metric=SquaredEuclidean, leaves=3, max_depth=0)"
+    );
+}
+
+#[test]
+fn test_search_with_trees() {
+    let vectors: Vec<Vector<8>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..1000).collect();
+    let index = ApproximateNearestNeighborsIndex::build(10, 8, &vectors, &ids);
+
+    for _ in 0..20 {
+        let query = Vector::random(None, None);
+        let all = index.search(query, 10);
+        let ids_of = |results: &[ApproximateNearestNeighborsSearchResult<8>]| {
+            results.iter().map(|r| r.vector_id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids_of(&index.search_with_trees(query, 10, 10)),
+            ids_of(&all)
+        );
+        assert_eq!(
+            ids_of(&index.search_with_trees(query, 10, 50)),
+            ids_of(&all)
+        );
+        assert!(index.search_with_trees(query, 10, 0).is_empty());
+
+        // the candidates of the first trees are a subset of the candidates of every tree
+        let fewer = index.search_with_trees(query, 10, 2);
+        for (all, fewer) in all.iter().zip(&fewer) {
+            assert!(all.distance <= fewer.distance);
+        }
+    }
+}
+
+#[test]
+fn test_from_parts() {
+    let vectors = vec![
+        Vector::new([-2.0, 1.0]),
+        Vector::new([-1.0, -1.0]),
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 0.0]),
+    ];
+    let ids = vec![10, 11, 12, 13];
+    // a single split on x = 0, with the vectors right of it above the hyperplane
+    let tree = || {
+        TreeNode::branch(
+            HyperPlane::new(Vector::new([1.0, 0.0]), 0.0),
+            TreeNode::leaf(vec![0, 1]),
+            TreeNode::leaf(vec![2, 3]),
+        )
+    };
+    let index = ApproximateNearestNeighborsIndex::from_parts(
+        vectors.clone(),
+        ids.clone(),
+        vec![tree(), tree()],
+    )
+    .unwrap();
+    assert_eq!(index.num_trees(), 2);
+    assert_eq!(index.tree_stats()[0].leaf_count, 2);
+    let results = index.search(Vector::new([2.0, 1.0]), 2);
+    assert_eq!(
+        results.iter().map(|r| r.vector_id).collect::<Vec<_>>(),
+        vec![12, 13]
+    );
+
+    let stranded = TreeNode::branch(
+        HyperPlane::new(Vector::new([1.0, 0.0]), 0.0),
+        TreeNode::leaf(vec![0, 1]),
+        TreeNode::leaf(vec![2, 4]),
+    );
+    let result =
+        ApproximateNearestNeighborsIndex::from_parts(vectors.clone(), ids, vec![tree(), stranded]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::LeafIndexOutOfRange {
+            tree: 1,
+            index: 4,
+            vectors: 4
+        })
+    );
+    let result = ApproximateNearestNeighborsIndex::from_parts(vectors, vec![10], vec![]);
+    assert_eq!(
+        result.err(),
+        Some(BuildError::LengthMismatch { vectors: 4, ids: 1 })
+    );
+}
+
+#[test]
+fn test_reachable_counts() {
+    let vectors: Vec<Vector<3>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(4, 8, &vectors[..200], &ids[..200]);
+    assert_eq!(index.reachable_counts(), vec![200; 4]);
+
+    index.extend(&vectors[200..], &ids[200..]).unwrap();
+    for id in 0..30 {
+        index.remove(id);
+    }
+    assert_eq!(index.reachable_counts(), vec![270; 4]);
+
+    // a tree that leaves a vector out of its leaves can never find it
+    let stranded = TreeNode::leaf(vec![0, 1, 1]);
+    let index = ApproximateNearestNeighborsIndex::from_parts(
+        vectors[..3].to_vec(),
+        vec![0, 1, 2],
+        vec![TreeNode::leaf(vec![0, 1, 2]), stranded],
+    )
+    .unwrap();
+    assert_eq!(index.reachable_counts(), vec![3, 2]);
+}
+
+#[test]
+fn test_validate() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let vectors: Vec<Vector<6>> = (0..500)
+        .map(|_| Vector::new(std::array::from_fn(|_| rng.gen_range(-1.0..1.0))))
+        .collect();
+    let ids: Vec<i32> = (0..500).collect();
+    for strategy in [SplitStrategy::RandomProjection, SplitStrategy::KMeans2] {
+        let mut index = IndexBuilder::new()
+            .num_trees(5)
+            .max_leaf_size(4)
+            .split_strategy(strategy)
+            .seed(2)
+            .build(&vectors[..400], &ids[..400])
+            .unwrap();
+        assert_eq!(index.validate(), Ok(()));
+
+        index.extend(&vectors[400..], &ids[400..]).unwrap();
+        for id in (0..500).step_by(7) {
+            index.remove(id);
+        }
+        assert_eq!(index.validate(), Ok(()));
+    }
+
+    let parts = |trees| {
+        ApproximateNearestNeighborsIndex::from_parts(vectors[..3].to_vec(), vec![0, 1, 2], trees)
+    };
+    let index = parts(vec![
+        TreeNode::leaf(vec![0, 1, 2]),
+        TreeNode::leaf(vec![2, 0]),
+    ])
+    .unwrap();
+    assert_eq!(
+        index.validate(),
+        Err(ValidationError::MissingIndex { tree: 1, index: 1 })
+    );
+    let index = parts(vec![TreeNode::leaf(vec![0, 1, 2, 1])]).unwrap();
+    assert_eq!(
+        index.validate(),
+        Err(ValidationError::DuplicateIndex { tree: 0, index: 1 })
+    );
+}
+
+#[test]
+fn test_leaves() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).map(|i| i * 3).collect();
+    let index = ApproximateNearestNeighborsIndex::build(3, 8, &vectors, &ids);
+
+    for tree in 0..3 {
+        let leaves = index.leaves(tree).unwrap();
+        assert_eq!(leaves.len(), index.tree_stats()[tree].leaf_count);
+        let mut held: Vec<i32> = leaves.into_iter().flatten().collect();
+        held.sort();
+        assert_eq!(held, ids);
+    }
+    assert!(index.leaves(3).is_none());
+
+    let parts = ApproximateNearestNeighborsIndex::from_parts(
+        vectors[..3].to_vec(),
+        vec![7, 8, 9],
+        vec![TreeNode::branch(
+            HyperPlane::new(Vector::new([1.0, 0.0, 0.0]), 0.0),
+            TreeNode::leaf(vec![2]),
+            TreeNode::leaf(vec![0, 1]),
+        )],
+    )
+    .unwrap();
+    assert_eq!(parts.leaves(0), Some(vec![vec![9], vec![7, 8]]));
+}
+
+#[test]
+fn test_build_from_iter() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let entries = (0..400).map(|i| (i, vectors[i as usize % 200]));
+    let index = ApproximateNearestNeighborsIndex::build_from_iter(3, 8, entries);
+
+    assert_eq!(index.len(), 200);
+    assert_eq!(
+        index.get(5).map(|v| v.into_array()),
+        Some(vectors[5].into_array())
+    );
+    assert!(
+        index.get(205).is_none(),
+        "Duplicate vectors should be dropped"
+    );
+    let results = index.exact_search(vectors[7], 1);
+    assert_eq!(results[0].vector_id, 7);
+}
+
+#[test]
+fn test_euclidean_distances_are_not_squared() {
+    let vectors: Vec<Vector<4>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let squared = ApproximateNearestNeighborsIndex::build_with_metric(
+        3,
+        8,
+        &vectors,
+        &ids,
+        DistanceMetric::SquaredEuclidean,
+    );
+    let euclidean = ApproximateNearestNeighborsIndex::build_with_metric(
+        3,
+        8,
+        &vectors,
+        &ids,
+        DistanceMetric::Euclidean,
+    );
+
+    let query = Vector::random(None, None);
+    let squared = squared.exact_search(query, 10);
+    let euclidean = euclidean.exact_search(query, 10);
+    for (a, b) in squared.iter().zip(euclidean.iter()) {
+        assert_eq!(a.vector_id, b.vector_id);
+        assert_eq!(a.distance.sqrt(), b.distance);
+        assert_eq!(b.distance, query.euclidian_distance(&b.vector));
+    }
+}
+
+#[test]
+fn test_equal_distances_are_ordered_by_id() {
+    let vectors = vec![
+        Vector::new([1.0, 0.0]),
+        Vector::new([0.0, 1.0]),
+        Vector::new([-1.0, 0.0]),
+        Vector::new([0.0, -1.0]),
+        Vector::new([5.0, 5.0]),
+    ];
+    let ids = vec![7, 3, 9, 1, 2];
+    let query = Vector::new([0.0, 0.0]);
+    for _ in 0..10 {
+        let index = ApproximateNearestNeighborsIndex::build(3, 2, &vectors, &ids);
+        for results in [
+            index.search_with_params(query, 4, 8),
+            index.exact_search(query, 4),
+        ] {
+            let ids: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+            assert_eq!(ids, vec![1, 3, 7, 9]);
+        }
+    }
+}
+
+#[test]
+fn test_search_prepared_cosine_query() {
+    let vectors: Vec<Vector<8>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = IndexBuilder::new()
+        .metric(DistanceMetric::Cosine)
+        .seed(11)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    let query = Vector::<8>::random(None, None);
+    let expected = index.search(query, 10);
+    let unit = query.normalized();
+    for prepared in [
+        query.prepare_for_cosine(),
+        PreparedQuery::normalized(unit),
+        PreparedQuery::new(query),
+    ] {
+        let results = index.search_prepared(&prepared, 10);
+        let ids: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+        let expected_ids: Vec<i32> = expected.iter().map(|r| r.vector_id).collect();
+        assert_eq!(ids, expected_ids);
+        for (result, expected) in results.iter().zip(&expected) {
+            assert!((result.distance - expected.distance).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn test_normalized_and_unnormalized_cosine_queries_rank_the_same() {
+    let mut vectors: Vec<Vector<8>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    vectors.push(Vector::new([0.0; 8]));
+    let ids: Vec<i32> = (0..301).collect();
+    let index = IndexBuilder::new()
+        .metric(DistanceMetric::Cosine)
+        .seed(5)
+        .build(&vectors, &ids)
+        .unwrap();
+
+    let query = Vector::<8>::random(None, None);
+    let normalized = PreparedQuery::normalized(query.normalized());
+    assert!(normalized.is_normalized());
+    for unnormalized in [PreparedQuery::new(query), PreparedQuery::new(query * 7.5)] {
+        assert!(!unnormalized.is_normalized());
+        let expected = index.search_prepared(&unnormalized, 301);
+        let results = index.search_prepared(&normalized, 301);
+        let ids: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+        let expected_ids: Vec<i32> = expected.iter().map(|r| r.vector_id).collect();
+        assert_eq!(ids, expected_ids);
+        for (result, expected) in results.iter().zip(&expected) {
+            assert!((result.distance - expected.distance).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn test_search_iter() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(4, 8, &vectors, &ids, 4);
+
+    let query = Vector::random(None, None);
+    let expected: Vec<i32> = index
+        .search(query, 10)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let found: Vec<i32> = index.search_iter(query, 10).map(|r| r.vector_id).collect();
+    assert_eq!(found, expected);
+
+    let first: Vec<i32> = index
+        .search_iter(query, 10)
+        .take(3)
+        .map(|r| r.vector_id)
+        .collect();
+    assert_eq!(first, expected[..3]);
+    assert_eq!(index.search_iter(query, 0).count(), 0);
+    assert_eq!(index.search_iter(query, -1).count(), 0);
+}
+
+#[test]
+fn test_prepared_search() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let index = ApproximateNearestNeighborsIndex::build_with_seed(4, 8, &vectors, &ids, 2);
+
+    let query = Vector::random(None, None);
+    let prepared = index.prepare(query);
+    assert_eq!(prepared.query().squared_norm(), query.dot(&query));
+    for top_k in [1, 5, 20] {
+        let expected: Vec<i32> = index
+            .search(query, top_k)
+            .iter()
+            .map(|r| r.vector_id)
+            .collect();
+        let found: Vec<i32> = prepared.search(top_k).iter().map(|r| r.vector_id).collect();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn test_max_inner_product_search() {
+    let vectors = vec![
+        Vector::new([1.0, 0.0]),
+        Vector::new([0.5, 0.5]),
+        Vector::new([4.0, 1.0]),
+        Vector::new([-3.0, 2.0]),
+    ];
+    let ids = vec![0, 1, 2, 3];
+    let index = ApproximateNearestNeighborsIndex::build_with_metric(
+        2,
+        2,
+        &vectors,
+        &ids,
+        DistanceMetric::MaxInnerProduct,
+    );
+
+    let query = Vector::new([1.0, 0.5]);
+    let results = index.exact_search(query, 4);
+    let ranked: Vec<i32> = results.iter().map(|r| r.vector_id).collect();
+    assert_eq!(ranked, vec![2, 0, 1, 3]);
+    for result in &results {
+        assert_eq!(result.distance, query.dot(&result.vector));
+    }
+
+    let results = index.search_with_params(query, 1, 8);
+    assert_eq!(results[0].vector_id, 2);
+    assert_eq!(results[0].distance, 4.5);
+}
+
+#[test]
+fn test_max_threads_index() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let builder = IndexBuilder::new().num_trees(4).max_leaf_size(8).seed(12);
+    let mut single = builder
+        .clone()
+        .max_threads(1)
+        .build(&vectors, &ids)
+        .unwrap();
+    let global = builder.build(&vectors, &ids).unwrap();
+
+    let query = Vector::random(None, None);
+    let single_ids: Vec<i32> = single
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let global_ids: Vec<i32> = global
+        .search(query, 5)
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    assert_eq!(single_ids, global_ids);
+
+    single.insert(Vector::new([5.0, 5.0, 5.0]), 200).unwrap();
+    let results = single.search_with_params(Vector::new([5.0, 5.0, 5.0]), 1, 8);
+    assert_eq!(results[0].vector_id, 200);
+}
+
+#[test]
+fn test_sequential_build_and_search() {
+    let vectors: Vec<Vector<4>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let parallel = ApproximateNearestNeighborsIndex::build_with_seed(4, 8, &vectors, &ids, 13);
+    let sequential = ApproximateNearestNeighborsIndex::build_sequential(4, 8, &vectors, &ids, 13);
+
+    for _ in 0..20 {
+        let query = Vector::random(None, None);
+        let expected = parallel.search(query, 10);
+        for results in [
+            parallel.search_sequential(query, 10),
+            sequential.search_sequential(query, 10),
+        ] {
+            assert_eq!(results.len(), expected.len());
+            for (a, b) in results.iter().zip(expected.iter()) {
+                assert_eq!(a.vector_id, b.vector_id);
+                assert_eq!(a.distance, b.distance);
+            }
+        }
+    }
+    assert!(sequential.search_sequential(vectors[0], 0).is_empty());
+}
+
+#[test]
+fn test_raw_vectors() {
+    let vectors: Vec<Vector<3>> = (0..50).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..50).collect();
+    let index = ApproximateNearestNeighborsIndex::build(2, 8, &vectors, &ids);
+
+    let raw = index.raw();
+    assert_eq!(raw.len(), index.len() * 3);
+    for (row, (_, vector)) in raw.chunks_exact(3).zip(index.iter()) {
+        assert_eq!(row, vector.as_slice());
+    }
+}
+
+#[test]
+fn test_exact_search_after_mutations() {
+    let vectors: Vec<Vector<8>> = (0..300).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..300).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 8, &vectors[..200], &ids[..200]);
+    index.extend(&vectors[200..250], &ids[200..250]).unwrap();
+    index.insert(vectors[250], 250).unwrap();
+    for id in (0..100).step_by(3) {
+        index.remove(id);
+    }
+    let other = ApproximateNearestNeighborsIndex::build(2, 8, &vectors[251..], &ids[251..]);
+    let index = index.merge(other).unwrap();
+
+    let query = Vector::random(None, None);
+    let mut expected: Vec<(i32, f32)> = index
+        .iter()
+        .map(|(id, vector)| (id, vector.squared_euclidian_distance(&query)))
+        .collect();
+    expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let results = index.exact_search(query, 10);
+    for (result, (id, distance)) in results.iter().zip(expected.iter()) {
+        assert_eq!(result.vector_id, *id);
+        assert_eq!(result.distance, *distance);
+    }
+}
+
+#[test]
+fn test_memory_usage() {
+    let vectors: Vec<Vector<16>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..1000).collect();
+    let one_tree = ApproximateNearestNeighborsIndex::build_with_seed(1, 10, &vectors, &ids, 0);
+    let four_trees = ApproximateNearestNeighborsIndex::build_with_seed(4, 10, &vectors, &ids, 0);
+
+    // every vector is stored once, and its position once in a leaf of every tree
+    let per_vector = 16 * 4 + 4 + 4 + size_of::<u32>();
+    assert!(one_tree.memory_usage() >= 1000 * per_vector);
+    assert!(four_trees.memory_usage() >= one_tree.memory_usage() + 3 * 1000 * size_of::<u32>());
+    // but a tree costs far less than a second copy of the vectors
+    assert!(four_trees.memory_usage() < 4 * one_tree.memory_usage());
+}
+
+#[test]
+fn test_on_tree_built() {
+    let vectors: Vec<Vector<3>> = (0..500).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..500).collect();
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let recorded = Arc::clone(&reports);
+    let index = IndexBuilder::new()
+        .num_trees(8)
+        .on_tree_built(move |built, total| recorded.lock().unwrap().push((built, total)))
+        .build(&vectors, &ids)
+        .unwrap();
+    assert_eq!(index.num_trees(), 8);
+
+    let mut reports = reports.lock().unwrap().clone();
+    reports.sort();
+    let expected: Vec<(usize, usize)> = (1..=8).map(|built| (built, 8)).collect();
+    assert_eq!(reports, expected);
+}
+
+#[test]
+fn test_build_cancellable() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let vectors: Vec<Vector<3>> = (0..500).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..500).collect();
+    let builder = IndexBuilder::new().num_trees(8);
+
+    let cancel = AtomicBool::new(false);
+    let index = builder.build_cancellable(&vectors, &ids, &cancel).unwrap();
+    assert_eq!(index.num_trees(), 8);
+    assert_eq!(index.len(), 500);
+
+    cancel.store(true, Ordering::Relaxed);
+    let result = builder.build_cancellable(&vectors, &ids, &cancel);
+    assert_eq!(result.unwrap_err(), BuildError::Cancelled);
+
+    // cancelling partway through abandons the trees still being built
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&cancel);
+    let result = IndexBuilder::new()
+        .num_trees(8)
+        .max_threads(1)
+        .on_tree_built(move |_, _| flag.store(true, Ordering::Relaxed))
+        .build_cancellable(&vectors, &ids, &cancel);
+    assert_eq!(result.unwrap_err(), BuildError::Cancelled);
+}
+
+#[test]
+fn test_all_with_ids() {
+    let mut vectors: Vec<Vector<3>> = (0..50).map(|_| Vector::random(None, None)).collect();
+    vectors.push(vectors[3]);
+    let ids: Vec<i32> = (0..vectors.len() as i32).map(|i| 1000 - i).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(3, 4, &vectors, &ids);
+    index.remove(1000);
+
+    let pairs = index.all_with_ids();
+    assert_eq!(pairs.len(), 49);
+    let positions: Vec<[f32; 3]> = index.all_vectors().iter().map(|v| v.into_array()).collect();
+    for (position, (id, vector)) in pairs.iter().enumerate() {
+        let input = ids.iter().position(|i| i == id).unwrap();
+        assert_eq!(vector.into_array(), vectors[input].into_array());
+        assert_eq!(vector.into_array(), positions[position]);
+    }
+    assert!(pairs.iter().all(|&(id, _)| id != 1000 && id != 950));
+
+    let index = IndexBuilder::new()
+        .keep_duplicates(true)
+        .build(&vectors, &ids)
+        .unwrap();
+    let mut pairs: Vec<(i32, [f32; 3])> = index
+        .all_with_ids()
+        .into_iter()
+        .map(|(id, vector)| (id, vector.into_array()))
+        .collect();
+    pairs.sort_by_key(|&(id, _)| id);
+    let mut expected: Vec<(i32, [f32; 3])> = ids
+        .iter()
+        .zip(&vectors)
+        .map(|(&id, vector)| (id, vector.into_array()))
+        .collect();
+    expected.sort_by_key(|&(id, _)| id);
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn test_contains_id() {
+    let mut vectors: Vec<Vector<3>> = (0..20).map(|_| Vector::random(None, None)).collect();
+    vectors.push(vectors[0]);
+    let ids: Vec<i32> = (0..vectors.len() as i32).collect();
+    let mut index = ApproximateNearestNeighborsIndex::build(2, 4, &vectors, &ids);
+    assert_eq!(index.len(), 20);
+    assert!((0..20).all(|id| index.contains_id(id)));
+    assert!(!index.contains_id(20), "the duplicate id should be dropped");
+    assert!(!index.contains_id(-1));
+
+    index.remove(5);
+    assert!(!index.contains_id(5));
+    index
+        .insert(Vector::random(Some(5.0), Some(6.0)), 42)
+        .unwrap();
+    assert!(index.contains_id(42));
+    assert_eq!(index.len(), 20);
+
+    let index = IndexBuilder::new()
+        .keep_duplicates(true)
+        .build(&vectors, &ids)
+        .unwrap();
+    assert_eq!(index.len(), 20);
+    assert!(index.contains_id(20));
+}
+
+#[test]
+fn test_seeded_inserts_are_deterministic() {
+    let vectors: Vec<Vector<3>> = (0..200).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..200).collect();
+    let extra: Vec<Vector<3>> = (0..100).map(|_| Vector::random(None, None)).collect();
+    let extra_ids: Vec<i32> = (200..300).collect();
+    let builder = IndexBuilder::new().num_trees(3).max_leaf_size(4).seed(7);
+
+    let build = |reseed: Option<u64>| {
+        let mut index = builder.build(&vectors, &ids).unwrap();
+        if let Some(seed) = reseed {
+            index.set_seed(seed);
+        }
+        for (vector, &id) in extra.iter().zip(&extra_ids).take(50) {
+            index.insert(*vector, id).unwrap();
+        }
+        index.extend(&extra[50..], &extra_ids[50..]).unwrap();
+        index.rebuild_trees(2, 8);
+        index.insert(Vector::new([5.0, 5.0, 5.0]), 300).unwrap();
+        (0..index.num_trees())
+            .map(|tree| index.leaves(tree).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(build(None), build(None));
+    assert_eq!(build(Some(3)), build(Some(3)));
+}
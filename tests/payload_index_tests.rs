@@ -0,0 +1,100 @@
+use sieve::builder::IndexBuilder;
+use sieve::error::BuildError;
+use sieve::payload_index::PayloadIndex;
+use sieve::vector::Vector;
+
+#[test]
+fn test_payload_index() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([10.0, 10.0]),
+    ];
+    let ids = vec![0, 1, 2];
+    let payloads = vec!["a.txt", "b.txt", "c.txt"];
+    let mut index = PayloadIndex::build(2, 2, &vectors, &ids, payloads).unwrap();
+
+    let results = index.search(Vector::new([9.0, 9.0]), 3);
+    assert_eq!(results[0].result.vector_id, 2);
+    assert_eq!(*results[0].payload, "c.txt");
+
+    index.insert(Vector::new([9.0, 9.5]), 3, "d.txt").unwrap();
+    let results = index.search(Vector::new([9.0, 9.0]), 4);
+    assert_eq!(*results[0].payload, "d.txt");
+
+    assert_eq!(index.remove(3), Some("d.txt"));
+    assert_eq!(index.remove(3), None);
+    assert_eq!(index.payload(0), Some(&"a.txt"));
+    assert_eq!(index.len(), 3);
+}
+
+#[test]
+fn test_payloads_of_duplicates_are_dropped() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([1.0, 2.0]),
+        Vector::new([3.0, 4.0]),
+    ];
+    let ids = vec![0, 1, 2];
+    let payloads = vec![
+        String::from("first"),
+        String::from("duplicate"),
+        String::from("other"),
+    ];
+    let index = IndexBuilder::new()
+        .build_with_payloads(&vectors, &ids, payloads)
+        .unwrap();
+
+    assert_eq!(index.len(), 2);
+    assert_eq!(index.payload(0).map(String::as_str), Some("first"));
+    assert_eq!(index.payload(1), None);
+    assert_eq!(index.payload(2).map(String::as_str), Some("other"));
+
+    let results = index.with_payloads(index.index().exact_search(Vector::new([1.0, 2.0]), 2));
+    let payloads: Vec<&str> = results.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, vec!["first", "other"]);
+}
+
+#[test]
+fn test_payloads_of_near_duplicates_kept_under_their_ids() {
+    let vectors = vec![
+        Vector::new([1.0, 2.0]),
+        Vector::new([1.0, 2.001]),
+        Vector::new([3.0, 4.0]),
+        Vector::new([3.0, 4.0]),
+    ];
+    let ids = vec![0, 1, 2, 2];
+    let payloads = vec!["first", "near", "other", "repeated"];
+    let index = IndexBuilder::new()
+        .keep_duplicates(true)
+        .dedup_epsilon(0.01)
+        .build_with_payloads(&vectors, &ids, payloads)
+        .unwrap();
+
+    assert_eq!(index.len(), 2);
+    assert_eq!(index.payload(1), Some(&"near"));
+    assert_eq!(index.payload(2), Some(&"other"));
+
+    let results = index.search(Vector::new([1.0, 2.0]), 3);
+    let found: Vec<(i32, &str)> = results
+        .iter()
+        .map(|r| (r.result.vector_id, *r.payload))
+        .collect();
+    assert_eq!(found, vec![(0, "first"), (1, "near"), (2, "other")]);
+}
+
+#[test]
+fn test_payload_length_mismatch() {
+    let vectors = vec![Vector::new([1.0, 2.0]), Vector::new([3.0, 4.0])];
+    let result = PayloadIndex::build(2, 2, &vectors, &[0, 1], vec![()]);
+    assert_eq!(
+        result.err().map(|e| e.to_string()),
+        Some(
+            BuildError::PayloadLengthMismatch {
+                vectors: 2,
+                payloads: 1
+            }
+            .to_string()
+        )
+    );
+}
@@ -0,0 +1,71 @@
+use std::collections::HashSet;
+
+use sieve::ann_index::ApproximateNearestNeighborsIndex;
+use sieve::builder::IndexBuilder;
+use sieve::quantized_index::QuantizedIndex;
+use sieve::vector::Vector;
+
+#[test]
+fn test_quantized_search() {
+    let vectors: Vec<Vector<16>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..1000).collect();
+    let builder = IndexBuilder::new().num_trees(8).max_leaf_size(16).seed(3);
+    let index = builder.build(&vectors, &ids).unwrap();
+    let quantized = builder.build_quantized(&vectors, &ids).unwrap();
+    assert_eq!(quantized.len(), 1000);
+    assert!(!quantized.has_originals());
+
+    let mut found = 0;
+    for query in vectors.iter().take(50) {
+        let results = quantized.search(*query, 10);
+        assert_eq!(results.len(), 10);
+        assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+        // the trees are the same, so only the quantization can change which candidates rank first
+        let expected: HashSet<i32> = index
+            .search(*query, 10)
+            .iter()
+            .map(|r| r.vector_id)
+            .collect();
+        found += results
+            .iter()
+            .filter(|r| expected.contains(&r.vector_id))
+            .count();
+    }
+    assert!(
+        found as f32 / 500.0 > 0.9,
+        "overlap {}",
+        found as f32 / 500.0
+    );
+
+    assert!(quantized.search(vectors[0], 0).is_empty());
+    assert!(quantized.search_reranked(vectors[0], 10, 40).is_none());
+}
+
+#[test]
+fn test_quantized_search_reranked() {
+    let vectors: Vec<Vector<16>> = (0..1000).map(|_| Vector::random(None, None)).collect();
+    let ids: Vec<i32> = (0..1000).collect();
+    let builder = IndexBuilder::new().num_trees(8).max_leaf_size(16).seed(3);
+    let index: ApproximateNearestNeighborsIndex<16> = builder.build(&vectors, &ids).unwrap();
+    let quantized = builder
+        .build_quantized_with_originals(&vectors, &ids)
+        .unwrap();
+    assert!(quantized.has_originals());
+
+    for query in vectors.iter().take(20) {
+        let reranked = quantized.search_reranked(*query, 5, 50).unwrap();
+        assert_eq!(reranked.len(), 5);
+        // the query is in the index, and reranking finds it at its exact distance of zero
+        assert_eq!(reranked[0].vector.as_slice(), query.as_slice());
+        assert_eq!(reranked[0].distance, 0.0);
+        for result in &reranked {
+            let original = index.get(result.vector_id).unwrap();
+            assert_eq!(result.vector.as_slice(), original.as_slice());
+            assert_eq!(result.distance, original.squared_euclidian_distance(query));
+        }
+    }
+
+    let quantizer = quantized.quantizer();
+    let built = QuantizedIndex::build(4, 16, &vectors, &ids).unwrap();
+    assert_eq!(built.quantizer(), quantizer);
+}